@@ -0,0 +1,172 @@
+use std::env;
+use std::process;
+
+const CONFIG_FILEPATH: &str = "./loopdrop-config.json";
+
+const USAGE: &str = "usage: loop-drop [CONFIG_PATH] [--write-config] [--session SESSION_PATH]
+                 [--import CHUNK:MIDI_PATH]... [--export MIDI_PATH]
+                 [--monitor] [--midi-log LOG_PATH] [--tui] [--virtual-outputs]
+                 [--dry-run] [--stop-after BEATS] [--render MIDI_PATH] [--script SCRIPT_PATH]
+
+  CONFIG_PATH       rig configuration to load (default: ./loopdrop-config.json)
+  --write-config    write the default rig configuration to CONFIG_PATH, then start
+  --session         restore loops from SESSION_PATH (if it exists) and autosave to it
+  --import          record the notes in MIDI_PATH onto chunk number CHUNK (from 0) and loop them
+  --export          write the current loop of the session to MIDI_PATH, then quit
+  --monitor         print every MIDI message sent and received (except clock)
+  --midi-log        append every MIDI message sent and received to LOG_PATH (rotated at 8 MB)
+  --tui             play the grid in the terminal (keyboard and mouse) instead of on the grid controller
+  --virtual-outputs create every output port as a virtual port for local software to connect to
+  --dry-run         open no MIDI ports, print (or --midi-log) every message the devices would be sent
+  --stop-after      shut down once the clock reaches BEATS
+  --render          play the session for --stop-after BEATS as fast as possible, write the notes to MIDI_PATH
+                    (or as text if it ends in .txt), then quit
+  --script          press grid buttons during a --render, from a JSON list in SCRIPT_PATH
+                    (e.g. [{ \"beats\": 1.5, \"button\": { \"Grid\": 0 }, \"value\": 127 }])";
+
+pub struct Args {
+    pub config_path: String,
+    pub write_config: bool,
+    pub session_path: Option<String>,
+    pub export_path: Option<String>,
+    pub imports: Vec<(usize, String)>,
+    pub monitor: bool,
+    pub midi_log_path: Option<String>,
+    pub tui: bool,
+    pub virtual_outputs: bool,
+    pub dry_run: bool,
+    pub stop_after: Option<i32>,
+    pub render_path: Option<String>,
+    pub script_path: Option<String>,
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        let mut config_path = None;
+        let mut write_config = false;
+        let mut session_path = None;
+        let mut export_path = None;
+        let mut imports = Vec::new();
+        let mut monitor = false;
+        let mut midi_log_path = None;
+        let mut tui = false;
+        let mut virtual_outputs = false;
+        let mut dry_run = false;
+        let mut stop_after = None;
+        let mut render_path = None;
+        let mut script_path = None;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--write-config" => write_config = true,
+                "--session" => session_path = Some(path_arg(&mut args, "SESSION_PATH", &arg)),
+                "--export" => export_path = Some(path_arg(&mut args, "MIDI_PATH", &arg)),
+                "--monitor" => monitor = true,
+                "--midi-log" => midi_log_path = Some(path_arg(&mut args, "LOG_PATH", &arg)),
+                "--tui" => tui = true,
+                "--virtual-outputs" => virtual_outputs = true,
+                "--dry-run" => dry_run = true,
+                "--render" => render_path = Some(path_arg(&mut args, "MIDI_PATH", &arg)),
+                "--script" => script_path = Some(path_arg(&mut args, "SCRIPT_PATH", &arg)),
+                "--stop-after" => {
+                    let value = path_arg(&mut args, "BEATS", &arg);
+                    match value.parse() {
+                        Ok(beats) if beats > 0 => stop_after = Some(beats),
+                        _ => {
+                            eprintln!("Expected a number of BEATS, got {}\n\n{}", value, USAGE);
+                            process::exit(1);
+                        }
+                    }
+                }
+                "--import" => {
+                    let value = path_arg(&mut args, "CHUNK:MIDI_PATH", &arg);
+                    let mut parts = value.splitn(2, ':');
+                    match (parts.next().map(str::parse), parts.next()) {
+                        (Some(Ok(chunk_index)), Some(path)) => {
+                            imports.push((chunk_index, String::from(path)))
+                        }
+                        _ => {
+                            eprintln!("Expected CHUNK:MIDI_PATH, got {}\n\n{}", value, USAGE);
+                            process::exit(1);
+                        }
+                    }
+                }
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ if arg.starts_with('-') || config_path.is_some() => {
+                    eprintln!("Unexpected argument: {}\n\n{}", arg, USAGE);
+                    process::exit(1);
+                }
+                _ => config_path = Some(arg),
+            }
+        }
+
+        if export_path.is_some() && session_path.is_none() {
+            eprintln!("--export needs a --session to export from\n\n{}", USAGE);
+            process::exit(1);
+        }
+
+        if tui && export_path.is_some() {
+            eprintln!(
+                "--export quits straight away, there is nothing to play with --tui\n\n{}",
+                USAGE
+            );
+            process::exit(1);
+        }
+
+        if render_path.is_some() && stop_after.is_none() {
+            eprintln!(
+                "--render needs --stop-after to know how long to play\n\n{}",
+                USAGE
+            );
+            process::exit(1);
+        }
+
+        if script_path.is_some() && render_path.is_none() {
+            eprintln!("--script only plays during a --render\n\n{}", USAGE);
+            process::exit(1);
+        }
+
+        if render_path.is_some() && (tui || export_path.is_some()) {
+            eprintln!("--render can't be used with --tui or --export\n\n{}", USAGE);
+            process::exit(1);
+        }
+
+        if dry_run {
+            // there are no devices, but outputs still need a port to record what they're sent
+            virtual_outputs = true;
+            if midi_log_path.is_none() {
+                monitor = true;
+            }
+        }
+
+        Args {
+            config_path: config_path.unwrap_or_else(|| String::from(CONFIG_FILEPATH)),
+            write_config,
+            session_path,
+            export_path,
+            imports,
+            monitor,
+            midi_log_path,
+            tui,
+            virtual_outputs,
+            dry_run,
+            stop_after,
+            render_path,
+            script_path,
+        }
+    }
+}
+
+fn path_arg(args: &mut impl Iterator<Item = String>, name: &str, flag: &str) -> String {
+    match args.next() {
+        Some(path) => path,
+        None => {
+            eprintln!("Missing {} for {}\n\n{}", name, flag, USAGE);
+            process::exit(1);
+        }
+    }
+}
@@ -11,6 +11,16 @@ pub trait Triggerable {
     fn get_active (&self) -> Option<HashSet<u32>> { None }
     fn latch_mode (&self) -> LatchMode { LatchMode::None }
     fn schedule_mode (&self) -> ScheduleMode { ScheduleMode::MostRecent }  
+
+    // the (channel, note, velocity) messages that triggering id would send right now (for exporting),
+    // with the velocity as played: any velocity map is left to playback so imports don't map twice
+    fn get_notes (&self, _id: u32, _velocity: u8) -> Vec<(u8, u8, u8)> { Vec::new() }
+
+    // the reverse of get_notes: which id (if any) would send this note on this channel (for importing)
+    fn get_id_for_note (&self, _channel: u8, _note: u8) -> Option<u32> { None }
+
+    // panic: release everything this device thinks is sounding, whatever triggered it
+    fn all_notes_off (&mut self) {}
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, Serialize, Deserialize)]
@@ -40,7 +50,7 @@ impl Coords {
     // }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Shape {
     pub rows: u32,
     pub cols: u32
@@ -64,17 +74,43 @@ pub struct ChunkMap {
     pub chunk: Box<dyn Triggerable + Send>,
     pub channel: Option<u32>,
     pub color: u8,
-    pub repeat_mode: RepeatMode
+    pub repeat_mode: RepeatMode,
+    pub keyboard: Option<KeyboardInput>
 }
 
 impl ChunkMap {
-    pub fn new (chunk: Box<dyn Triggerable + Send>, coords: Coords, shape: Shape, color: u8, channel: Option<u32>, repeat_mode: RepeatMode) -> Box<Self> {
+    pub fn new (chunk: Box<dyn Triggerable + Send>, coords: Coords, shape: Shape, color: u8, channel: Option<u32>, repeat_mode: RepeatMode, keyboard: Option<KeyboardInput>) -> Box<Self> {
         Box::new(ChunkMap {
-            chunk, coords, shape, color, channel, repeat_mode
+            chunk, coords, shape, color, channel, repeat_mode, keyboard
         })
     }
 }
 
+// notes from an external keyboard that play a chunk's buttons: first_note plays the
+// top left one, each note above it the next one along (row by row)
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct KeyboardInput {
+    pub port_name: String,
+    // create port_name as a port for local software to play into
+    #[serde(default, rename = "virtual")]
+    pub is_virtual: bool,
+    // 1 - 16, or any channel if not set
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub first_note: u8
+}
+
+impl KeyboardInput {
+    // the position of the button in the chunk (may be past its last one)
+    pub fn index_for (&self, channel: u8, note: u8) -> Option<usize> {
+        if self.channel.map_or(false, |c| c != channel) || note < self.first_note {
+            None
+        } else {
+            Some((note - self.first_note) as usize)
+        }
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, Serialize, Deserialize)]
 pub enum RepeatMode {
     Global,
@@ -1,9 +1,18 @@
-use chunk::{Coords, RepeatMode, Shape};
+use chunk::{Coords, KeyboardInput, RepeatMode, Shape};
+use controllers::MODULATOR_SLOTS;
+use midi_connection::PortMatcher;
+use regex::Regex;
+use scheduler::{MAX_BPM, MIN_BPM};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, to_writer_pretty};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::fs::File;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::BufReader;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 impl Config {
     pub fn read(filepath: &str) -> Result<Self, Box<dyn Error>> {
@@ -21,6 +30,44 @@ impl Config {
         Ok(())
     }
 
+    // polls the file for changes, sending each new version that passes validation
+    pub fn watch(filepath: &str) -> mpsc::Receiver<Self> {
+        let filepath = String::from(filepath);
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let mut last_modified = modified_at(&filepath);
+            loop {
+                thread::sleep(Duration::from_secs(1));
+
+                let modified = modified_at(&filepath);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match Config::read(&filepath) {
+                    Ok(config) => match config.validate() {
+                        Ok(()) => {
+                            if tx.send(config).is_err() {
+                                break;
+                            }
+                        }
+                        Err(errors) => {
+                            println!("[WARN] ignoring invalid config {}:", filepath);
+                            for error in errors {
+                                println!("  {}", error);
+                            }
+                        }
+                    },
+                    Err(err) => println!("[WARN] failed to reload config {}: {}", filepath, err),
+                }
+            }
+        });
+
+        rx
+    }
+
     pub fn default() -> Self {
         let micromonsta_port_name = "MicroMonsta 2"; // synth
 
@@ -44,6 +91,7 @@ impl Config {
                     color: 125, // gross
                     channel: Some(6),
                     repeat_mode: RepeatMode::Global,
+                    keyboard: None,
                     device: DeviceConfig::multi(vec![
                         DeviceConfig::MidiKeys {
                             output: MidiPortConfig::new(micromonsta_port_name, 1),
@@ -69,6 +117,7 @@ impl Config {
                     color: 12, // soft yellow
                     channel: None,
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                     device: DeviceConfig::multi(vec![
                         DeviceConfig::offset("ext"),
                         DeviceConfig::PitchOffsetChunk {
@@ -84,6 +133,7 @@ impl Config {
                     color: 43, // blue
                     channel: None,
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                 },
                 // SYNTH OFFSET
                 ChunkConfig {
@@ -93,6 +143,7 @@ impl Config {
                     color: 55, // pink
                     channel: None,
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                 },
                 // ROOT NOTE SELECTOR
                 ChunkConfig {
@@ -107,6 +158,7 @@ impl Config {
                     color: 35, // soft green
                     channel: None,
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                 },
                 // SCALE MODE SELECTOR
                 ChunkConfig {
@@ -116,6 +168,7 @@ impl Config {
                     color: 0, // black
                     channel: None,
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                 },
                 // DRUMS
                 ChunkConfig {
@@ -130,6 +183,7 @@ impl Config {
                     color: 8, // warm white
                     channel: Some(0),
                     repeat_mode: RepeatMode::NoCycle,
+                    keyboard: None,
                 },
                 ChunkConfig {
                     device: DeviceConfig::MidiTriggers {
@@ -143,6 +197,7 @@ impl Config {
                     color: 15, // yellow
                     channel: Some(3),
                     repeat_mode: RepeatMode::NoCycle,
+                    keyboard: None,
                 },
                 // SAMPLER
                 ChunkConfig {
@@ -157,6 +212,7 @@ impl Config {
                     color: 9, // orange
                     channel: Some(2),
                     repeat_mode: RepeatMode::OnlyQuant,
+                    keyboard: None,
                 },
                 // BASS
                 ChunkConfig {
@@ -182,6 +238,7 @@ impl Config {
                     color: 43, // blue
                     channel: Some(4),
                     repeat_mode: RepeatMode::Global,
+                    keyboard: None,
                 },
                 // SYNTH
                 ChunkConfig {
@@ -206,6 +263,7 @@ impl Config {
                     color: 59, // pink
                     channel: Some(5),
                     repeat_mode: RepeatMode::Global,
+                    keyboard: None,
                 },
             ],
             clock_input_port_name: String::from("RK006"),
@@ -215,9 +273,14 @@ impl Config {
                 String::from(micromonsta_port_name),
             ],
             keep_alive_port_names: vec![],
+            clock: ClockConfig::default(),
+            port_aliases: BTreeMap::new(),
+            thru: Vec::new(),
+            grid: GridConfig::default(),
             controllers: vec![
                 ControllerConfig::Twister {
                     port_name: String::from("Midi Fighter Twister"),
+                    is_virtual: false,
                     mixer_port: MidiPortConfig::new(bluebox_output_name, 1),
                     modulators: vec![
                         ModulatorConfig::rx(
@@ -260,13 +323,20 @@ impl Config {
                 },
                 ControllerConfig::Umi3 {
                     port_name: String::from("Logidy UMI3"),
+                    is_virtual: false,
                 },
             ],
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+fn modified_at(filepath: &str) -> Option<SystemTime> {
+    fs::metadata(filepath)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Config {
     pub chunks: Vec<ChunkConfig>,
     pub clock_input_port_name: String,
@@ -274,9 +344,161 @@ pub struct Config {
     pub keep_alive_port_names: Vec<String>,
     pub resync_port_names: Vec<String>,
     pub controllers: Vec<ControllerConfig>,
+    #[serde(default)]
+    pub clock: ClockConfig,
+    // names that can be used in place of a port name, resolved to the first pattern that matches
+    #[serde(default)]
+    pub port_aliases: BTreeMap<String, Vec<PortPattern>>,
+    // inputs played straight through to outputs, alongside the loops
+    #[serde(default)]
+    pub thru: Vec<ThruConfig>,
+    // the controller the loops are played on
+    #[serde(default)]
+    pub grid: GridConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum GridConfig {
+    LaunchpadProMk3 {
+        port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
+    },
+    LaunchpadX {
+        port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
+    },
+    LaunchpadMiniMk3 {
+        port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
+    },
+    LaunchpadMk2 {
+        port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
+    },
+    // any 8x8 grid of notes: first_note is the top left pad (bottom left if bottom_up)
+    NoteGrid {
+        port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
+        channel: u8,
+        first_note: u8,
+        #[serde(default)]
+        bottom_up: bool,
+        // loop, flatten, undo, redo, hold, suppress, scale, shift
+        #[serde(default)]
+        top_notes: Vec<u8>,
+        // repeat rates from the top
+        #[serde(default)]
+        right_notes: Vec<u8>,
+    },
+}
+
+impl GridConfig {
+    pub fn port_name(&self) -> &str {
+        match self {
+            GridConfig::LaunchpadProMk3 { port_name, .. }
+            | GridConfig::LaunchpadX { port_name, .. }
+            | GridConfig::LaunchpadMiniMk3 { port_name, .. }
+            | GridConfig::LaunchpadMk2 { port_name, .. }
+            | GridConfig::NoteGrid { port_name, .. } => port_name,
+        }
+    }
+
+    // the grid is software on this machine: its input and output are ports of our own
+    pub fn is_virtual(&self) -> bool {
+        match self {
+            GridConfig::LaunchpadProMk3 { is_virtual, .. }
+            | GridConfig::LaunchpadX { is_virtual, .. }
+            | GridConfig::LaunchpadMiniMk3 { is_virtual, .. }
+            | GridConfig::LaunchpadMk2 { is_virtual, .. }
+            | GridConfig::NoteGrid { is_virtual, .. } => *is_virtual,
+        }
+    }
+}
+
+impl Default for GridConfig {
+    fn default() -> Self {
+        let port_name = if cfg!(target_os = "linux") {
+            "Launchpad Pro MK3"
+        } else {
+            "Launchpad Pro MK3 LPProMK3 MIDI"
+        };
+        GridConfig::LaunchpadProMk3 {
+            port_name: String::from(port_name),
+            is_virtual: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum PortPattern {
+    Name(String),
+    // case insensitive
+    Contains(String),
+    Regex(String),
 }
 
-#[derive(Serialize, Deserialize)]
+impl PortPattern {
+    pub fn matcher(&self) -> Result<PortMatcher, String> {
+        match self {
+            PortPattern::Name(name) => Ok(PortMatcher::Name(name.clone())),
+            PortPattern::Contains(value) => Ok(PortMatcher::Contains(value.clone())),
+            PortPattern::Regex(pattern) => Regex::new(pattern)
+                .map(PortMatcher::Regex)
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClockConfig {
+    // tempo of the internal clock that runs whenever no external clock is coming in
+    pub bpm: f64,
+    // how long the external clock can stop before the internal clock takes over
+    pub external_timeout_ms: u64,
+    // create clock_input_port_name as a port for local software to send clock to
+    pub virtual_input: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            bpm: 120.0,
+            external_timeout_ms: 500,
+            virtual_input: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThruConfig {
+    pub input_port_name: String,
+    // create input_port_name as a port for local software to play into
+    #[serde(default)]
+    pub virtual_input: bool,
+    // only this channel (1 - 16) is passed on, or every channel if not set
+    #[serde(default)]
+    pub input_channel: Option<u8>,
+    // everything is sent on the channel of each output
+    pub outputs: Vec<MidiPortConfig>,
+    // lowest and highest note passed on
+    #[serde(default = "full_note_range")]
+    pub note_range: (u8, u8),
+    // play the white keys as steps of the global scale (middle C is the root)
+    #[serde(default)]
+    pub scale: bool,
+}
+
+fn full_note_range() -> (u8, u8) {
+    (0, 127)
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ChunkConfig {
     pub coords: Coords,
     pub shape: Shape,
@@ -284,15 +506,24 @@ pub struct ChunkConfig {
     pub channel: Option<u32>,
     pub repeat_mode: RepeatMode,
     pub device: DeviceConfig,
+    // an external keyboard that plays (and records into) this chunk like its buttons
+    #[serde(default)]
+    pub keyboard: Option<KeyboardInput>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct MidiPortConfig {
     pub name: String,
     pub channel: u8,
+    // how long the device takes to respond, other ports are held back to match the slowest
+    #[serde(default)]
+    pub latency_ms: u32,
+    // create a port called `name` for local software to connect to, instead of finding a device
+    #[serde(default, rename = "virtual")]
+    pub is_virtual: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct SidechainOutput {
     pub id: u32,
 }
@@ -302,11 +533,13 @@ impl MidiPortConfig {
         MidiPortConfig {
             name: String::from(name),
             channel,
+            latency_ms: 0,
+            is_virtual: false,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum DeviceConfig {
     Multi {
         devices: Vec<DeviceConfig>,
@@ -348,15 +581,19 @@ impl DeviceConfig {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum ControllerConfig {
     Twister {
         port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
         mixer_port: MidiPortConfig,
         modulators: Vec<Option<ModulatorConfig>>,
     },
     Umi3 {
         port_name: String,
+        #[serde(default, rename = "virtual")]
+        is_virtual: bool,
     },
     VT4Key {
         output: MidiPortConfig,
@@ -370,14 +607,14 @@ pub enum ControllerConfig {
     },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct ModulatorConfig {
     pub port: MidiPortConfig,
     pub rx_port: Option<MidiPortConfig>,
     pub modulator: Modulator,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Modulator {
     Cc(u8, u8),
     MaxCc(u8, u8, u8),
@@ -406,3 +643,922 @@ impl ModulatorConfig {
         })
     }
 }
+
+// the launchpad exposes an 8x8 grid, an 8x8 scale page and a row of bottom buttons
+const GRID_ROWS: u32 = 17;
+const GRID_COLS: u32 = 8;
+
+// channels used by the twister mixer
+const MIXER_CHANNELS: u32 = 8;
+const MAX_LATENCY_MS: u32 = 500;
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let validator = self.walk();
+
+        if validator.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(validator.errors)
+        }
+    }
+
+    // port name -> the first config of it, for every output port the config mentions
+    // (validation makes sure the port wide options agree)
+    pub fn output_ports(&self) -> HashMap<String, MidiPortConfig> {
+        self.walk()
+            .ports
+            .into_iter()
+            .map(|(name, (port, _))| (name, port))
+            .collect()
+    }
+
+    // how long to hold back each device port: its difference to the slowest device, so that they
+    // all sound together. Ports without a config (only sending clock, resync or keep-alive) are
+    // left out, clock shouldn't wait for the slowest device
+    pub fn output_delays(&self) -> HashMap<String, Duration> {
+        let port_configs = self.output_ports();
+        let max_latency = port_configs
+            .values()
+            .map(|port| port.latency_ms)
+            .max()
+            .unwrap_or(0);
+        port_configs
+            .into_iter()
+            .map(|(name, port)| {
+                let delay = Duration::from_millis((max_latency - port.latency_ms) as u64);
+                (name, delay)
+            })
+            .collect()
+    }
+
+    // alias -> matchers, for midi_connection::set_port_aliases (invalid patterns are skipped)
+    pub fn port_matchers(&self) -> HashMap<String, Vec<PortMatcher>> {
+        self.port_aliases
+            .iter()
+            .map(|(alias, patterns)| {
+                let matchers = patterns
+                    .iter()
+                    .filter_map(|pattern| pattern.matcher().ok())
+                    .collect();
+                (alias.clone(), matchers)
+            })
+            .collect()
+    }
+
+    fn walk(&self) -> Validator {
+        let mut validator = Validator::default();
+
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            validator.chunk(&format!("chunks[{}]", index), chunk);
+        }
+
+        for (index, controller) in self.controllers.iter().enumerate() {
+            validator.controller(&format!("controllers[{}]", index), controller);
+        }
+
+        for (index, thru) in self.thru.iter().enumerate() {
+            validator.thru(&format!("thru[{}]", index), thru);
+        }
+
+        validator.grid("grid", &self.grid);
+        validator.offsets();
+        validator.clock("clock", &self.clock);
+        validator.port_aliases("port_aliases", &self.port_aliases);
+        validator
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    errors: Vec<ConfigError>,
+    cells: HashMap<(u32, u32), String>,
+    // offset_id -> (note_offset, path) of the first MidiKeys device using it
+    offset_users: HashMap<String, (i32, String)>,
+    // offset_id -> path of the OffsetChunk controlling it
+    offset_controllers: HashMap<String, String>,
+    // port name -> (config, path) of the first config of that port
+    ports: HashMap<String, (MidiPortConfig, String)>,
+    // keyboard port name -> (virtual, path) of the first chunk listening to it
+    keyboard_ports: HashMap<String, (bool, String)>,
+}
+
+impl Validator {
+    fn error(&mut self, path: &str, message: String) {
+        self.errors.push(ConfigError {
+            path: String::from(path),
+            message,
+        })
+    }
+
+    fn clock(&mut self, path: &str, clock: &ClockConfig) {
+        if !(MIN_BPM..=MAX_BPM).contains(&clock.bpm) {
+            self.error(
+                &format!("{}.bpm", path),
+                format!("{} is out of range ({} - {})", clock.bpm, MIN_BPM, MAX_BPM),
+            );
+        }
+
+        // external clock ticks are ~80ms apart at 30 BPM, anything shorter would flap
+        if clock.external_timeout_ms < 100 {
+            self.error(
+                &format!("{}.external_timeout_ms", path),
+                String::from("must be at least 100"),
+            );
+        }
+    }
+
+    fn port_aliases(&mut self, path: &str, aliases: &BTreeMap<String, Vec<PortPattern>>) {
+        for (alias, patterns) in aliases {
+            let path = format!("{}.{}", path, alias);
+            if patterns.is_empty() {
+                self.error(&path, String::from("must contain at least one pattern"));
+            }
+
+            for (index, pattern) in patterns.iter().enumerate() {
+                match pattern {
+                    PortPattern::Contains(value) if value.is_empty() => self.error(
+                        &format!("{}[{}].Contains", path, index),
+                        String::from("would match any port"),
+                    ),
+                    PortPattern::Regex(_) => {
+                        if let Err(err) = pattern.matcher() {
+                            self.error(&format!("{}[{}].Regex", path, index), err);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn chunk(&mut self, path: &str, chunk: &ChunkConfig) {
+        let Coords { row, col } = chunk.coords;
+        let Shape { rows, cols } = chunk.shape;
+
+        if rows == 0 || cols == 0 {
+            self.error(
+                &format!("{}.shape", path),
+                String::from("chunk must have at least one row and one column"),
+            );
+        } else if row + rows > GRID_ROWS || col + cols > GRID_COLS {
+            self.error(
+                path,
+                format!(
+                    "{}x{} chunk at row {}, col {} extends outside the {}x{} grid",
+                    rows, cols, row, col, GRID_ROWS, GRID_COLS
+                ),
+            );
+        }
+
+        let mut overlapping = Vec::new();
+        for r in row..(row + rows).min(GRID_ROWS) {
+            for c in col..(col + cols).min(GRID_COLS) {
+                if let Some(other) = self.cells.get(&(r, c)) {
+                    if !overlapping.iter().any(|(o, _, _)| o == other) {
+                        overlapping.push((other.clone(), r, c));
+                    }
+                } else {
+                    self.cells.insert((r, c), String::from(path));
+                }
+            }
+        }
+
+        for (other, r, c) in overlapping {
+            self.error(
+                path,
+                format!(
+                    "overlaps {} (first shared button at row {}, col {})",
+                    other, r, c
+                ),
+            );
+        }
+
+        if let Some(keyboard) = &chunk.keyboard {
+            self.keyboard(&format!("{}.keyboard", path), keyboard, rows * cols);
+        }
+
+        if let Some(channel) = chunk.channel {
+            if channel >= MIXER_CHANNELS {
+                self.error(
+                    &format!("{}.channel", path),
+                    format!(
+                        "mixer channel {} is out of range (0 - {})",
+                        channel,
+                        MIXER_CHANNELS - 1
+                    ),
+                );
+            }
+        }
+
+        self.device(&format!("{}.device", path), &chunk.device);
+    }
+
+    fn device(&mut self, path: &str, device: &DeviceConfig) {
+        match device {
+            DeviceConfig::Multi { devices } => {
+                if devices.is_empty() {
+                    self.error(
+                        &format!("{}.Multi.devices", path),
+                        String::from("must contain at least one device"),
+                    );
+                }
+                for (index, device) in devices.iter().enumerate() {
+                    self.device(&format!("{}.Multi.devices[{}]", path, index), device);
+                }
+            }
+            DeviceConfig::MidiKeys {
+                output,
+                offset_id,
+                note_offset,
+                velocity_map,
+                ..
+            } => {
+                let path = format!("{}.MidiKeys", path);
+                self.port(&format!("{}.output", path), output);
+                self.velocity_map(&format!("{}.velocity_map", path), velocity_map);
+
+                if let Some((existing, existing_path)) = self.offset_users.get(offset_id).cloned() {
+                    if existing != *note_offset {
+                        self.error(
+                            &format!("{}.note_offset", path),
+                            format!(
+                                "offset \"{}\" already has note_offset {} (set by {})",
+                                offset_id, existing, existing_path
+                            ),
+                        );
+                    }
+                } else {
+                    self.offset_users
+                        .insert(offset_id.clone(), (*note_offset, path.clone()));
+                }
+            }
+            DeviceConfig::OffsetChunk { id } => {
+                let path = format!("{}.OffsetChunk.id", path);
+                if let Some(existing_path) = self.offset_controllers.get(id).cloned() {
+                    self.error(
+                        &path,
+                        format!(
+                            "offset \"{}\" is already controlled by {}",
+                            id, existing_path
+                        ),
+                    );
+                } else {
+                    self.offset_controllers.insert(id.clone(), path);
+                }
+            }
+            DeviceConfig::PitchOffsetChunk { output } => {
+                self.port(&format!("{}.PitchOffsetChunk.output", path), output);
+            }
+            DeviceConfig::RootSelect { output_modulators } => {
+                self.modulators(
+                    &format!("{}.RootSelect.output_modulators", path),
+                    output_modulators,
+                );
+            }
+            DeviceConfig::ScaleSelect => (),
+            DeviceConfig::MidiTriggers {
+                output,
+                trigger_ids,
+                velocity_map,
+                ..
+            } => {
+                let path = format!("{}.MidiTriggers", path);
+                self.port(&format!("{}.output", path), output);
+                self.velocity_map(&format!("{}.velocity_map", path), velocity_map);
+                if trigger_ids.is_empty() {
+                    self.error(
+                        &format!("{}.trigger_ids", path),
+                        String::from("must contain at least one note"),
+                    );
+                }
+            }
+        }
+    }
+
+    fn controller(&mut self, path: &str, controller: &ControllerConfig) {
+        match controller {
+            ControllerConfig::Twister {
+                mixer_port,
+                modulators,
+                ..
+            } => {
+                let path = format!("{}.Twister", path);
+                self.port(&format!("{}.mixer_port", path), mixer_port);
+                self.modulators(&format!("{}.modulators", path), modulators);
+
+                for (index, modulator) in modulators.iter().enumerate().skip(MODULATOR_SLOTS) {
+                    if modulator.is_some() {
+                        self.error(
+                            &format!("{}.modulators[{}]", path, index),
+                            format!(
+                                "the Twister can only address {} modulators",
+                                MODULATOR_SLOTS
+                            ),
+                        );
+                    }
+                }
+            }
+            ControllerConfig::Umi3 { .. } => (),
+            ControllerConfig::VT4Key { output } => {
+                self.port(&format!("{}.VT4Key.output", path), output);
+            }
+            ControllerConfig::ClockPulse { output, divider } => {
+                let path = format!("{}.ClockPulse", path);
+                self.port(&format!("{}.output", path), output);
+                if *divider < 1 {
+                    self.error(
+                        &format!("{}.divider", path),
+                        format!("divider must be at least 1, got {}", divider),
+                    );
+                }
+            }
+            ControllerConfig::Init { modulators } => {
+                self.modulators(&format!("{}.Init.modulators", path), modulators);
+            }
+        }
+    }
+
+    fn thru(&mut self, path: &str, thru: &ThruConfig) {
+        if let Some(channel) = thru.input_channel {
+            if channel < 1 || channel > 16 {
+                self.error(
+                    &format!("{}.input_channel", path),
+                    format!("MIDI channel {} is out of range (1 - 16)", channel),
+                );
+            }
+        }
+
+        if thru.outputs.is_empty() {
+            self.error(
+                &format!("{}.outputs", path),
+                String::from("must contain at least one output"),
+            );
+        }
+        for (index, output) in thru.outputs.iter().enumerate() {
+            self.port(&format!("{}.outputs[{}]", path, index), output);
+        }
+
+        let (low, high) = thru.note_range;
+        if low > high || high > 127 {
+            self.error(
+                &format!("{}.note_range", path),
+                format!("[{}, {}] is not a range of notes (0 - 127)", low, high),
+            );
+        }
+    }
+
+    fn grid(&mut self, path: &str, grid: &GridConfig) {
+        if let GridConfig::NoteGrid {
+            channel,
+            first_note,
+            top_notes,
+            right_notes,
+            ..
+        } = grid
+        {
+            let path = format!("{}.NoteGrid", path);
+            if *channel < 1 || *channel > 16 {
+                self.error(
+                    &format!("{}.channel", path),
+                    format!("MIDI channel {} is out of range (1 - 16)", channel),
+                );
+            }
+
+            if *first_note as u32 + 64 > 128 {
+                self.error(
+                    &format!("{}.first_note", path),
+                    format!("64 pads from note {} run past note 127", first_note),
+                );
+            }
+
+            let grid_notes = (*first_note as u32)..(*first_note as u32 + 64);
+            for (name, notes) in [("top_notes", top_notes), ("right_notes", right_notes)].iter() {
+                if notes.len() > 8 {
+                    self.error(
+                        &format!("{}.{}", path, name),
+                        String::from("can't have more than 8 buttons"),
+                    );
+                }
+                for (index, note) in notes.iter().enumerate() {
+                    if grid_notes.contains(&(*note as u32)) {
+                        self.error(
+                            &format!("{}.{}[{}]", path, name, index),
+                            format!("note {} is one of the pads", note),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn keyboard(&mut self, path: &str, keyboard: &KeyboardInput, buttons: u32) {
+        if let Some(channel) = keyboard.channel {
+            if channel < 1 || channel > 16 {
+                self.error(
+                    &format!("{}.channel", path),
+                    format!("MIDI channel {} is out of range (1 - 16)", channel),
+                );
+            }
+        }
+
+        if keyboard.first_note as u32 + buttons > 128 {
+            self.error(
+                &format!("{}.first_note", path),
+                format!(
+                    "{} buttons from note {} run past note 127",
+                    buttons, keyboard.first_note
+                ),
+            );
+        }
+
+        // chunks on the same keyboard share its input
+        if let Some((is_virtual, existing_path)) = self.keyboard_ports.get(&keyboard.port_name) {
+            if *is_virtual != keyboard.is_virtual {
+                let message = format!(
+                    "port \"{}\" already has virtual {} (set by {})",
+                    keyboard.port_name, is_virtual, existing_path
+                );
+                self.error(&format!("{}.virtual", path), message);
+            }
+        } else {
+            self.keyboard_ports.insert(
+                keyboard.port_name.clone(),
+                (keyboard.is_virtual, String::from(path)),
+            );
+        }
+    }
+
+    fn modulators(&mut self, path: &str, modulators: &[Option<ModulatorConfig>]) {
+        for (index, modulator) in modulators.iter().enumerate() {
+            if let Some(modulator) = modulator {
+                let path = format!("{}[{}]", path, index);
+                self.port(&format!("{}.port", path), &modulator.port);
+                if let Some(rx_port) = &modulator.rx_port {
+                    self.port(&format!("{}.rx_port", path), rx_port);
+                }
+            }
+        }
+    }
+
+    fn port(&mut self, path: &str, port: &MidiPortConfig) {
+        if port.channel < 1 || port.channel > 16 {
+            self.error(
+                &format!("{}.channel", path),
+                format!("MIDI channel {} is out of range (1 - 16)", port.channel),
+            );
+        }
+
+        if port.latency_ms > MAX_LATENCY_MS {
+            self.error(
+                &format!("{}.latency_ms", path),
+                format!(
+                    "{} is out of range (0 - {})",
+                    port.latency_ms, MAX_LATENCY_MS
+                ),
+            );
+        }
+
+        // these belong to the port, every config of it has to agree
+        if let Some((existing, existing_path)) = self.ports.get(&port.name).cloned() {
+            if existing.latency_ms != port.latency_ms {
+                self.error(
+                    &format!("{}.latency_ms", path),
+                    format!(
+                        "port \"{}\" already has latency_ms {} (set by {})",
+                        port.name, existing.latency_ms, existing_path
+                    ),
+                );
+            }
+            if existing.is_virtual != port.is_virtual {
+                self.error(
+                    &format!("{}.virtual", path),
+                    format!(
+                        "port \"{}\" already has virtual {} (set by {})",
+                        port.name, existing.is_virtual, existing_path
+                    ),
+                );
+            }
+        } else {
+            self.ports
+                .insert(port.name.clone(), (port.clone(), String::from(path)));
+        }
+    }
+
+    fn velocity_map(&mut self, path: &str, velocity_map: &Option<Vec<u8>>) {
+        if let Some(velocity_map) = velocity_map {
+            if velocity_map.is_empty() {
+                self.error(
+                    path,
+                    String::from("must contain at least one velocity (or be null)"),
+                );
+            }
+        }
+    }
+
+    fn offsets(&mut self) {
+        let mut unused: Vec<(String, String)> = self
+            .offset_controllers
+            .iter()
+            .filter(|(id, _)| !self.offset_users.contains_key(*id))
+            .map(|(id, path)| (id.clone(), path.clone()))
+            .collect();
+        unused.sort();
+
+        for (id, path) in unused {
+            self.error(
+                &path,
+                format!("offset \"{}\" is not used by any MidiKeys device", id),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    fn drums(coords: Coords, trigger_ids: Vec<u8>) -> ChunkConfig {
+        ChunkConfig {
+            device: DeviceConfig::MidiTriggers {
+                output: MidiPortConfig::new("Drums", 10),
+                velocity_map: None,
+                trigger_ids,
+                sidechain_output: None,
+            },
+            coords,
+            shape: Shape::new(1, 4),
+            color: 8,
+            channel: None,
+            repeat_mode: RepeatMode::NoCycle,
+            keyboard: None,
+        }
+    }
+
+    fn empty_config() -> Config {
+        Config {
+            chunks: Vec::new(),
+            clock_input_port_name: String::from("RK006"),
+            clock_output_port_names: Vec::new(),
+            keep_alive_port_names: Vec::new(),
+            resync_port_names: Vec::new(),
+            controllers: Vec::new(),
+            clock: ClockConfig::default(),
+            port_aliases: BTreeMap::new(),
+            thru: Vec::new(),
+            grid: GridConfig::default(),
+        }
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(messages(&Config::default()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn overlapping_and_out_of_bounds_chunks() {
+        let mut config = empty_config();
+        config.chunks.push(drums(Coords::new(0, 0), vec![36]));
+        config.chunks.push(drums(Coords::new(0, 2), vec![36]));
+        config.chunks.push(drums(Coords::new(16, 6), vec![36]));
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[1]: overlaps chunks[0] (first shared button at row 0, col 2)",
+                "chunks[2]: 1x4 chunk at row 16, col 6 extends outside the 17x8 grid",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_lists_and_bad_channels() {
+        let mut config = empty_config();
+        config.chunks.push(ChunkConfig {
+            device: DeviceConfig::multi(vec![
+                DeviceConfig::MidiTriggers {
+                    output: MidiPortConfig::new("Drums", 0),
+                    velocity_map: Some(vec![]),
+                    trigger_ids: vec![],
+                    sidechain_output: None,
+                },
+                DeviceConfig::ScaleSelect,
+            ]),
+            ..drums(Coords::new(0, 0), vec![36])
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[0].device.Multi.devices[0].MidiTriggers.output.channel: MIDI channel 0 is out of range (1 - 16)",
+                "chunks[0].device.Multi.devices[0].MidiTriggers.velocity_map: must contain at least one velocity (or be null)",
+                "chunks[0].device.Multi.devices[0].MidiTriggers.trigger_ids: must contain at least one note",
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_offsets() {
+        let keys = |note_offset| DeviceConfig::MidiKeys {
+            output: MidiPortConfig::new("Synth", 1),
+            offset_id: String::from("keys"),
+            note_offset,
+            velocity_map: None,
+            octave_offset: 0,
+        };
+
+        let mut config = empty_config();
+        config.chunks.push(ChunkConfig {
+            device: DeviceConfig::multi(vec![keys(-4), keys(0)]),
+            ..drums(Coords::new(0, 0), vec![36])
+        });
+        config.chunks.push(ChunkConfig {
+            device: DeviceConfig::multi(vec![
+                DeviceConfig::offset("keys"),
+                DeviceConfig::offset("keys"),
+                DeviceConfig::offset("bass"),
+            ]),
+            ..drums(Coords::new(1, 0), vec![36])
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[0].device.Multi.devices[1].MidiKeys.note_offset: offset \"keys\" already has note_offset -4 (set by chunks[0].device.Multi.devices[0].MidiKeys)",
+                "chunks[1].device.Multi.devices[1].OffsetChunk.id: offset \"keys\" is already controlled by chunks[1].device.Multi.devices[0].OffsetChunk.id",
+                "chunks[1].device.Multi.devices[2].OffsetChunk.id: offset \"bass\" is not used by any MidiKeys device",
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_twister_modulators() {
+        let mut modulators = vec![None; MODULATOR_SLOTS];
+        modulators.push(ModulatorConfig::new("Synth", 1, Modulator::Cc(1, 64)));
+        modulators.push(None);
+
+        let mut config = empty_config();
+        config.controllers.push(ControllerConfig::Twister {
+            port_name: String::from("Midi Fighter Twister"),
+            is_virtual: false,
+            mixer_port: MidiPortConfig::new("Mixer", 1),
+            modulators,
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "controllers[0].Twister.modulators[16]: the Twister can only address 16 modulators"
+            ]
+        );
+    }
+    #[test]
+    fn conflicting_port_options() {
+        let mut slow = MidiPortConfig::new("Drums", 10);
+        slow.latency_ms = 12;
+        slow.is_virtual = true;
+
+        let mut config = empty_config();
+        config.chunks.push(drums(Coords::new(0, 0), vec![36]));
+        config.chunks.push(ChunkConfig {
+            device: DeviceConfig::PitchOffsetChunk { output: slow },
+            ..drums(Coords::new(1, 0), vec![36])
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[1].device.PitchOffsetChunk.output.latency_ms: port \"Drums\" already has latency_ms 0 (set by chunks[0].device.MidiTriggers.output)",
+                "chunks[1].device.PitchOffsetChunk.output.virtual: port \"Drums\" already has virtual false (set by chunks[0].device.MidiTriggers.output)",
+            ]
+        );
+        assert!(config.output_ports().get("Drums") == Some(&MidiPortConfig::new("Drums", 10)));
+    }
+
+    #[test]
+    fn output_delays_match_the_slowest_device() {
+        let mut slow = MidiPortConfig::new("Synth", 1);
+        slow.latency_ms = 12;
+
+        let mut config = empty_config();
+        config
+            .clock_output_port_names
+            .push(String::from("Sequencer"));
+        config.chunks.push(drums(Coords::new(0, 0), vec![36]));
+        config.chunks.push(ChunkConfig {
+            device: DeviceConfig::PitchOffsetChunk { output: slow },
+            ..drums(Coords::new(1, 0), vec![36])
+        });
+
+        let delays = config.output_delays();
+        assert_eq!(delays.get("Drums"), Some(&Duration::from_millis(12)));
+        assert_eq!(delays.get("Synth"), Some(&Duration::from_millis(0)));
+        // only gets clock, which is sent straight away
+        assert_eq!(delays.get("Sequencer"), None);
+    }
+
+    #[test]
+    fn port_alias_patterns() {
+        let mut config = empty_config();
+        config.port_aliases.insert(
+            String::from("drums"),
+            vec![
+                PortPattern::Regex(String::from("^RK006( [0-9]+)? PORT 6$")),
+                PortPattern::Contains(String::new()),
+                PortPattern::Regex(String::from("(")),
+            ],
+        );
+        config.port_aliases.insert(String::from("synth"), vec![]);
+
+        let errors = messages(&config);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0],
+            "port_aliases.drums[1].Contains: would match any port"
+        );
+        assert!(errors[1].starts_with("port_aliases.drums[2].Regex: "));
+        assert_eq!(
+            errors[2],
+            "port_aliases.synth: must contain at least one pattern"
+        );
+
+        let matchers = config.port_matchers();
+        assert_eq!(matchers.get("drums").map(|m| m.len()), Some(2));
+    }
+
+    #[test]
+    fn note_grids() {
+        let mut config = empty_config();
+        config.grid = GridConfig::NoteGrid {
+            port_name: String::from("APC mini"),
+            is_virtual: false,
+            channel: 17,
+            first_note: 70,
+            bottom_up: true,
+            top_notes: vec![64, 65, 66, 67, 68, 69, 70, 71, 72],
+            right_notes: vec![],
+        };
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "grid.NoteGrid.channel: MIDI channel 17 is out of range (1 - 16)",
+                "grid.NoteGrid.first_note: 64 pads from note 70 run past note 127",
+                "grid.NoteGrid.top_notes: can't have more than 8 buttons",
+                "grid.NoteGrid.top_notes[6]: note 70 is one of the pads",
+                "grid.NoteGrid.top_notes[7]: note 71 is one of the pads",
+                "grid.NoteGrid.top_notes[8]: note 72 is one of the pads",
+            ]
+        );
+
+        let grid: GridConfig =
+            serde_json::from_str(r#"{ "LaunchpadX": { "port_name": "Launchpad X" } }"#).unwrap();
+        assert_eq!(grid.port_name(), "Launchpad X");
+    }
+
+    #[test]
+    fn keyboard_inputs() {
+        let mut config = empty_config();
+        config.chunks.push(ChunkConfig {
+            keyboard: Some(KeyboardInput {
+                port_name: String::from("Keystep"),
+                is_virtual: false,
+                channel: Some(0),
+                first_note: 125,
+            }),
+            ..drums(Coords::new(0, 0), vec![36, 38, 40, 42])
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[0].keyboard.channel: MIDI channel 0 is out of range (1 - 16)",
+                "chunks[0].keyboard.first_note: 4 buttons from note 125 run past note 127",
+            ]
+        );
+    }
+
+    #[test]
+    fn virtual_inputs() {
+        let mut config = empty_config();
+        config.grid =
+            serde_json::from_str(r#"{ "LaunchpadX": { "port_name": "Grid", "virtual": true } }"#)
+                .unwrap();
+        assert!(config.grid.is_virtual());
+
+        let thru: ThruConfig = serde_json::from_str(
+            r#"{ "input_port_name": "Keys", "virtual_input": true, "outputs": [] }"#,
+        )
+        .unwrap();
+        assert!(thru.virtual_input);
+
+        let controller: ControllerConfig =
+            serde_json::from_str(r#"{ "Umi3": { "port_name": "Pedal", "virtual": true } }"#)
+                .unwrap();
+        assert!(
+            controller
+                == ControllerConfig::Umi3 {
+                    port_name: String::from("Pedal"),
+                    is_virtual: true,
+                }
+        );
+
+        let keyboard: KeyboardInput =
+            serde_json::from_str(r#"{ "port_name": "Keys", "first_note": 48 }"#).unwrap();
+        assert!(!keyboard.is_virtual);
+        config.chunks.push(ChunkConfig {
+            keyboard: Some(keyboard.clone()),
+            ..drums(Coords::new(0, 0), vec![36])
+        });
+        config.chunks.push(ChunkConfig {
+            keyboard: Some(KeyboardInput {
+                is_virtual: true,
+                ..keyboard
+            }),
+            ..drums(Coords::new(1, 0), vec![38])
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "chunks[1].keyboard.virtual: port \"Keys\" already has virtual false (set by chunks[0].keyboard)",
+            ]
+        );
+    }
+
+    #[test]
+    fn thru_routes() {
+        let mut config = empty_config();
+        config.thru.push(ThruConfig {
+            input_port_name: String::from("Keystep"),
+            virtual_input: false,
+            input_channel: Some(17),
+            outputs: vec![],
+            note_range: (60, 48),
+            scale: true,
+        });
+        config.thru.push(ThruConfig {
+            input_port_name: String::from("Keystep"),
+            virtual_input: false,
+            input_channel: None,
+            outputs: vec![MidiPortConfig::new("Synth", 0)],
+            note_range: (0, 127),
+            scale: false,
+        });
+
+        assert_eq!(
+            messages(&config),
+            vec![
+                "thru[0].input_channel: MIDI channel 17 is out of range (1 - 16)",
+                "thru[0].outputs: must contain at least one output",
+                "thru[0].note_range: [60, 48] is not a range of notes (0 - 127)",
+                "thru[1].outputs[0].channel: MIDI channel 0 is out of range (1 - 16)",
+            ]
+        );
+
+        // only the input port and outputs are required
+        let thru: ThruConfig = serde_json::from_str(
+            r#"{ "input_port_name": "Keystep", "outputs": [{ "name": "Synth", "channel": 2 }] }"#,
+        )
+        .unwrap();
+        assert!(thru.note_range == (0, 127) && thru.input_channel.is_none() && !thru.scale);
+    }
+
+    #[test]
+    fn clock_defaults_and_ranges() {
+        let mut json = serde_json::to_value(empty_config()).unwrap();
+        json.as_object_mut().unwrap().remove("clock");
+        let config: Config = serde_json::from_value(json).unwrap();
+        assert!(config.clock == ClockConfig::default());
+
+        let mut config = empty_config();
+        config.clock.bpm = 0.0;
+        config.clock.external_timeout_ms = 10;
+        assert_eq!(
+            messages(&config),
+            vec![
+                "clock.bpm: 0 is out of range (20 - 300)",
+                "clock.external_timeout_ms: must be at least 100"
+            ]
+        );
+    }
+}
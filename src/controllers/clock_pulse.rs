@@ -1,4 +1,4 @@
-use midi_connection;
+use midi_connection::{self, MidiMessage};
 use scheduler::MidiTime;
 
 pub struct ClockPulse {
@@ -24,12 +24,16 @@ impl ClockPulse {
 impl ::controllers::Schedulable for ClockPulse {
     fn schedule(&mut self, pos: MidiTime, _length: MidiTime) {
         if (pos.ticks() - 1) % self.divider == 0 {
-            self.midi_output
-                .send(&[144 - 1 + self.channel, 36, 127])
-                .unwrap();
-            self.midi_output
-                .send(&[144 - 1 + self.channel, 36, 0])
-                .unwrap();
+            self.midi_output.send(MidiMessage::NoteOn {
+                channel: self.channel,
+                note: 36,
+                velocity: 127,
+            });
+            self.midi_output.send(MidiMessage::NoteOn {
+                channel: self.channel,
+                note: 36,
+                velocity: 0,
+            });
         }
     }
 }
@@ -4,11 +4,14 @@ mod twister;
 mod umi3;
 mod vt4_key;
 
+use midi_connection::MidiMessage;
 use midi_time::MidiTime;
+use session::AutomationSession;
+use std::sync::mpsc;
 
 pub use self::clock_pulse::ClockPulse;
 pub use self::init::Init;
-pub use self::twister::Twister;
+pub use self::twister::{Twister, MODULATOR_SLOTS};
 pub use self::umi3::Umi3;
 pub use self::vt4_key::VT4Key;
 
@@ -22,10 +25,10 @@ pub struct Modulator {
 impl Modulator {
     pub fn send_polar(&mut self, value: f64) {
         if let ::config::Modulator::PitchBend(..) = self.modulator {
-            let value = polar_to_msb_lsb(value);
-            self.port
-                .send(&[224 - 1 + self.channel, value.0, value.1])
-                .unwrap();
+            self.port.send(MidiMessage::PitchBend {
+                channel: self.channel,
+                value: polar_to_pitch_bend(value),
+            });
         } else {
             self.send(polar_to_midi(value));
         }
@@ -34,9 +37,11 @@ impl Modulator {
     pub fn send(&mut self, value: u8) {
         match self.modulator {
             ::config::Modulator::Cc(id, ..) => {
-                self.port
-                    .send(&[176 - 1 + self.channel, id, value])
-                    .unwrap();
+                self.port.send(MidiMessage::ControlChange {
+                    channel: self.channel,
+                    control: id,
+                    value,
+                });
             }
             ::config::Modulator::PolarCcSwitch {
                 cc_low,
@@ -49,39 +54,47 @@ impl Modulator {
                     if let Some(cc) = cc_low {
                         let abs = polar_value * -1.0;
                         let value = float_to_midi(abs * abs);
-                        self.port
-                            .send(&[176 - 1 + self.channel, cc, value])
-                            .unwrap();
+                        self.port.send(MidiMessage::ControlChange {
+                            channel: self.channel,
+                            control: cc,
+                            value,
+                        });
                     }
                 } else {
                     if let Some(cc) = cc_high {
                         let value = float_to_midi(polar_value);
-                        self.port
-                            .send(&[176 - 1 + self.channel, cc, value])
-                            .unwrap();
+                        self.port.send(MidiMessage::ControlChange {
+                            channel: self.channel,
+                            control: cc,
+                            value,
+                        });
                     }
                 }
 
                 if let Some(cc) = cc_switch {
                     let value = if polar_value < 0.0 { 0 } else { 127 };
 
-                    self.port
-                        .send(&[176 - 1 + self.channel, cc, value])
-                        .unwrap();
+                    self.port.send(MidiMessage::ControlChange {
+                        channel: self.channel,
+                        control: cc,
+                        value,
+                    });
                 }
             }
             ::config::Modulator::MaxCc(id, max, ..) => {
                 let f_value = value as f64 / 127.0 as f64;
                 let u_value = (f_value * max as f64).min(127.0) as u8;
-                self.port
-                    .send(&[176 - 1 + self.channel, id, u_value])
-                    .unwrap();
+                self.port.send(MidiMessage::ControlChange {
+                    channel: self.channel,
+                    control: id,
+                    value: u_value,
+                });
             }
             ::config::Modulator::PitchBend(..) => {
-                let value = polar_to_msb_lsb(midi_to_polar(value));
-                self.port
-                    .send(&[224 - 1 + self.channel, value.0, value.1])
-                    .unwrap();
+                self.port.send(MidiMessage::PitchBend {
+                    channel: self.channel,
+                    value: polar_to_pitch_bend(midi_to_polar(value)),
+                });
             }
         }
     }
@@ -89,23 +102,27 @@ impl Modulator {
     pub fn send_default(&mut self) {
         match self.modulator {
             ::config::Modulator::Cc(id, value) => {
-                self.port
-                    .send(&[176 - 1 + self.channel, id, value])
-                    .unwrap();
+                self.port.send(MidiMessage::ControlChange {
+                    channel: self.channel,
+                    control: id,
+                    value,
+                });
             }
             ::config::Modulator::PolarCcSwitch { default, .. } => {
                 self.send(default);
             }
             ::config::Modulator::MaxCc(id, max, value) => {
-                self.port
-                    .send(&[176 - 1 + self.channel, id, value.min(max)])
-                    .unwrap();
+                self.port.send(MidiMessage::ControlChange {
+                    channel: self.channel,
+                    control: id,
+                    value: value.min(max),
+                });
             }
             ::config::Modulator::PitchBend(value) => {
-                let value = ::controllers::polar_to_msb_lsb(value);
-                self.port
-                    .send(&[224 - 1 + self.channel, value.0, value.1])
-                    .unwrap();
+                self.port.send(MidiMessage::PitchBend {
+                    channel: self.channel,
+                    value: polar_to_pitch_bend(value),
+                });
             }
         }
     }
@@ -113,22 +130,23 @@ impl Modulator {
 
 pub trait Schedulable {
     fn schedule(&mut self, _pos: MidiTime, _length: MidiTime) {}
-}
-
-pub fn polar_to_msb_lsb(input: f64) -> (u8, u8) {
-    let max = (2.0f64).powf(14.0) / 2.0;
-    let input_14bit = (input.max(-1.0).min(0.99999999999) * max + max) as u16;
 
-    let lsb = mask7(input_14bit as u8);
-    let msb = mask7((input_14bit >> 7) as u8);
+    // where the session keeps its automation (e.g. the port name), stable across config edits
+    fn session_key(&self) -> Option<&str> {
+        None
+    }
 
-    (lsb, msb)
+    // only controllers that record their own loops have anything to save, the answer
+    // arrives on the receiver so the scheduler doesn't wait for it
+    fn save_automation(&self) -> Option<mpsc::Receiver<AutomationSession>> {
+        None
+    }
+    fn load_automation(&mut self, _automation: AutomationSession) {}
 }
 
-/// 7 bit mask
-#[inline(always)]
-pub fn mask7(input: u8) -> u8 {
-    input & 0b01111111
+pub fn polar_to_pitch_bend(input: f64) -> u16 {
+    let max = (2.0f64).powf(14.0) / 2.0;
+    (input.max(-1.0).min(0.99999999999) * max + max) as u16
 }
 
 pub fn midi_to_polar(value: u8) -> f64 {
@@ -1,8 +1,9 @@
 use lfo::Lfo;
 use loop_grid_launchpad::LoopGridParams;
 use loop_recorder::{LoopEvent, LoopRecorder};
-use midi_connection;
+use midi_connection::{self, get_input_port, MidiMessage};
 use output_value::OutputValue;
+use session::{AutomationLoop, AutomationSession};
 use std::sync::mpsc;
 use throttled_output::ThrottledOutput;
 use trigger_envelope::TriggerEnvelope;
@@ -15,7 +16,11 @@ use std::thread;
 
 use super::midi_to_float;
 
+// number of modulator slots addressable from the knobs (see Control::from_id)
+pub const MODULATOR_SLOTS: usize = 16;
+
 pub struct Twister {
+    port_name: String,
     _midi_input: midi_connection::ThreadReference,
     tx: mpsc::Sender<TwisterMessage>,
 }
@@ -29,6 +34,7 @@ enum EventSource {
 impl Twister {
     pub fn new(
         port_name: &str,
+        is_virtual: bool,
         main_output: midi_connection::SharedMidiOutputConnection,
         main_channel: u8,
         modulators: Vec<Option<Modulator>>,
@@ -45,36 +51,40 @@ impl Twister {
         let tx_clock = tx.clone();
 
         let mut output = midi_connection::get_shared_output(port_name);
-
-        let input = midi_connection::get_input(port_name, move |_stamp, message| {
-            let control = Control::from_id(message[1] as u32);
-            if message[0] == 176 {
+        output.set_virtual(is_virtual);
+
+        let input = get_input_port(port_name, is_virtual, move |_stamp, message| {
+            let (channel, id, value) = match message {
+                MidiMessage::ControlChange {
+                    channel,
+                    control,
+                    value,
+                } => (channel, control, value),
+                _ => return,
+            };
+
+            let control = Control::from_id(id as u32);
+            if channel == 1 {
                 tx_input
                     .send(TwisterMessage::ControlChange(
                         control,
-                        OutputValue::On(message[2]),
+                        OutputValue::On(value),
                         EventSource::User,
                     ))
                     .unwrap();
-            } else if message[0] == 177 {
-                tx_input
-                    .send(TwisterMessage::Recording(control, message[2] > 0))
-                    .unwrap();
-            } else if message[0] == 179 && message[1] < 4 && message[2] == 127 {
+            } else if channel == 2 {
                 tx_input
-                    .send(TwisterMessage::BankChange(message[1]))
+                    .send(TwisterMessage::Recording(control, value > 0))
                     .unwrap();
-            } else if message[0] == 179
-                && (message[1] == 10 || message[1] == 16 || message[1] == 22 || message[1] == 28)
-            {
+            } else if channel == 4 && id < 4 && value == 127 {
+                tx_input.send(TwisterMessage::BankChange(id)).unwrap();
+            } else if channel == 4 && (id == 10 || id == 16 || id == 22 || id == 28) {
                 tx_input
-                    .send(TwisterMessage::LeftButton(message[2] > 0))
+                    .send(TwisterMessage::LeftButton(value > 0))
                     .unwrap();
-            } else if message[0] == 179
-                && (message[1] == 13 || message[1] == 19 || message[1] == 25 || message[1] == 31)
-            {
+            } else if channel == 4 && (id == 13 || id == 19 || id == 25 || id == 31) {
                 tx_input
-                    .send(TwisterMessage::RightButton(message[2] > 0))
+                    .send(TwisterMessage::RightButton(value > 0))
                     .unwrap();
             }
         });
@@ -215,30 +225,30 @@ impl Twister {
                             Control::ChannelVolume(channel) => {
                                 let cc =
                                     channel_offsets[channel as usize % channel_offsets.len()] + 0;
-                                throttled_main_output.send(&[
-                                    176 - 1 + main_channel,
-                                    cc as u8,
-                                    midi_ease_out(value),
-                                ]);
+                                throttled_main_output.send(MidiMessage::ControlChange {
+                                    channel: main_channel,
+                                    control: cc as u8,
+                                    value: midi_ease_out(value),
+                                });
                             }
 
                             Control::ChannelReverb(channel) => {
                                 let cc =
                                     channel_offsets[channel as usize % channel_offsets.len()] + 1;
-                                throttled_main_output.send(&[
-                                    176 - 1 + main_channel,
-                                    cc as u8,
-                                    midi_ease_out(value),
-                                ]);
+                                throttled_main_output.send(MidiMessage::ControlChange {
+                                    channel: main_channel,
+                                    control: cc as u8,
+                                    value: midi_ease_out(value),
+                                });
                             }
                             Control::ChannelDelay(channel) => {
                                 let cc =
                                     channel_offsets[channel as usize % channel_offsets.len()] + 2;
-                                throttled_main_output.send(&[
-                                    176 - 1 + main_channel,
-                                    cc as u8,
-                                    midi_ease_out(value),
-                                ]);
+                                throttled_main_output.send(MidiMessage::ControlChange {
+                                    channel: main_channel,
+                                    control: cc as u8,
+                                    value: midi_ease_out(value),
+                                });
                             }
 
                             Control::ChannelFilter(channel) => {
@@ -248,19 +258,19 @@ impl Twister {
                                     channel_offsets[channel as usize % channel_offsets.len()] + 4;
 
                                 if value > 60 {
-                                    throttled_main_output.send(&[
-                                        176 - 1 + main_channel,
-                                        hp_cc as u8,
-                                        (value.max(64) - 64) * 2,
-                                    ]);
+                                    throttled_main_output.send(MidiMessage::ControlChange {
+                                        channel: main_channel,
+                                        control: hp_cc as u8,
+                                        value: (value.max(64) - 64) * 2,
+                                    });
                                 }
 
                                 if value < 70 {
-                                    throttled_main_output.send(&[
-                                        176 - 1 + main_channel,
-                                        lp_cc as u8,
-                                        value.min(63) * 2,
-                                    ]);
+                                    throttled_main_output.send(MidiMessage::ControlChange {
+                                        channel: main_channel,
+                                        control: lp_cc as u8,
+                                        value: value.min(63) * 2,
+                                    });
                                 }
                             }
 
@@ -366,32 +376,39 @@ impl Twister {
 
                         let value = *last_values.get(&control).unwrap_or(&0);
                         if let Some(id) = control_ids.get(&control) {
-                            output
-                                .send(&[176, id.clone() as u8, *cued_value.unwrap_or(&value)])
-                                .unwrap();
+                            output.send(MidiMessage::ControlChange {
+                                channel: 1,
+                                control: id.clone() as u8,
+                                value: *cued_value.unwrap_or(&value),
+                            });
 
                             let channel = id / 2 % 8;
 
                             // MFT animation for currently looping (Channel 6)
-                            if cued_value.is_some() {
-                                output.send(&[181, id.clone() as u8, 61]).unwrap();
+                            let animation = if cued_value.is_some() {
+                                61
                             // Fast Indicator Pulse
                             } else if cueing {
-                                output.send(&[181, id.clone() as u8, 15]).unwrap();
+                                15
                             // Fast RGB Pulse
                             } else if frozen {
-                                output.send(&[181, id.clone() as u8, 59]).unwrap();
+                                59
                             // Slow Indicator Pulse
                             } else if triggering_channels.contains(&channel) {
-                                output.send(&[181, id.clone() as u8, 17]).unwrap();
+                                17
                             // Turn off indicator (flash)
                             } else if loops.contains_key(&control) {
                                 // control has loop
-                                output.send(&[181, id.clone() as u8, 13]).unwrap();
+                                13
                             // Slow RGB Pulse
                             } else {
-                                output.send(&[181, id.clone() as u8, 0]).unwrap();
-                            }
+                                0
+                            };
+                            output.send(MidiMessage::ControlChange {
+                                channel: 6,
+                                control: id.clone() as u8,
+                                value: animation,
+                            });
                         }
                     }
 
@@ -428,7 +445,11 @@ impl Twister {
                         }
 
                         if current_bank != params.bank {
-                            output.send(&[179, params.bank, 127]).unwrap();
+                            output.send(MidiMessage::ControlChange {
+                                channel: 4,
+                                control: params.bank,
+                                value: 127,
+                            });
                             current_bank = params.bank;
                         }
 
@@ -482,8 +503,16 @@ impl Twister {
                                 }
                             } else {
                                 // force refresh to clear out stalled animations by swapping pages
-                                output.send(&[179, (params.bank + 1) % 4, 127]).unwrap();
-                                output.send(&[179, params.bank, 127]).unwrap();
+                                output.send(MidiMessage::ControlChange {
+                                    channel: 4,
+                                    control: (params.bank + 1) % 4,
+                                    value: 127,
+                                });
+                                output.send(MidiMessage::ControlChange {
+                                    channel: 4,
+                                    control: params.bank,
+                                    value: 127,
+                                });
                             }
 
                             for control in control_ids.keys() {
@@ -538,23 +567,102 @@ impl Twister {
 
                         throttled_main_output.flush();
                     }
+                    TwisterMessage::SaveAutomation(reply) => {
+                        let mut automation = AutomationSession {
+                            events: recorder.events(),
+                            ..AutomationSession::default()
+                        };
+                        for (control, id) in &control_ids {
+                            if let Some(value) = loops.get(control) {
+                                automation.loops.insert(
+                                    *id,
+                                    AutomationLoop {
+                                        offset: value.offset,
+                                        length: value.length,
+                                    },
+                                );
+                            }
+                            if let Some(value) = last_values.get(control) {
+                                automation.values.insert(*id, *value);
+                            }
+                        }
+                        reply.send(automation).ok();
+                    }
+
+                    TwisterMessage::LoadAutomation(automation) => {
+                        let mut automation = automation;
+                        automation.rebase();
+
+                        recorder.restore(automation.events);
+                        loops = automation
+                            .loops
+                            .iter()
+                            .map(|(id, value)| {
+                                (
+                                    Control::from_id(*id),
+                                    Loop {
+                                        offset: value.offset,
+                                        length: value.length,
+                                    },
+                                )
+                            })
+                            .collect();
+
+                        for (id, value) in automation.values {
+                            let control = Control::from_id(id);
+                            if control_ids.contains_key(&control) {
+                                last_values.insert(control, value);
+                                tx_feedback.send(TwisterMessage::Send(control)).unwrap();
+                            }
+                        }
+
+                        for control in control_ids.keys() {
+                            tx_feedback.send(TwisterMessage::Refresh(*control)).unwrap();
+                        }
+                    }
+
+                    TwisterMessage::Close => break,
                 }
             }
         });
 
         Twister {
+            port_name: String::from(port_name),
             _midi_input: input,
             tx: tx_clock,
         }
     }
 }
 
+impl Drop for Twister {
+    fn drop(&mut self) {
+        // stop the feedback thread so a replacement can take over the port
+        self.tx.send(TwisterMessage::Close).ok();
+    }
+}
+
 impl ::controllers::Schedulable for Twister {
     fn schedule(&mut self, pos: MidiTime, length: MidiTime) {
         self.tx
             .send(TwisterMessage::Schedule { pos, length })
             .unwrap();
     }
+
+    fn session_key(&self) -> Option<&str> {
+        Some(&self.port_name)
+    }
+
+    fn save_automation(&self) -> Option<mpsc::Receiver<AutomationSession>> {
+        let (tx, rx) = mpsc::channel();
+        self.tx.send(TwisterMessage::SaveAutomation(tx)).unwrap();
+        Some(rx)
+    }
+
+    fn load_automation(&mut self, automation: AutomationSession) {
+        self.tx
+            .send(TwisterMessage::LoadAutomation(automation))
+            .unwrap();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -568,6 +676,9 @@ enum TwisterMessage {
     LeftButton(bool),
     RightButton(bool),
     Schedule { pos: MidiTime, length: MidiTime },
+    SaveAutomation(mpsc::Sender<AutomationSession>),
+    LoadAutomation(AutomationSession),
+    Close,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
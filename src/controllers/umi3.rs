@@ -1,5 +1,5 @@
 use ::loop_grid_launchpad::LoopGridRemoteEvent;
-use ::midi_connection;
+use ::midi_connection::{self, MidiMessage};
 
 use std::sync::mpsc;
 
@@ -8,17 +8,17 @@ pub struct Umi3 {
 }
 
 impl Umi3 {
-    pub fn new (port_name: &str, remote_tx: mpsc::Sender<LoopGridRemoteEvent>) -> Self {
-        let input = midi_connection::get_input(port_name, move |_stamp, message| {
+    pub fn new (port_name: &str, is_virtual: bool, remote_tx: mpsc::Sender<LoopGridRemoteEvent>) -> Self {
+        let input = midi_connection::get_input_port(port_name, is_virtual, move |_stamp, message| {
             match message {
-                [144, 60, velocity] => {
-                    remote_tx.send(LoopGridRemoteEvent::LoopButton(velocity > &0)).unwrap();
+                MidiMessage::NoteOn { channel: 1, note: 60, velocity } => {
+                    remote_tx.send(LoopGridRemoteEvent::LoopButton(velocity > 0)).unwrap();
                 },
-                [144, 62, velocity] => {
-                    remote_tx.send(LoopGridRemoteEvent::DoubleButton(velocity > &0)).unwrap();
+                MidiMessage::NoteOn { channel: 1, note: 62, velocity } => {
+                    remote_tx.send(LoopGridRemoteEvent::DoubleButton(velocity > 0)).unwrap();
                 },
-                [144, 64, velocity] => {
-                    remote_tx.send(LoopGridRemoteEvent::SustainButton(velocity > &0)).unwrap();
+                MidiMessage::NoteOn { channel: 1, note: 64, velocity } => {
+                    remote_tx.send(LoopGridRemoteEvent::SustainButton(velocity > 0)).unwrap();
                 },
                 _ => ()
             }
@@ -1,4 +1,4 @@
-use ::midi_connection;
+use ::midi_connection::{self, MidiMessage};
 use std::sync::{Arc, Mutex};
 use ::scale::{Scale};
 use ::scheduler::MidiTime;
@@ -34,7 +34,7 @@ impl ::controllers::Schedulable for VT4Key {
         }
 
         if Some(key) != self.last_key {
-            self.midi_output.send(&[176 - 1 + self.channel, 48, key]).unwrap();
+            self.midi_output.send(MidiMessage::ControlChange { channel: self.channel, control: 48, value: key });
             self.last_key = Some(key);
         }
     }
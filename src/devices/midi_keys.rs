@@ -1,5 +1,5 @@
 use chunk::{MidiTime, OutputValue, Triggerable};
-use midi_connection;
+use midi_connection::{self, MidiMessage};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -72,9 +72,11 @@ impl Triggerable for MidiKeys {
                 if self.output_values.contains_key(&id) {
                     let (note_id, _) = *self.output_values.get(&id).unwrap();
 
-                    self.midi_port
-                        .send(&[144 + self.midi_channel - 1, note_id, 0])
-                        .unwrap();
+                    self.midi_port.send(MidiMessage::NoteOn {
+                        channel: self.midi_channel,
+                        note: note_id,
+                        velocity: 0,
+                    });
                     self.output_values.remove(&id);
                 }
             }
@@ -82,31 +84,61 @@ impl Triggerable for MidiKeys {
                 let note_id = get_note_id(id, &self.scale, &self.offset, self.octave_offset);
                 let velocity = ::devices::map_velocity(&self.velocity_map, velocity);
 
-                self.midi_port
-                    .send(&[144 + self.midi_channel - 1, note_id, velocity])
-                    .unwrap();
+                self.midi_port.send(MidiMessage::NoteOn {
+                    channel: self.midi_channel,
+                    note: note_id,
+                    velocity,
+                });
                 self.output_values.insert(id, (note_id, velocity));
             }
         }
     }
 
+    fn get_notes(&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+        let note_id = get_note_id(id, &self.scale, &self.offset, self.octave_offset);
+        vec![(self.midi_channel, note_id, velocity)]
+    }
+
+    fn get_id_for_note(&self, channel: u8, note: u8) -> Option<u32> {
+        if channel != self.midi_channel {
+            return None;
+        }
+
+        // chord inversions make the mapping hard to invert directly, so search (lowest id wins)
+        (0..64).find(|id| get_note_id(*id, &self.scale, &self.offset, self.octave_offset) == note)
+    }
+
+    fn all_notes_off(&mut self) {
+        for (_, (note_id, _)) in self.output_values.drain() {
+            self.midi_port.send(MidiMessage::NoteOn {
+                channel: self.midi_channel,
+                note: note_id,
+                velocity: 0,
+            });
+        }
+    }
+
     fn on_tick(&mut self, _: MidiTime) {
         let mut to_update = HashMap::new();
 
         for (id, (note_id, velocity)) in &self.output_values {
             let new_note_id = get_note_id(*id, &self.scale, &self.offset, self.octave_offset);
             if note_id != &new_note_id {
-                self.midi_port
-                    .send(&[144 + self.midi_channel - 1, *note_id, 0])
-                    .unwrap();
+                self.midi_port.send(MidiMessage::NoteOn {
+                    channel: self.midi_channel,
+                    note: *note_id,
+                    velocity: 0,
+                });
                 to_update.insert(id.clone(), (new_note_id, velocity.clone()));
             }
         }
 
         for (id, item) in to_update {
-            self.midi_port
-                .send(&[144 + self.midi_channel - 1, item.0, item.1])
-                .unwrap();
+            self.midi_port.send(MidiMessage::NoteOn {
+                channel: self.midi_channel,
+                note: item.0,
+                velocity: item.1,
+            });
             self.output_values.insert(id, item);
         }
     }
@@ -1,5 +1,5 @@
 use chunk::{MidiTime, OutputValue, Triggerable};
-use midi_connection;
+use midi_connection::{self, MidiMessage};
 
 use std::{
     collections::HashMap,
@@ -48,14 +48,42 @@ impl Triggerable for MidiTriggers {
         self.last_pos = time;
     }
 
+    fn get_notes(&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+        let note_id = self.trigger_ids[id as usize % self.trigger_ids.len()];
+        vec![(self.midi_channel, note_id, velocity)]
+    }
+
+    fn get_id_for_note(&self, channel: u8, note: u8) -> Option<u32> {
+        if channel != self.midi_channel {
+            return None;
+        }
+
+        self.trigger_ids
+            .iter()
+            .position(|trigger_id| *trigger_id == note)
+            .map(|index| index as u32)
+    }
+
+    fn all_notes_off(&mut self) {
+        for (_, (channel, note_id, _)) in self.output_values.drain() {
+            self.midi_port.send(MidiMessage::NoteOn {
+                channel,
+                note: note_id,
+                velocity: 0,
+            });
+        }
+    }
+
     fn trigger(&mut self, id: u32, value: OutputValue) {
         match value {
             OutputValue::Off => {
                 if self.output_values.contains_key(&id) {
                     let (channel, note_id, _) = *self.output_values.get(&id).unwrap();
-                    self.midi_port
-                        .send(&[144 - 1 + channel, note_id, 0])
-                        .unwrap();
+                    self.midi_port.send(MidiMessage::NoteOn {
+                        channel,
+                        note: note_id,
+                        velocity: 0,
+                    });
                     self.output_values.remove(&id);
                 }
             }
@@ -65,9 +93,11 @@ impl Triggerable for MidiTriggers {
                 let velocity = ::devices::map_velocity(&self.velocity_map, velocity);
 
                 // send note
-                self.midi_port
-                    .send(&[144 - 1 + channel, note_id, velocity])
-                    .unwrap();
+                self.midi_port.send(MidiMessage::NoteOn {
+                    channel,
+                    note: note_id,
+                    velocity,
+                });
 
                 // send sync if kick
                 if let Some(sidechain_output) = &mut self.sidechain_output {
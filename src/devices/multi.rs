@@ -38,4 +38,15 @@ impl Triggerable for MultiChunk {
     fn schedule_mode (&self) -> ScheduleMode { 
         self.chunks[0].schedule_mode()
     }  
+    fn get_notes (&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+        self.chunks.iter().flat_map(|chunk| chunk.get_notes(id, velocity)).collect()
+    }
+    fn get_id_for_note (&self, channel: u8, note: u8) -> Option<u32> {
+        self.chunks.iter().find_map(|chunk| chunk.get_id_for_note(channel, note))
+    }
+    fn all_notes_off (&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            chunk.all_notes_off();
+        }
+    }
 }
\ No newline at end of file
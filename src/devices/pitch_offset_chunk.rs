@@ -1,4 +1,5 @@
 use chunk::{OutputValue, Triggerable};
+use midi_connection::MidiMessage;
 pub use midi_connection::SharedMidiOutputConnection;
 
 use std::collections::HashMap;
@@ -34,24 +35,14 @@ impl Triggerable for PitchOffsetChunk {
         }
 
         let result: i32 = self.output_values.values().sum();
-        let msb_lsb = polar_to_msb_lsb(result as f32 / 12.0);
-        self.midi_output
-            .send(&[224 + self.channel - 1, msb_lsb.0, msb_lsb.1])
-            .unwrap();
+        self.midi_output.send(MidiMessage::PitchBend {
+            channel: self.channel,
+            value: polar_to_pitch_bend(result as f32 / 12.0),
+        });
     }
 }
 
-pub fn polar_to_msb_lsb(input: f32) -> (u8, u8) {
+pub fn polar_to_pitch_bend(input: f32) -> u16 {
     let max = (2.0f32).powf(14.0) / 2.0;
-    let input_14bit = (input.max(-1.0).min(1.0) * max + max) as u16;
-
-    let lsb = mask7(input_14bit as u8);
-    let msb = mask7((input_14bit >> 7) as u8);
-    (lsb, msb)
-}
-
-/// 7 bit mask
-#[inline(always)]
-pub fn mask7(input: u8) -> u8 {
-    input & 0b01111111
+    (input.max(-1.0).min(1.0) * max + max) as u16
 }
@@ -0,0 +1,70 @@
+use super::{
+    launchpad_grid_id, launchpad_grid_note, launchpad_light_channel, launchpad_pad, Button,
+    GridSurface, Light,
+};
+use midi_connection::MidiMessage;
+
+const TOP_BUTTONS: [u8; 8] = [104, 105, 106, 107, 108, 109, 110, 111];
+const RIGHT_SIDE_BUTTONS: [u8; 8] = [89, 79, 69, 59, 49, 39, 29, 19];
+
+// the Launchpad MK2 in session layout: the grid, the top row (cc) and the right side (notes)
+pub struct LaunchpadMk2;
+
+impl LaunchpadMk2 {
+    pub fn new() -> Self {
+        LaunchpadMk2
+    }
+}
+
+impl GridSurface for LaunchpadMk2 {
+    fn button_for(&self, message: &MidiMessage) -> Option<(Button, u8)> {
+        if let Some((note, value)) = launchpad_pad(message) {
+            if let Some(index) = RIGHT_SIDE_BUTTONS.iter().position(|&x| x == note) {
+                return Some((Button::Right(index), value));
+            }
+            return launchpad_grid_id(note).map(|id| (Button::Grid(id), value));
+        }
+
+        match *message {
+            MidiMessage::ControlChange {
+                channel: 1,
+                control,
+                value,
+            } => TOP_BUTTONS
+                .iter()
+                .position(|&x| x == control)
+                .map(|index| (Button::Top(index), value)),
+            _ => None,
+        }
+    }
+
+    fn light(&self, button: Button, light: Light, pulsing: bool) -> Option<MidiMessage> {
+        let channel = launchpad_light_channel(pulsing);
+        let velocity = light.value();
+        match button {
+            Button::Grid(id) if id < 64 => Some(MidiMessage::NoteOn {
+                channel,
+                note: launchpad_grid_note(id),
+                velocity,
+            }),
+            Button::Right(index) if index < 8 => Some(MidiMessage::NoteOn {
+                channel,
+                note: RIGHT_SIDE_BUTTONS[index],
+                velocity,
+            }),
+            Button::Top(index) if index < 8 => Some(MidiMessage::ControlChange {
+                channel,
+                control: TOP_BUTTONS[index],
+                value: velocity,
+            }),
+            _ => None,
+        }
+    }
+
+    fn init_messages(&self) -> Vec<MidiMessage> {
+        // session layout
+        vec![MidiMessage::SysEx(vec![
+            0x00, 0x20, 0x29, 0x02, 0x18, 0x22, 0x00,
+        ])]
+    }
+}
@@ -0,0 +1,89 @@
+use super::{
+    launchpad_grid_id, launchpad_grid_note, launchpad_light_channel, launchpad_pad, Button,
+    GridSurface, Light,
+};
+use midi_connection::MidiMessage;
+
+const TOP_BUTTONS: [u8; 8] = [91, 92, 93, 94, 95, 96, 97, 98];
+const RIGHT_SIDE_BUTTONS: [u8; 8] = [89, 79, 69, 59, 49, 39, 29, 19];
+const LEFT_SIDE_BUTTONS: [u8; 8] = [80, 70, 60, 50, 40, 30, 20, 10];
+const BOTTOM_BUTTONS: [u8; 8] = [101, 102, 103, 104, 105, 106, 107, 108];
+const FOOTER_BUTTONS: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+// every button there is, in programmer mode
+pub struct LaunchpadProMk3;
+
+impl LaunchpadProMk3 {
+    pub fn new() -> Self {
+        LaunchpadProMk3
+    }
+}
+
+impl GridSurface for LaunchpadProMk3 {
+    fn button_for(&self, message: &MidiMessage) -> Option<(Button, u8)> {
+        if let Some((note, value)) = launchpad_pad(message) {
+            return launchpad_grid_id(note).map(|id| (Button::Grid(id), value));
+        }
+
+        match *message {
+            MidiMessage::ControlChange {
+                channel: 1,
+                control,
+                value,
+            } => {
+                let position = |buttons: &[u8]| buttons.iter().position(|&x| x == control);
+                let button = if let Some(index) = position(&LEFT_SIDE_BUTTONS) {
+                    Button::Left(index)
+                } else if let Some(index) = position(&RIGHT_SIDE_BUTTONS) {
+                    Button::Right(index)
+                } else if let Some(index) = position(&TOP_BUTTONS) {
+                    Button::Top(index)
+                } else if let Some(index) = position(&BOTTOM_BUTTONS) {
+                    Button::Bottom(index)
+                } else if let Some(index) = position(&FOOTER_BUTTONS) {
+                    Button::Footer(index)
+                } else {
+                    return None;
+                };
+                Some((button, value))
+            }
+            _ => None,
+        }
+    }
+
+    fn light(&self, button: Button, light: Light, pulsing: bool) -> Option<MidiMessage> {
+        let channel = launchpad_light_channel(pulsing);
+        let velocity = light.value();
+
+        if let Button::Grid(id) = button {
+            if id < 64 {
+                return Some(MidiMessage::NoteOn {
+                    channel,
+                    note: launchpad_grid_note(id),
+                    velocity,
+                });
+            }
+        }
+
+        let control = match button {
+            Button::Top(index) => TOP_BUTTONS.get(index),
+            Button::Left(index) => LEFT_SIDE_BUTTONS.get(index),
+            Button::Right(index) => RIGHT_SIDE_BUTTONS.get(index),
+            Button::Bottom(index) => BOTTOM_BUTTONS.get(index),
+            Button::Footer(index) => FOOTER_BUTTONS.get(index),
+            Button::Grid(_) => None,
+        }?;
+        Some(MidiMessage::ControlChange {
+            channel,
+            control: *control,
+            value: velocity,
+        })
+    }
+
+    fn init_messages(&self) -> Vec<MidiMessage> {
+        // programmer mode
+        vec![MidiMessage::SysEx(vec![
+            0x00, 0x20, 0x29, 0x02, 0x0E, 0x0E, 0x01,
+        ])]
+    }
+}
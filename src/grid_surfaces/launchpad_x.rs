@@ -0,0 +1,85 @@
+use super::{
+    launchpad_grid_id, launchpad_grid_note, launchpad_light_channel, launchpad_pad, Button,
+    GridSurface, Light,
+};
+use midi_connection::MidiMessage;
+
+const TOP_BUTTONS: [u8; 8] = [91, 92, 93, 94, 95, 96, 97, 98];
+const RIGHT_SIDE_BUTTONS: [u8; 8] = [89, 79, 69, 59, 49, 39, 29, 19];
+
+// the Launchpad X and Mini MK3 in programmer mode: the grid, the top row and the right side
+// (no loop length buttons, bottom row, trigger modes or banks)
+pub struct LaunchpadX {
+    device_id: u8,
+}
+
+impl LaunchpadX {
+    pub fn new() -> Self {
+        LaunchpadX { device_id: 0x0C }
+    }
+
+    pub fn mini() -> Self {
+        LaunchpadX { device_id: 0x0D }
+    }
+}
+
+impl GridSurface for LaunchpadX {
+    fn button_for(&self, message: &MidiMessage) -> Option<(Button, u8)> {
+        if let Some((note, value)) = launchpad_pad(message) {
+            return launchpad_grid_id(note).map(|id| (Button::Grid(id), value));
+        }
+
+        match *message {
+            MidiMessage::ControlChange {
+                channel: 1,
+                control,
+                value,
+            } => {
+                if let Some(index) = TOP_BUTTONS.iter().position(|&x| x == control) {
+                    Some((Button::Top(index), value))
+                } else if let Some(index) = RIGHT_SIDE_BUTTONS.iter().position(|&x| x == control) {
+                    Some((Button::Right(index), value))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn light(&self, button: Button, light: Light, pulsing: bool) -> Option<MidiMessage> {
+        let channel = launchpad_light_channel(pulsing);
+        let velocity = light.value();
+        match button {
+            Button::Grid(id) if id < 64 => Some(MidiMessage::NoteOn {
+                channel,
+                note: launchpad_grid_note(id),
+                velocity,
+            }),
+            Button::Top(index) if index < 8 => Some(MidiMessage::ControlChange {
+                channel,
+                control: TOP_BUTTONS[index],
+                value: velocity,
+            }),
+            Button::Right(index) if index < 8 => Some(MidiMessage::ControlChange {
+                channel,
+                control: RIGHT_SIDE_BUTTONS[index],
+                value: velocity,
+            }),
+            _ => None,
+        }
+    }
+
+    fn init_messages(&self) -> Vec<MidiMessage> {
+        // programmer mode
+        vec![MidiMessage::SysEx(vec![
+            0x00,
+            0x20,
+            0x29,
+            0x02,
+            self.device_id,
+            0x0E,
+            0x01,
+        ])]
+    }
+}
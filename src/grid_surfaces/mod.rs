@@ -0,0 +1,221 @@
+mod launchpad_mk2;
+mod launchpad_pro_mk3;
+mod launchpad_x;
+mod note_grid;
+
+use midi_connection::MidiMessage;
+use serde::Deserialize;
+
+pub use self::launchpad_mk2::LaunchpadMk2;
+pub use self::launchpad_pro_mk3::LaunchpadProMk3;
+pub use self::launchpad_x::LaunchpadX;
+pub use self::note_grid::NoteGrid;
+
+// a physical button (and its light), by where it is rather than what MIDI it sends
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub enum Button {
+    // 0 - 63, row by row from the top left
+    Grid(u32),
+    // 0 - 7 from the left: loop, flatten, undo, redo, hold, suppress, scale, shift
+    Top(usize),
+    // 0 - 7 from the top: loop lengths
+    Left(usize),
+    // 0 - 7 from the top: repeat rates (and the beat)
+    Right(usize),
+    // 0 - 7 from the left: the row of pads under the grid
+    Bottom(usize),
+    // 0 - 7 from the left, under the bottom row: trigger modes (0 - 3) and banks (4 - 7)
+    Footer(usize),
+}
+
+// the controller the looper is played on, surfaces that lack some buttons just do without them
+pub trait GridSurface: Send + Sync {
+    // the button a message from the surface is about, and the value it was pressed with (0
+    // when released)
+    fn button_for(&self, message: &MidiMessage) -> Option<(Button, u8)>;
+
+    // the message that lights the button, None if the surface doesn't have it
+    fn light(&self, button: Button, light: Light, pulsing: bool) -> Option<MidiMessage>;
+
+    // sent every time the surface connects (e.g. to put it into programmer mode)
+    fn init_messages(&self) -> Vec<MidiMessage> {
+        Vec::new()
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+pub enum Light {
+    // http://launchpaddr.com/mk2palette/
+    Value(u8),
+    Yellow,
+    YellowMed,
+    Lime,
+    LimeLow,
+    Purple,
+    Green,
+    GreenMed,
+    GreenLow,
+    GreenDark,
+    Orange,
+    OrangeMed,
+    OrangeLow,
+    Red,
+    RedMed,
+    RedLow,
+    BlueDark,
+    White,
+    Off,
+    None,
+}
+
+impl Light {
+    pub fn unwrap_or(self, value: Light) -> Light {
+        match self {
+            Light::None => value,
+            _ => self,
+        }
+    }
+
+    // the index in the Launchpad palette (the MK2, Pro MK3, X and Mini MK3 all share it)
+    pub fn value(&self) -> u8 {
+        match self {
+            Light::Yellow => 13,
+            Light::YellowMed => 97,
+            Light::Lime => 73,
+            Light::LimeLow => 63,
+            Light::Purple => 94,
+            Light::Green => 17,
+            Light::GreenMed => 76,
+            Light::GreenLow => 18,
+            Light::GreenDark => 35,
+            Light::Orange => 96,
+            Light::OrangeMed => 126,
+            Light::OrangeLow => 105,
+            Light::Red => 72,
+            Light::RedMed => 120,
+            Light::RedLow => 6,
+            Light::BlueDark => 43,
+            Light::White => 3,
+            Light::Value(value) => *value,
+            _ => 0,
+        }
+    }
+}
+
+// novation's programmer (and MK2 session) layout: 11 is the bottom left pad, 88 the top right
+fn launchpad_grid_note(id: u32) -> u8 {
+    let row = id / 8;
+    let col = id % 8;
+    ((8 - row) * 10 + col + 1) as u8
+}
+
+fn launchpad_grid_id(note: u8) -> Option<u32> {
+    let row = note / 10;
+    let col = note % 10;
+    if row >= 1 && row <= 8 && col >= 1 && col <= 8 {
+        Some(((8 - row) * 8 + col - 1) as u32)
+    } else {
+        None
+    }
+}
+
+// the pad messages all the launchpads share: velocity (and aftertouch) on channel 1
+fn launchpad_pad(message: &MidiMessage) -> Option<(u8, u8)> {
+    match *message {
+        MidiMessage::NoteOn {
+            channel: 1,
+            note,
+            velocity,
+        } => Some((note, velocity)),
+        MidiMessage::PolyAftertouch {
+            channel: 1,
+            note,
+            pressure,
+        } => Some((note, pressure)),
+        MidiMessage::NoteOff {
+            channel: 1, note, ..
+        } => Some((note, 0)),
+        _ => None,
+    }
+}
+
+// lights sent on channel 3 pulse
+fn launchpad_light_channel(pulsing: bool) -> u8 {
+    if pulsing {
+        3
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_buttons() -> Vec<Button> {
+        let mut result: Vec<Button> = (0..64).map(Button::Grid).collect();
+        for index in 0..8 {
+            result.push(Button::Top(index));
+            result.push(Button::Left(index));
+            result.push(Button::Right(index));
+            result.push(Button::Bottom(index));
+            result.push(Button::Footer(index));
+        }
+        result
+    }
+
+    // pressing whatever a light was sent to must come back as the same button
+    fn assert_round_trips(surface: &dyn GridSurface, expected_count: usize) {
+        let mut count = 0;
+        for button in all_buttons() {
+            let press = match surface.light(button, Light::White, false) {
+                Some(MidiMessage::NoteOn { channel, note, .. }) => MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity: 100,
+                },
+                Some(MidiMessage::ControlChange {
+                    channel, control, ..
+                }) => MidiMessage::ControlChange {
+                    channel,
+                    control,
+                    value: 100,
+                },
+                Some(other) => panic!("unexpected light message {:?}", other),
+                None => continue,
+            };
+            assert_eq!(surface.button_for(&press), Some((button, 100)));
+            count += 1;
+        }
+        assert_eq!(count, expected_count);
+    }
+
+    #[test]
+    fn buttons_round_trip() {
+        assert_round_trips(&LaunchpadProMk3::new(), 64 + 8 * 5);
+        assert_round_trips(&LaunchpadX::new(), 64 + 8 * 2);
+        assert_round_trips(&LaunchpadX::mini(), 64 + 8 * 2);
+        assert_round_trips(&LaunchpadMk2::new(), 64 + 8 * 2);
+        assert_round_trips(&NoteGrid::new(2, 36, true, vec![100, 101], vec![]), 64 + 2);
+    }
+
+    #[test]
+    fn launchpad_layout() {
+        assert_eq!(launchpad_grid_note(0), 81);
+        assert_eq!(launchpad_grid_note(63), 18);
+        assert_eq!(launchpad_grid_id(11), Some(56));
+        assert_eq!(launchpad_grid_id(19), None);
+        assert_eq!(launchpad_grid_id(90), None);
+
+        let surface = LaunchpadProMk3::new();
+        assert_eq!(
+            surface.light(Button::Grid(0), Light::Green, true),
+            Some(MidiMessage::NoteOn {
+                channel: 3,
+                note: 81,
+                velocity: 17
+            })
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use super::{Button, GridSurface, Light};
+use midi_connection::MidiMessage;
+
+// any 8x8 grid of notes on one channel, lit with the launchpad palette as note velocity
+// (no pulsing), with optional notes for the top row and right side buttons
+pub struct NoteGrid {
+    channel: u8,
+    first_note: u8,
+    bottom_up: bool,
+    top_notes: Vec<u8>,
+    right_notes: Vec<u8>,
+}
+
+impl NoteGrid {
+    // first_note is the top left pad (bottom left if bottom_up), the rest follow row by row
+    pub fn new(
+        channel: u8,
+        first_note: u8,
+        bottom_up: bool,
+        top_notes: Vec<u8>,
+        right_notes: Vec<u8>,
+    ) -> Self {
+        NoteGrid {
+            channel,
+            first_note,
+            bottom_up,
+            top_notes,
+            right_notes,
+        }
+    }
+
+    fn grid_note(&self, id: u32) -> u8 {
+        let row = if self.bottom_up { 7 - id / 8 } else { id / 8 };
+        self.first_note + (row * 8 + id % 8) as u8
+    }
+
+    fn grid_id(&self, note: u8) -> Option<u32> {
+        let index = note.checked_sub(self.first_note)? as u32;
+        if index >= 64 {
+            return None;
+        }
+        let row = if self.bottom_up {
+            7 - index / 8
+        } else {
+            index / 8
+        };
+        Some(row * 8 + index % 8)
+    }
+}
+
+impl GridSurface for NoteGrid {
+    fn button_for(&self, message: &MidiMessage) -> Option<(Button, u8)> {
+        let (note, value) = match *message {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if channel == self.channel => (note, velocity),
+            MidiMessage::NoteOff { channel, note, .. } if channel == self.channel => (note, 0),
+            _ => return None,
+        };
+
+        if let Some(index) = self.top_notes.iter().position(|&x| x == note) {
+            Some((Button::Top(index), value))
+        } else if let Some(index) = self.right_notes.iter().position(|&x| x == note) {
+            Some((Button::Right(index), value))
+        } else {
+            self.grid_id(note).map(|id| (Button::Grid(id), value))
+        }
+    }
+
+    fn light(&self, button: Button, light: Light, _pulsing: bool) -> Option<MidiMessage> {
+        let note = match button {
+            Button::Grid(id) if id < 64 => self.grid_note(id),
+            Button::Top(index) => *self.top_notes.get(index)?,
+            Button::Right(index) => *self.right_notes.get(index)?,
+            _ => return None,
+        };
+        Some(MidiMessage::NoteOn {
+            channel: self.channel,
+            note,
+            velocity: light.value(),
+        })
+    }
+}
@@ -1,8 +1,9 @@
 use std::cmp::Ordering;
 use ::output_value::OutputValue;
 use ::midi_time::MidiTime;
+use serde::{Deserialize, Serialize};
 
-#[derive(Eq, Debug, Copy, Clone)]
+#[derive(Eq, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct LoopEvent {
     pub value: OutputValue,
     pub pos: MidiTime,
@@ -0,0 +1,3789 @@
+extern crate circular_queue;
+use self::circular_queue::CircularQueue;
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use grid_surfaces::{Button, Light};
+use midi_file;
+use midi_time::MidiTime;
+use scheduler;
+use tap_tempo::TapTempo;
+
+use chunk::{
+    ChunkMap, Coords, KeyboardInput, LatchMode, MidiMap, RepeatMode, ScheduleMode, Triggerable,
+};
+use loop_recorder::{LoopEvent, LoopRecorder};
+use loop_state::{LoopCollection, LoopState, LoopStateChange, LoopTransform};
+use output_value::OutputValue;
+use session::{LoopSession, LoopSnapshot};
+
+// footer buttons
+const TRIGGER_MODE_BUTTONS: [Button; 4] = [
+    Button::Footer(0),
+    Button::Footer(1),
+    Button::Footer(2),
+    Button::Footer(3),
+];
+const BANK_BUTTONS: [Button; 4] = [
+    Button::Footer(4),
+    Button::Footer(5),
+    Button::Footer(6),
+    Button::Footer(7),
+];
+const BANK_COLORS: [u8; 4] = [17, 17, 17, 17];
+
+const LOOP_BUTTON: Button = Button::Top(0);
+const FLATTEN_BUTTON: Button = Button::Top(1);
+const UNDO_BUTTON: Button = Button::Top(2);
+const REDO_BUTTON: Button = Button::Top(3);
+const HOLD_BUTTON: Button = Button::Top(4);
+const SUPPRESS_BUTTON: Button = Button::Top(5);
+const SESSION_BUTTON: Button = Button::Top(6);
+const SHIFT_BUTTON: Button = Button::Top(7);
+
+// THE LAUNCHPAD PRO MK3 IS JUST TOO DAMN SENSITIVE!
+const VELOCITY_THRESHOLD: u8 = 20;
+
+lazy_static! {
+    static ref REPEAT_RATES: [MidiTime; 8] = [
+        MidiTime::from_measure(2, 1),
+        MidiTime::from_measure(1, 1),
+        MidiTime::from_measure(2, 3),
+        MidiTime::from_measure(1, 2),
+        MidiTime::from_measure(1, 3),
+        MidiTime::from_measure(1, 4),
+        MidiTime::from_measure(1, 6),
+        MidiTime::from_measure(1, 8)
+    ];
+    static ref LOOP_LENGTHS: [MidiTime; 8] = [
+        MidiTime::from_beats(1),
+        MidiTime::from_beats(2),
+        MidiTime::from_beats(3),
+        MidiTime::from_beats(4),
+        MidiTime::from_beats(8),
+        MidiTime::from_beats(16),
+        MidiTime::from_beats(32),
+        MidiTime::from_beats(64)
+    ];
+}
+
+pub struct LoopGridParams {
+    pub swing: f64,
+    pub bank: u8,
+    pub frozen: bool,
+    pub cueing: bool,
+    pub duck_triggered: bool,
+    pub channel_triggered: HashSet<u32>,
+    pub reset_automation: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TriggerMode {
+    Immediate = 0,
+    Quantized = 1,
+    Repeat = 2,
+    Cycle = 3,
+}
+
+impl TriggerMode {
+    fn from_id(id: usize) -> TriggerMode {
+        match id {
+            1 => TriggerMode::Quantized,
+            2 => TriggerMode::Repeat,
+            3 => TriggerMode::Cycle,
+            _ => TriggerMode::Immediate,
+        }
+    }
+
+    fn to_id(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum LoopGridRemoteEvent {
+    DoubleButton(bool),
+    LoopButton(bool),
+    SustainButton(bool),
+}
+
+#[derive(Debug, Clone)]
+struct RepeatState {
+    phase: RepeatPhase,
+    transform: LoopTransform,
+    to: MidiTime,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RepeatPhase {
+    None,
+    Pending,
+    QuantizePending,
+    QuantizeCurrent,
+    Current,
+    Triggered,
+}
+
+enum TransformTarget {
+    All,
+    Main,
+    Scale,
+    Selected,
+}
+
+// what the player did, as the grid it was played on (or an external keyboard) reports it
+#[derive(Debug, Clone)]
+pub enum GridEvent {
+    LoopButton(bool),
+    FlattenButton(bool),
+    UndoButton(bool),
+    RedoButton(bool),
+    HoldButton(bool),
+    SuppressButton(bool),
+    ScaleButton(bool),
+    ShiftButton(bool),
+    None,
+    LengthButton {
+        id: usize,
+        pressed: bool,
+    },
+    RateButton {
+        id: usize,
+        pressed: bool,
+    },
+    TriggerModeButton {
+        id: usize,
+        pressed: bool,
+    },
+    BankButton {
+        id: usize,
+        pressed: bool,
+    },
+    GridInput {
+        id: u32,
+        value: u8,
+        at: Instant,
+    },
+    KeyboardInput {
+        port_name: String,
+        channel: u8,
+        note: u8,
+        value: u8,
+        at: Instant,
+    },
+}
+
+impl GridEvent {
+    // a press (value > 0) or release of a button on the grid
+    pub fn from_button(button: Button, value: u8, at: Instant) -> GridEvent {
+        let pressed = value > 0;
+        match button {
+            Button::Grid(id) => GridEvent::GridInput { at, id, value },
+            // the bottom row plays ids 128 - 135
+            Button::Bottom(index) => GridEvent::GridInput {
+                at,
+                id: 128 + index as u32,
+                value,
+            },
+            Button::Left(id) => GridEvent::LengthButton { id, pressed },
+            Button::Right(id) => GridEvent::RateButton { id, pressed },
+            Button::Top(id) => match id {
+                0 => GridEvent::LoopButton(pressed),
+                1 => GridEvent::FlattenButton(pressed),
+                2 => GridEvent::UndoButton(pressed),
+                3 => GridEvent::RedoButton(pressed),
+                4 => GridEvent::HoldButton(pressed),
+                5 => GridEvent::SuppressButton(pressed),
+                6 => GridEvent::ScaleButton(pressed),
+                7 => GridEvent::ShiftButton(pressed),
+                _ => GridEvent::None,
+            },
+            // the first 4 pick the trigger mode, the last 4 switch banks
+            Button::Footer(id) if id < 4 => GridEvent::TriggerModeButton { id, pressed },
+            Button::Footer(id) => GridEvent::BankButton {
+                id: id - 4,
+                pressed,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct CycleStep {
+    id: u32,
+    value: OutputValue,
+    rate: MidiTime,
+    offset: MidiTime,
+}
+
+// the looper itself: played with GridEvents and moved along by schedule, it triggers the
+// chunks and keeps what every button should show (the owner sends that on to the grid)
+pub struct LoopGrid {
+    params: Arc<Mutex<LoopGridParams>>,
+
+    // handled at the next schedule
+    input_events: Vec<GridEvent>,
+    remote_events: Vec<LoopGridRemoteEvent>,
+
+    // external keyboards playing chunks (by chunk index)
+    keyboards: HashMap<usize, KeyboardInput>,
+
+    mapping: HashMap<Coords, MidiMap>,
+    chunks: Vec<Box<dyn Triggerable>>,
+    chunk_colors: Vec<Light>,
+    chunk_channels: HashMap<usize, u32>,
+    chunk_trigger_ids: Vec<Vec<u32>>,
+
+    no_suppress: HashSet<u32>,
+    no_suppress_held: HashSet<u32>,
+    chunk_repeat_mode: HashMap<usize, RepeatMode>,
+    trigger_latch_for: HashMap<usize, u32>,
+    loop_length: MidiTime,
+
+    repeat_off_beat: bool,
+
+    // selection
+    selection_override: LoopTransform,
+    selection: HashSet<u32>,
+    suppressing: bool,
+    holding: bool,
+    holding_at: MidiTime,
+    shift_held: bool,
+    selection_override_offset: Option<isize>,
+    refresh_loop_length_in: Option<i32>,
+
+    loop_held: bool,
+    loop_from: MidiTime,
+    should_flatten: bool,
+
+    selecting_scale: bool,
+    selecting_scale_held: bool,
+    last_selecting_scale: Instant,
+
+    rate: MidiTime,
+    recorder: LoopRecorder,
+
+    last_pos: MidiTime,
+    last_raw_pos: MidiTime,
+    last_length: MidiTime,
+
+    // where the previous schedule started and when this one did, for placing grid presses
+    prev_raw_pos: MidiTime,
+    scheduled_at: Instant,
+    input_pos: Option<MidiTime>,
+
+    current_bank: u8,
+
+    sustained_values: HashMap<u32, LoopTransform>,
+    override_values: HashMap<u32, LoopTransform>,
+    input_values: HashMap<u32, OutputValue>,
+    currently_held_inputs: Vec<u32>,
+    currently_held_rates: Vec<usize>,
+    last_changed_triggers: HashMap<u32, MidiTime>,
+
+    frozen_loop: Option<LoopCollection>,
+
+    // out state
+    current_swing: f64,
+    out_transforms: HashMap<u32, LoopTransform>,
+    repeat_states: HashMap<u32, RepeatState>,
+
+    out_values: HashMap<u32, OutputValue>,
+    grid_out: HashMap<u32, ButtonLight>,
+    bottom_button_out: HashMap<u32, ButtonLight>,
+    select_out: Light,
+    last_repeat_light_out: Light,
+    last_triggered: HashMap<usize, CircularQueue<u32>>,
+
+    trigger_mode: TriggerMode,
+    chunk_cycle_step: HashMap<usize, CycleStep>,
+    chunk_cycle_next_pos: HashMap<usize, MidiTime>,
+    cycle_groups: HashMap<usize, Vec<CycleStep>>,
+
+    // display state
+    display: HashMap<Button, ButtonLight>,
+    light_changes: Vec<(Button, ButtonLight)>,
+    active: HashSet<u32>,
+    recording: HashSet<u32>,
+
+    // right side buttons
+    last_beat_light: usize,
+    last_repeat_light: usize,
+
+    clock: Option<scheduler::SchedulerHandle>,
+    tap_tempo: TapTempo,
+    tap_light: Light,
+
+    loop_state: LoopState,
+    panic_requested: bool,
+}
+
+impl LoopGrid {
+    pub fn new(chunk_map: Vec<Box<ChunkMap>>, params: Arc<Mutex<LoopGridParams>>) -> Self {
+        let loop_length = MidiTime::from_beats(8);
+        let mut base_loop = LoopCollection::new(loop_length);
+
+        let mut instance = LoopGrid {
+            loop_length,
+            params,
+
+            trigger_mode: TriggerMode::Immediate,
+            chunk_cycle_step: HashMap::new(),
+            chunk_cycle_next_pos: HashMap::new(),
+            cycle_groups: HashMap::new(),
+
+            input_events: Vec::new(),
+            remote_events: Vec::new(),
+            keyboards: HashMap::new(),
+
+            chunk_repeat_mode: HashMap::new(),
+            mapping: HashMap::new(),
+            chunks: Vec::new(),
+            chunk_colors: Vec::new(),
+            chunk_channels: HashMap::new(),
+            chunk_trigger_ids: Vec::new(),
+
+            no_suppress: HashSet::new(),
+            no_suppress_held: HashSet::new(),
+            trigger_latch_for: HashMap::new(),
+
+            repeat_off_beat: false,
+
+            // selection
+            selection_override: LoopTransform::None,
+            selection: HashSet::new(),
+            suppressing: false,
+            holding: false,
+            holding_at: MidiTime::zero(),
+            shift_held: false,
+            selection_override_offset: None,
+            refresh_loop_length_in: None,
+
+            loop_held: false,
+            loop_from: MidiTime::from_ticks(0),
+            should_flatten: false,
+
+            selecting_scale: false,
+            selecting_scale_held: false,
+            last_selecting_scale: Instant::now(),
+
+            rate: MidiTime::from_beats(2),
+            recorder: LoopRecorder::new(),
+
+            last_pos: MidiTime::from_ticks(0),
+            last_raw_pos: MidiTime::from_ticks(0),
+            last_length: MidiTime::from_ticks(0),
+
+            prev_raw_pos: MidiTime::from_ticks(0),
+            scheduled_at: Instant::now(),
+            input_pos: None,
+
+            current_bank: 0,
+
+            sustained_values: HashMap::new(),
+            override_values: HashMap::new(),
+            input_values: HashMap::new(),
+            currently_held_inputs: Vec::new(),
+            currently_held_rates: Vec::new(),
+            last_changed_triggers: HashMap::new(),
+
+            frozen_loop: None,
+
+            // out state
+            current_swing: 0.0,
+            out_transforms: HashMap::new(),
+            repeat_states: HashMap::new(),
+
+            out_values: HashMap::new(),
+            grid_out: HashMap::new(),
+            bottom_button_out: HashMap::new(),
+            select_out: Light::Off,
+            last_repeat_light_out: Light::Off,
+            last_triggered: HashMap::new(),
+
+            // display state
+            display: HashMap::new(),
+            light_changes: Vec::new(),
+            active: HashSet::new(),
+            recording: HashSet::new(),
+
+            last_beat_light: 7,
+            clock: None,
+            tap_tempo: TapTempo::new(),
+            tap_light: Light::None,
+            last_repeat_light: 7,
+
+            loop_state: LoopState::new(loop_length),
+            panic_requested: false,
+        };
+
+        for item in chunk_map {
+            let chunk_index = instance.chunks.len();
+            for trigger_id in instance.register_chunk(chunk_index, item) {
+                base_loop
+                    .transforms
+                    .insert(trigger_id, LoopTransform::Value(OutputValue::On(100)));
+            }
+        }
+
+        // create base level undo
+        instance.loop_state.set(base_loop);
+
+        instance.send_light(HOLD_BUTTON, Light::Value(32), false);
+        instance.send_light(SUPPRESS_BUTTON, Light::Value(57), false);
+        instance.send_light(SESSION_BUTTON, Light::Value(45), false);
+        instance.refresh_loop_button();
+        instance.refresh_undo_redo_lights();
+        instance.refresh_selected_bank();
+        instance.refresh_selected_trigger_mode();
+
+        for id in 0..136 {
+            instance.refresh_grid_button(id);
+        }
+
+        instance
+    }
+
+    // swap in rebuilt chunks (e.g. after a config reload) without touching loops or the recorder
+    pub fn replace_chunks(
+        &mut self,
+        replacements: Vec<(usize, Box<ChunkMap>)>,
+        chunk_count: usize,
+    ) {
+        let mut released: Vec<usize> = replacements.iter().map(|(index, _)| *index).collect();
+        released.extend(chunk_count..self.chunks.len());
+
+        // release everything first so that moved chunks can't clobber each other's mapping
+        for chunk_index in released {
+            self.release_chunk(chunk_index);
+        }
+
+        self.chunks.truncate(chunk_count);
+        self.chunk_trigger_ids.truncate(chunk_count);
+        self.chunk_colors.truncate(chunk_count);
+
+        // like at startup, hold on what the rebuilt chunks report as active (unless a loop
+        // already has something to say about it). A reload isn't something to undo, so this
+        // goes into every loop of the history rather than a new one
+        let mut active_ids = Vec::new();
+        for (chunk_index, item) in replacements {
+            active_ids.extend(self.register_chunk(chunk_index, item));
+        }
+
+        if !active_ids.is_empty() {
+            self.loop_state.amend(|collection| {
+                for trigger_id in &active_ids {
+                    collection
+                        .transforms
+                        .entry(*trigger_id)
+                        .or_insert(LoopTransform::Value(OutputValue::On(100)));
+                }
+            });
+        }
+
+        self.refresh_cycle_groups();
+        for id in 0..136 {
+            self.refresh_override(id);
+            self.refresh_grid_button(id);
+        }
+    }
+
+    // handled at the next schedule, in the order they came in
+    pub fn input(&mut self, event: GridEvent) {
+        self.input_events.push(event);
+    }
+
+    pub fn remote(&mut self, event: LoopGridRemoteEvent) {
+        self.remote_events.push(event);
+    }
+
+    // the lights set since the last call, in order (resending one is left to the grid)
+    pub fn take_lights(&mut self) -> Vec<(Button, ButtonLight)> {
+        std::mem::replace(&mut self.light_changes, Vec::new())
+    }
+
+    // what the button is showing now (the outputs only ever need the changes, see take_lights)
+    #[cfg(test)]
+    fn light(&self, button: Button) -> ButtonLight {
+        self.display
+            .get(&button)
+            .cloned()
+            .unwrap_or(ButtonLight::Constant(Light::Off))
+    }
+
+    // the ports of every keyboard a chunk is listening to, and whether they're virtual
+    pub fn keyboard_ports(&self) -> HashMap<String, bool> {
+        let mut ports = HashMap::new();
+        for keyboard in self.keyboards.values() {
+            *ports.entry(keyboard.port_name.clone()).or_insert(false) |= keyboard.is_virtual;
+        }
+        ports
+    }
+
+    // Shift + Scale + Suppress was pressed since the last call
+    pub fn take_panic_request(&mut self) -> bool {
+        std::mem::replace(&mut self.panic_requested, false)
+    }
+
+    // silence everything the devices are playing, loops carry on from their next events
+    pub fn panic(&mut self) {
+        self.release_all();
+        for chunk in &mut self.chunks {
+            chunk.all_notes_off();
+        }
+    }
+
+    // the clock that tap tempo adjusts (only once the scheduler is running)
+    pub fn set_clock(&mut self, clock: scheduler::SchedulerHandle) {
+        self.clock = Some(clock);
+    }
+
+    // only hands over what was recorded since the last snapshot, the session writer keeps the rest
+    pub fn save_session(&mut self) -> LoopSnapshot {
+        LoopSnapshot {
+            loop_length: self.loop_length,
+            recorded: self.recorder.take_recorded(),
+            undos: self.loop_state.undos().to_vec(),
+            redos: self.loop_state.redos().to_vec(),
+        }
+    }
+
+    pub fn load_session(&mut self, session: LoopSession) {
+        let mut session = session;
+        session.rebase();
+
+        self.recorder.restore(session.events);
+        self.loop_state.restore(session.undos, session.redos);
+        self.set_loop_length(session.loop_length);
+    }
+
+    // renders one pass of the current loop (the one the clock is in) with a track per chunk
+    pub fn export_loop(&self) -> Vec<midi_file::Track> {
+        let collection = self.loop_state.get();
+        let start = self.last_pos.quantize(collection.length);
+        let end = start + collection.length;
+
+        let mut events = Vec::new();
+        let mut cycle_groups: HashMap<usize, Vec<CycleStep>> = HashMap::new();
+        for (id, transform) in &collection.transforms {
+            match transform {
+                &LoopTransform::Value(value) => {
+                    if value.is_on() {
+                        events.push(LoopEvent {
+                            id: *id,
+                            pos: start,
+                            value,
+                        });
+                    }
+                }
+                &LoopTransform::Range { pos, length } => {
+                    self.render_range(*id, pos, length, start, end, &mut events)
+                }
+                &LoopTransform::Repeat {
+                    rate,
+                    offset,
+                    value,
+                } => {
+                    let mut pos = next_repeat(start, rate, offset);
+                    while pos < end {
+                        events.push(LoopEvent {
+                            id: *id,
+                            pos,
+                            value,
+                        });
+                        events.push(LoopEvent {
+                            id: *id,
+                            pos: pos + rate.half(),
+                            value: OutputValue::Off,
+                        });
+                        pos = pos + rate;
+                    }
+                }
+                &LoopTransform::Cycle {
+                    rate,
+                    offset,
+                    value,
+                } => {
+                    if let Some(chunk_index) = self.chunk_index_for_id(*id) {
+                        cycle_groups
+                            .entry(chunk_index)
+                            .or_insert(Vec::new())
+                            .push(CycleStep {
+                                id: *id,
+                                rate,
+                                offset,
+                                value,
+                            });
+                    }
+                }
+                &LoopTransform::None => (),
+            }
+        }
+
+        for steps in cycle_groups.values_mut() {
+            steps.sort_by_key(|step| step.id);
+            render_cycle(steps, start, end, &mut events);
+        }
+
+        let mut tracks: Vec<midi_file::Track> = (0..self.chunks.len())
+            .map(|chunk_index| midi_file::Track::new(&format!("Chunk {}", chunk_index)))
+            .collect();
+        let mut playing: HashMap<u32, Vec<(u8, u8, u8)>> = HashMap::new();
+
+        events.retain(|event| event.pos < end);
+        events.sort_by_key(|event| (event.pos, event.value.is_on()));
+
+        for event in events {
+            if let Some(mapping) = self.mapping.get(&Coords::from(event.id)) {
+                let track = &mut tracks[mapping.chunk_index];
+                for (channel, note, _) in playing.remove(&event.id).unwrap_or_default() {
+                    track.note(event.pos - start, channel, note, 0);
+                }
+
+                if let OutputValue::On(velocity) = event.value {
+                    let notes = self.chunks[mapping.chunk_index].get_notes(mapping.id, velocity);
+                    for (channel, note, velocity) in &notes {
+                        track.note(event.pos - start, *channel, *note, *velocity);
+                    }
+                    playing.insert(event.id, notes);
+                }
+            }
+        }
+
+        // release anything still held when the loop wraps
+        for (id, notes) in playing {
+            if let Some(mapping) = self.mapping.get(&Coords::from(id)) {
+                for (channel, note, _) in notes {
+                    tracks[mapping.chunk_index].note(end - start, channel, note, 0);
+                }
+            }
+        }
+
+        tracks.retain(|track| !track.is_empty());
+        tracks
+    }
+
+    fn render_range(
+        &self,
+        id: u32,
+        range_pos: MidiTime,
+        range_length: MidiTime,
+        start: MidiTime,
+        end: MidiTime,
+        result: &mut Vec<LoopEvent>,
+    ) {
+        // playback is aligned so that output pos % range_length == recorded pos % range_length
+        let mut cycle_start = start - ((start - range_pos) % range_length);
+        let mut value_at_start = None;
+
+        while cycle_start < end {
+            let start_value = match self.recorder.get_event_at(id, range_pos) {
+                Some(event) if event.is_on() => event.value,
+                _ => OutputValue::Off,
+            };
+
+            let mut cycle = vec![(cycle_start, start_value)];
+            if let Some(events) =
+                self.recorder
+                    .get_range_for(id, range_pos, range_pos + range_length)
+            {
+                // one right at the start is already start_value
+                for event in events.iter().filter(|event| event.pos > range_pos) {
+                    cycle.push((cycle_start + (event.pos - range_pos), event.value));
+                }
+            }
+
+            for (pos, value) in cycle {
+                if pos < start {
+                    value_at_start = Some(value);
+                } else {
+                    result.push(LoopEvent { id, pos, value });
+                }
+            }
+
+            cycle_start = cycle_start + range_length;
+        }
+
+        // a note that started before the export window is still sounding at the start
+        if let Some(value) = value_at_start {
+            if value.is_on() {
+                result.push(LoopEvent {
+                    id,
+                    pos: start,
+                    value,
+                });
+            }
+        }
+    }
+
+    // records the notes onto the chunk's pads before anything else in the recorder and loops
+    // them in a new undo step, returns how many notes were mapped and how many had no pad
+    pub fn import_notes(
+        &mut self,
+        chunk_index: usize,
+        notes: &[midi_file::NoteEvent],
+    ) -> (usize, usize) {
+        let trigger_ids = match self.chunk_trigger_ids.get(chunk_index) {
+            Some(trigger_ids) => trigger_ids.clone(),
+            None => return (0, notes.len()),
+        };
+
+        // notes on another channel (or without a pad) don't belong to this chunk
+        let mut skipped = 0;
+        let mut mapped = Vec::new();
+        for note in notes {
+            let trigger_id = self.chunks[chunk_index]
+                .get_id_for_note(note.channel, note.note)
+                .and_then(|id| trigger_ids.get(id as usize));
+
+            if let Some(trigger_id) = trigger_id {
+                mapped.push((*trigger_id, note));
+            } else if note.velocity > 0 {
+                skipped += 1;
+            }
+        }
+
+        // a note-on has to start inside the loop, a note-off can land right on its end
+        let end = mapped
+            .iter()
+            .map(|(_, note)| {
+                if note.velocity > 0 {
+                    note.pos + MidiTime::tick()
+                } else {
+                    note.pos
+                }
+            })
+            .max()
+            .unwrap_or(MidiTime::zero());
+        let bar = MidiTime::from_beats(4);
+        let length = LOOP_LENGTHS
+            .iter()
+            .find(|length| **length >= end)
+            .copied()
+            .unwrap_or_else(|| bar * ((end.ticks() + bar.ticks() - 1) / bar.ticks()));
+
+        // keep clear of anything already recorded (and of zero, where new recordings start)
+        let first_pos = self
+            .recorder
+            .first_pos()
+            .unwrap_or(MidiTime::zero())
+            .min(MidiTime::zero());
+        let start = MidiTime::from_ticks(
+            (first_pos.ticks().div_euclid(length.ticks()) - 1) * length.ticks(),
+        );
+
+        let mut imported = 0;
+        let mut used_ids = HashSet::new();
+        for (trigger_id, note) in mapped {
+            // note-offs on the loop end are taken care of below
+            if note.pos >= length {
+                continue;
+            }
+
+            let value = if note.velocity > 0 {
+                imported += 1;
+                OutputValue::On(note.velocity)
+            } else {
+                OutputValue::Off
+            };
+            self.recorder.add(LoopEvent {
+                id: trigger_id,
+                pos: start + note.pos,
+                value,
+            });
+            used_ids.insert(trigger_id);
+        }
+
+        // make sure notes held over the loop end are released before it starts again
+        let last_tick = start + length - MidiTime::tick();
+        for id in used_ids {
+            let held = match self.recorder.get_event_at(id, last_tick) {
+                Some(event) => event.is_on() && event.pos < last_tick,
+                None => false,
+            };
+            if held {
+                self.recorder.add(LoopEvent {
+                    id,
+                    pos: last_tick,
+                    value: OutputValue::Off,
+                });
+            }
+        }
+
+        let mut collection = self.loop_state.get().clone();
+        for id in trigger_ids {
+            collection
+                .transforms
+                .insert(id, LoopTransform::Range { pos: start, length });
+        }
+        self.loop_state.set(collection);
+
+        (imported, skipped)
+    }
+
+    // returns the trigger ids that the chunk reports as active (excluding latched)
+    fn register_chunk(&mut self, chunk_index: usize, item: Box<ChunkMap>) -> Vec<u32> {
+        let item = *item;
+        let mut count = 0;
+        let mut trigger_ids = Vec::new();
+        let mut active_ids = Vec::new();
+
+        for row in (item.coords.row)..(item.coords.row + item.shape.rows) {
+            for col in (item.coords.col)..(item.coords.col + item.shape.cols) {
+                self.mapping.insert(
+                    Coords::new(row, col),
+                    MidiMap {
+                        chunk_index,
+                        id: count,
+                    },
+                );
+                trigger_ids.push(Coords::id_from(row, col));
+                // preallocate memory for 50,000 recorded events per channel
+                self.recorder.allocate(count, 50000);
+                count += 1;
+            }
+        }
+
+        if item.chunk.latch_mode() == LatchMode::NoSuppress {
+            for id in &trigger_ids {
+                self.no_suppress.insert(*id);
+            }
+        } else if item.chunk.latch_mode() == LatchMode::LatchSuppress {
+            for id in &trigger_ids {
+                self.no_suppress_held.insert(*id);
+            }
+        }
+
+        if item.repeat_mode == RepeatMode::OnlyQuant {
+            for id in &trigger_ids {
+                self.no_suppress_held.insert(*id);
+            }
+        }
+
+        if let Some(active) = item.chunk.get_active() {
+            for id in active {
+                if let Some(trigger_id) = trigger_ids.get(id as usize) {
+                    if item.chunk.latch_mode() == LatchMode::LatchSingle {
+                        self.trigger_latch_for.insert(chunk_index, *trigger_id);
+                    } else {
+                        active_ids.push(*trigger_id);
+                    }
+                }
+            }
+        }
+
+        self.chunk_repeat_mode.insert(chunk_index, item.repeat_mode);
+
+        if let Some(channel) = item.channel {
+            self.chunk_channels.insert(chunk_index, channel);
+        }
+
+        if let Some(keyboard) = item.keyboard {
+            self.keyboards.insert(chunk_index, keyboard);
+        }
+
+        if chunk_index < self.chunks.len() {
+            self.chunk_trigger_ids[chunk_index] = trigger_ids;
+            self.chunk_colors[chunk_index] = Light::Value(item.color);
+            self.chunks[chunk_index] = item.chunk;
+        } else {
+            self.chunk_trigger_ids.push(trigger_ids);
+            self.chunk_colors.push(Light::Value(item.color));
+            self.chunks.push(item.chunk);
+        }
+
+        active_ids
+    }
+
+    // sends offs for anything the chunk is still playing and forgets its layout
+    fn release_chunk(&mut self, chunk_index: usize) {
+        let trigger_ids = match self.chunk_trigger_ids.get(chunk_index) {
+            Some(trigger_ids) => trigger_ids.clone(),
+            None => return,
+        };
+
+        for id in trigger_ids {
+            let coords = Coords::from(id);
+            if let Some(mapped) = self.mapping.get(&coords).copied() {
+                if mapped.chunk_index == chunk_index {
+                    if self.out_values.get(&id).map_or(false, |v| v.is_on()) {
+                        self.chunks[chunk_index].trigger(mapped.id, OutputValue::Off);
+                    }
+                    self.mapping.remove(&coords);
+                }
+            }
+            self.out_values.remove(&id);
+            self.no_suppress.remove(&id);
+            self.no_suppress_held.remove(&id);
+        }
+
+        self.trigger_latch_for.remove(&chunk_index);
+        self.chunk_channels.remove(&chunk_index);
+        self.keyboards.remove(&chunk_index);
+        self.chunk_repeat_mode.remove(&chunk_index);
+        self.last_triggered.remove(&chunk_index);
+        self.cycle_groups.remove(&chunk_index);
+        self.chunk_cycle_step.remove(&chunk_index);
+        self.chunk_cycle_next_pos.remove(&chunk_index);
+    }
+
+    // the button a key plays, if a chunk is listening for it (the first chunk wins)
+    fn keyboard_trigger_id(&self, port_name: &str, channel: u8, note: u8) -> Option<u32> {
+        let mut chunk_indexes: Vec<usize> = self.keyboards.keys().cloned().collect();
+        chunk_indexes.sort();
+        chunk_indexes.into_iter().find_map(|chunk_index| {
+            let keyboard = &self.keyboards[&chunk_index];
+            if keyboard.port_name != port_name {
+                return None;
+            }
+            let index = keyboard.index_for(channel, note)?;
+            self.chunk_trigger_ids[chunk_index].get(index).cloned()
+        })
+    }
+
+    fn grid_event(&mut self, event: GridEvent) {
+        match event {
+            GridEvent::LoopButton(pressed) => {
+                if self.selecting_scale && self.shift_held {
+                    if pressed {
+                        self.tap_tempo();
+                    }
+                } else {
+                    if pressed {
+                        self.start_loop();
+                    } else {
+                        self.end_loop();
+                    }
+                }
+            }
+            GridEvent::FlattenButton(pressed) => {
+                if pressed {
+                    self.commit_selection_override();
+                    if self.should_flatten {
+                        self.flatten();
+                    } else if self.selection.len() > 0 {
+                        self.clear_loops(TransformTarget::Selected, true);
+                    } else {
+                        if self.shift_held {
+                            self.clear_loops(TransformTarget::All, false);
+                            self.clear_automation();
+                        } else {
+                            if self.selecting_scale {
+                                self.clear_loops(TransformTarget::Scale, false);
+                            } else {
+                                self.clear_loops(TransformTarget::Main, false);
+                            }
+                        }
+                    }
+                    self.clear_selection();
+                }
+            }
+            GridEvent::UndoButton(pressed) => {
+                if pressed {
+                    if self.shift_held {
+                        self.halve_loop_length();
+                    } else if self.selection.len() > 0 {
+                        self.undo_selection();
+                    } else {
+                        self.loop_state.undo();
+                    }
+                }
+            }
+            GridEvent::RedoButton(pressed) => {
+                if pressed {
+                    if self.shift_held {
+                        self.double_loop_length();
+                    } else if self.selection.len() > 0 {
+                        self.redo_selection();
+                    } else {
+                        self.loop_state.redo();
+                    }
+                }
+            }
+            GridEvent::HoldButton(pressed) => {
+                self.holding = pressed;
+                self.holding_at = self.last_pos;
+                self.refresh_selection_override();
+                self.refresh_should_flatten();
+            }
+            GridEvent::SuppressButton(pressed) => {
+                if pressed && self.shift_held && self.selecting_scale_held {
+                    // handled by whoever owns the ports (see take_panic_request), the press
+                    // only completes the combo so it doesn't suppress anything
+                    self.panic_requested = true;
+                } else {
+                    self.suppressing = pressed;
+                }
+                self.refresh_selection_override();
+                self.refresh_should_flatten();
+            }
+            GridEvent::ScaleButton(pressed) => {
+                if pressed {
+                    self.last_selecting_scale = Instant::now();
+                    self.selecting_scale = !self.selecting_scale;
+                } else if self.last_selecting_scale.elapsed() > Duration::from_millis(300) {
+                    self.selecting_scale = !self.selecting_scale;
+                }
+
+                self.selecting_scale_held = pressed;
+
+                self.refresh_selecting_scale();
+                self.refresh_undo_redo_lights();
+            }
+            GridEvent::ShiftButton(pressed) => {
+                self.shift_held = pressed;
+                if pressed {
+                    self.clear_selection()
+                }
+
+                self.refresh_selecting_scale();
+                self.refresh_select_state();
+                self.refresh_undo_redo_lights();
+            }
+            GridEvent::LengthButton { id, pressed } => {
+                if pressed {
+                    self.set_loop_length(LOOP_LENGTHS[id % LOOP_LENGTHS.len()]);
+                }
+            }
+            GridEvent::RateButton { id, pressed } => {
+                let current_index = self.currently_held_rates.iter().position(|v| v == &id);
+
+                if pressed && current_index == None {
+                    self.currently_held_rates.push(id);
+                } else if let Some(index) = current_index {
+                    self.currently_held_rates.remove(index);
+                }
+
+                if self.currently_held_rates.len() > 0 {
+                    let id = *self.currently_held_rates.iter().last().unwrap();
+                    let rate = REPEAT_RATES[id as usize];
+                    self.repeat_off_beat = self.shift_held;
+                    self.set_rate(rate);
+                }
+            }
+            GridEvent::TriggerModeButton { id, pressed } => {
+                if pressed {
+                    self.set_trigger_mode(TriggerMode::from_id(id));
+                }
+            }
+            GridEvent::BankButton { id, pressed } => {
+                if pressed {
+                    self.set_bank(id as u8)
+                }
+            }
+            GridEvent::GridInput { id, value, at } => {
+                let value = adjust_velocity(value);
+
+                // record at the position the pad was actually hit, not where the schedule is now
+                self.input_pos = Some(self.pos_at(at));
+                if value > 0 {
+                    self.grid_input(id, OutputValue::On(value));
+                } else {
+                    self.grid_input(id, OutputValue::Off);
+                }
+                self.input_pos = None;
+            }
+            GridEvent::KeyboardInput {
+                port_name,
+                channel,
+                note,
+                value,
+                at,
+            } => {
+                if let Some(id) = self.keyboard_trigger_id(&port_name, channel, note) {
+                    // keyboards aren't over sensitive like the launchpad, velocity is used as is
+                    self.input_pos = Some(self.pos_at(at));
+                    if value > 0 {
+                        self.keyboard_input(id, OutputValue::On(value));
+                    } else {
+                        self.keyboard_input(id, OutputValue::Off);
+                    }
+                    self.input_pos = None;
+                }
+            }
+            GridEvent::None => (),
+        }
+    }
+
+    fn remote_event(&mut self, event: LoopGridRemoteEvent) {
+        match event {
+            LoopGridRemoteEvent::LoopButton(pressed) => {
+                if pressed {
+                    self.start_loop();
+                } else {
+                    self.end_loop();
+                }
+            }
+            LoopGridRemoteEvent::DoubleButton(pressed) => {
+                if pressed {
+                    self.double_loop_length();
+                }
+            }
+            LoopGridRemoteEvent::SustainButton(pressed) => {
+                self.sustain_button(pressed);
+            }
+        }
+    }
+
+    fn drain_input_events(&mut self) {
+        let loop_change_events: Vec<LoopStateChange> =
+            self.loop_state.change_queue.try_iter().collect();
+        for event in loop_change_events {
+            self.initial_loop();
+            self.refresh_active();
+            self.refresh_loop_length();
+            self.refresh_cycle_groups();
+
+            if event == LoopStateChange::Set {
+                self.clear_recording();
+            }
+        }
+
+        let input_events = std::mem::replace(&mut self.input_events, Vec::new());
+        for event in input_events {
+            self.grid_event(event)
+        }
+
+        let remote_events = std::mem::replace(&mut self.remote_events, Vec::new());
+        for event in remote_events {
+            self.remote_event(event)
+        }
+
+        let bank = self.params.lock().unwrap().bank;
+        if self.current_bank != bank {
+            self.current_bank = bank;
+            self.refresh_selected_bank();
+        }
+    }
+
+    // the (swung) position at the given time, between the previous schedule and this one
+    fn pos_at(&self, at: Instant) -> MidiTime {
+        match &self.clock {
+            Some(clock) => self.pos_before(
+                self.scheduled_at.saturating_duration_since(at),
+                clock.tick_duration(),
+            ),
+            None => self.last_pos,
+        }
+    }
+
+    fn pos_before(&self, ago: Duration, tick_duration: Duration) -> MidiTime {
+        let ticks_ago = MidiTime::from_float(ago.as_secs_f64() / tick_duration.as_secs_f64());
+        let raw_pos = if self.last_raw_pos - ticks_ago < self.prev_raw_pos {
+            self.prev_raw_pos
+        } else {
+            self.last_raw_pos - ticks_ago
+        };
+
+        raw_pos.swing(self.current_swing)
+    }
+
+    fn update_swing(&mut self) {
+        let params = self.params.lock().unwrap();
+        self.current_swing = params.swing;
+    }
+
+    pub fn schedule(&mut self, range: scheduler::ScheduleRange) {
+        if range.stopped {
+            self.release_all();
+            return;
+        }
+
+        // only read swing on 8th notes to prevent back scheduling
+        if range.ticked && range.to.floor() % MidiTime::from_ticks(12) == MidiTime::zero() {
+            self.update_swing();
+        }
+
+        if !range.jumped {
+            self.prev_raw_pos = self.last_raw_pos;
+        } else {
+            self.prev_raw_pos = range.from;
+        }
+        self.scheduled_at = Instant::now();
+        self.last_raw_pos = range.from;
+        self.last_pos = range.from.swing(self.current_swing);
+        self.last_length = range.to.swing(self.current_swing) - self.last_pos;
+
+        if range.jumped {
+            self.initial_loop();
+        }
+
+        if range.ticked {
+            // handle revert of loop length button
+            if let Some(remain) = self.refresh_loop_length_in {
+                if remain > 0 {
+                    self.refresh_loop_length_in = Some(remain - 1);
+                } else {
+                    self.refresh_loop_length_in = None;
+                    self.refresh_loop_length();
+                }
+            }
+
+            self.refresh_tap_light();
+            self.refresh_side_buttons();
+            self.refresh_recording();
+            self.chunk_tick();
+        }
+
+        // consume the grid and other controllers
+        self.drain_input_events();
+
+        // clear repeats from last cycle
+        let mut to_refresh = Vec::new();
+
+        for (id, repeat_state) in &mut self.repeat_states {
+            if repeat_state.phase != RepeatPhase::None && self.last_pos >= repeat_state.to {
+                if let Some(LoopTransform::Repeat { rate, offset, .. }) =
+                    self.override_values.get(&id)
+                {
+                    repeat_state.to =
+                        next_repeat(self.last_pos + MidiTime::from_sub_ticks(1), *rate, *offset);
+                    repeat_state.phase = RepeatPhase::Current;
+                } else if let Some(LoopTransform::Cycle { rate, offset, .. }) =
+                    self.override_values.get(&id)
+                {
+                    repeat_state.to =
+                        next_repeat(self.last_pos + MidiTime::from_sub_ticks(1), *rate, *offset);
+                    repeat_state.phase = RepeatPhase::Current;
+                } else if let Some(LoopTransform::Value { .. }) = self.override_values.get(&id) {
+                    // extend quantize
+                    repeat_state.to = repeat_state.to + self.rate - MidiTime::from_sub_ticks(1);
+                    repeat_state.phase = RepeatPhase::QuantizeCurrent;
+                    to_refresh.push(id.clone());
+                } else if repeat_state.phase == RepeatPhase::QuantizeCurrent {
+                    // handle quantize end
+                    repeat_state.phase = RepeatPhase::None;
+                    to_refresh.push(id.clone());
+                } else if let LoopTransform::Value { .. } = repeat_state.transform {
+                    // detect quantize start
+                    repeat_state.to = repeat_state.to + self.rate - MidiTime::from_sub_ticks(1);
+                    repeat_state.phase = RepeatPhase::QuantizeCurrent;
+                    to_refresh.push(id.clone());
+                } else {
+                    repeat_state.phase = RepeatPhase::None;
+                    if !self.currently_held_inputs.contains(&(id % 64)) {
+                        to_refresh.push(id.clone())
+                    }
+                }
+            }
+        }
+
+        for id in to_refresh {
+            self.refresh_override(id);
+            self.refresh_grid_button(id);
+        }
+
+        let mut events = self.get_events();
+        let mut ranked = HashMap::new();
+        for (key, value) in &self.last_triggered {
+            for id in value.iter() {
+                *ranked.entry((key.clone(), id.clone())).or_insert(0) += 1;
+            }
+        }
+
+        // sort events so that earlier defined chunks schedule first
+        events.sort_by(|a, b| {
+            let a_mapping = self.mapping.get(&Coords::from(a.id));
+            let b_mapping = self.mapping.get(&Coords::from(b.id));
+            if let Some(a_mapping) = a_mapping {
+                if let Some(b_mapping) = b_mapping {
+                    let chunk_cmp = a_mapping.chunk_index.cmp(&b_mapping.chunk_index);
+                    let schedule_mode = self
+                        .chunks
+                        .get(a_mapping.chunk_index)
+                        .unwrap()
+                        .schedule_mode();
+                    return if chunk_cmp == Ordering::Equal
+                        && schedule_mode == ScheduleMode::Percussion
+                    {
+                        ranked
+                            .get(&(b_mapping.chunk_index, b_mapping.id))
+                            .unwrap_or(&0)
+                            .cmp(
+                                ranked
+                                    .get(&(a_mapping.chunk_index, a_mapping.id))
+                                    .unwrap_or(&0),
+                            )
+                    } else {
+                        chunk_cmp
+                    };
+                }
+            }
+            a.id.cmp(&b.id)
+        });
+
+        for event in events {
+            if let Some(mapping) = self.mapping.get(&Coords::from(event.id)) {
+                if event.value.is_on() {
+                    self.last_triggered
+                        .entry(mapping.chunk_index)
+                        .or_insert(CircularQueue::with_capacity(8))
+                        .push(event.id);
+                }
+                self.event(event);
+            }
+        }
+
+        self.update_cycle_steps();
+    }
+
+    fn refresh_selected_bank(&mut self) {
+        for (index, button) in BANK_BUTTONS.iter().enumerate() {
+            if self.current_bank == index as u8 {
+                self.send_light(*button, Light::White, true);
+            } else {
+                self.send_light(*button, Light::Value(BANK_COLORS[index]), false);
+            }
+        }
+    }
+
+    fn refresh_selected_trigger_mode(&mut self) {
+        for (index, button) in TRIGGER_MODE_BUTTONS.iter().enumerate() {
+            if self.trigger_mode.to_id() == index {
+                self.send_light(*button, Light::White, true);
+            } else {
+                self.send_light(*button, Light::RedLow, false);
+            }
+        }
+    }
+
+    fn refresh_side_buttons(&mut self) {
+        let pos = self.last_pos;
+
+        let beat_display_multiplier = (24.0 * 8.0) / self.loop_length.ticks() as f64;
+        let shifted_beat_position = (pos.ticks() as f64 * beat_display_multiplier / 24.0) as usize;
+
+        let current_beat_light = shifted_beat_position % 8;
+        let current_repeat_light = REPEAT_RATES
+            .iter()
+            .position(|v| v == &self.rate)
+            .unwrap_or(0);
+
+        let rate_color = if self.repeat_off_beat {
+            Light::RedMed
+        } else {
+            Light::YellowMed
+        };
+
+        if current_repeat_light != self.last_repeat_light
+            || self.last_repeat_light_out != rate_color
+        {
+            self.send_light(Button::Right(self.last_repeat_light), Light::Off, false);
+            self.send_light(Button::Right(current_repeat_light), rate_color, false);
+        }
+
+        let beat_start = pos.is_whole_beat();
+        let base_last_beat_light = if current_repeat_light == self.last_beat_light {
+            rate_color
+        } else {
+            Light::None
+        };
+
+        let base_beat_light = if current_repeat_light == current_beat_light {
+            rate_color
+        } else {
+            Light::None
+        };
+
+        if current_beat_light != self.last_beat_light {
+            self.send_light(
+                Button::Right(self.last_beat_light),
+                base_last_beat_light.unwrap_or(Light::Off),
+                false,
+            );
+            if !beat_start {
+                self.send_light(
+                    Button::Right(current_beat_light),
+                    base_beat_light.unwrap_or(Light::GreenLow),
+                    false,
+                );
+            }
+        }
+
+        // while tapping the tempo, the beat flashes for longer (red if the taps are ignored)
+        let flash_ticks = if self.tap_light == Light::None { 3 } else { 6 };
+        if beat_start {
+            self.send_light(
+                Button::Right(current_beat_light),
+                self.tap_light.unwrap_or(Light::White),
+                false,
+            );
+        } else if pos.beat_tick() == flash_ticks {
+            self.send_light(
+                Button::Right(current_beat_light),
+                base_beat_light.unwrap_or(Light::GreenLow),
+                false,
+            );
+        }
+
+        self.last_beat_light = current_beat_light;
+        self.last_repeat_light = current_repeat_light;
+        self.last_repeat_light_out = rate_color;
+    }
+
+    fn refresh_undo_redo_lights(&mut self) {
+        let color = if self.selecting_scale_held && self.shift_held {
+            // nudging
+            Light::Orange
+        } else if self.shift_held {
+            Light::GreenLow
+        } else {
+            Light::RedLow
+        };
+
+        self.send_light(UNDO_BUTTON, color, false);
+        self.send_light(REDO_BUTTON, color, false);
+    }
+
+    fn refresh_loop_button(&mut self) {
+        self.send_light(LOOP_BUTTON, Light::YellowMed, false);
+    }
+
+    fn refresh_loop_length(&mut self) {
+        for index in 0..LOOP_LENGTHS.len() {
+            let prev_button_length = *LOOP_LENGTHS
+                .get(index.wrapping_sub(1))
+                .unwrap_or(&MidiTime::zero());
+            let button_length = LOOP_LENGTHS[index];
+            let next_button_length = *LOOP_LENGTHS
+                .get(index + 1)
+                .unwrap_or(&(LOOP_LENGTHS[LOOP_LENGTHS.len() - 1] * 2));
+
+            let result = if button_length == self.loop_length {
+                Light::Yellow
+            } else if self.loop_length < button_length && self.loop_length > prev_button_length {
+                Light::Red
+            } else if self.loop_length > button_length && self.loop_length < next_button_length {
+                Light::Red
+            } else {
+                Light::Off
+            };
+
+            self.send_light(Button::Left(index), result, false);
+        }
+    }
+
+    fn send_light(&mut self, button: Button, light: Light, pulsing: bool) {
+        let light = if pulsing {
+            ButtonLight::Pulsing(light)
+        } else {
+            ButtonLight::Constant(light)
+        };
+        self.set_light(button, light);
+    }
+
+    fn set_light(&mut self, button: Button, light: ButtonLight) {
+        self.display.insert(button, light);
+        self.light_changes.push((button, light));
+    }
+
+    fn set_bank(&mut self, id: u8) {
+        let mut params = self.params.lock().unwrap();
+        params.bank = id;
+    }
+
+    fn grid_input(&mut self, id: u32, value: OutputValue) {
+        let current_index = self.currently_held_inputs.iter().position(|v| v == &id);
+        let scale_id = id + 64;
+        let mut fresh_trigger = false;
+
+        if value.is_on() {
+            if current_index == None {
+                self.currently_held_inputs.push(id);
+                fresh_trigger = true;
+            }
+        } else if let Some(index) = current_index {
+            self.currently_held_inputs.remove(index);
+        }
+
+        // use fresh_trigger detection to filter out aftertouch changes
+        if self.shift_held && value.is_on() {
+            if fresh_trigger {
+                if self.selection.contains(&id) {
+                    self.selection.remove(&scale_id);
+                    self.selection.remove(&id);
+                } else {
+                    if self.selecting_scale {
+                        // hack to avoid including drums/vox
+                        self.selection.insert(scale_id);
+                    } else {
+                        self.selection.insert(id);
+                    }
+                }
+
+                // range selection
+                if self.currently_held_inputs.len() == 2 {
+                    let from = Coords::from(self.currently_held_inputs[0]);
+                    let to = Coords::from(self.currently_held_inputs[1]);
+
+                    let from_row = u32::min(from.row, to.row);
+                    let to_row = u32::max(from.row, to.row) + 1;
+                    let from_col = u32::min(from.col, to.col);
+                    let to_col = u32::max(from.col, to.col) + 1;
+
+                    for row in from_row..to_row {
+                        for col in from_col..to_col {
+                            let row_offset = if self.selecting_scale { 8 } else { 0 };
+                            let id = Coords::id_from(row + row_offset, col);
+                            self.selection.insert(id);
+                            self.refresh_grid_button(id);
+                        }
+                    }
+                }
+
+                self.refresh_grid_button(id);
+            }
+        } else {
+            // HACK: filter out aftertouch if that key wasn't already pressed (e.g. after releasing shift while still holding keys)
+            let in_scale_view = id < 128
+                && (self.selecting_scale
+                    && (self.selection.len() == 0 || !self.selection.contains(&id)))
+                || self.selection.contains(&scale_id);
+            let (target_id, other_id) = if in_scale_view {
+                (scale_id, id)
+            } else {
+                (id, scale_id)
+            };
+
+            self.trigger_input(target_id, Some(other_id), value, fresh_trigger);
+        }
+        self.refresh_should_flatten();
+    }
+
+    // a key always plays its own button, whichever view the launchpad is showing
+    fn keyboard_input(&mut self, id: u32, value: OutputValue) {
+        let fresh_trigger = value.is_on()
+            && !self
+                .input_values
+                .get(&id)
+                .unwrap_or(&OutputValue::Off)
+                .is_on();
+        self.trigger_input(id, None, value, fresh_trigger);
+        self.refresh_should_flatten();
+    }
+
+    // plays (and records) the button, releasing the same pad in the other view (main / scale)
+    fn trigger_input(
+        &mut self,
+        target_id: u32,
+        other_id: Option<u32>,
+        value: OutputValue,
+        fresh_trigger: bool,
+    ) {
+        if !value.is_on()
+            || fresh_trigger
+            || self
+                .input_values
+                .get(&target_id)
+                .unwrap_or(&OutputValue::Off)
+                .is_on()
+        {
+            self.input_values.insert(target_id, value);
+            if let Some(other_id) = other_id {
+                self.input_values.remove(&other_id);
+            }
+            self.refresh_input(target_id);
+            if let Some(other_id) = other_id {
+                self.refresh_input(other_id);
+            }
+        }
+    }
+
+    fn refresh_all_inputs(&mut self) {
+        for id in 0..136 {
+            self.refresh_input(id);
+        }
+    }
+
+    fn refresh_input(&mut self, id: u32) {
+        let value = self.input_values.get(&id).unwrap_or(&OutputValue::Off);
+        let original_value = self.override_values.get(&id).cloned();
+        let transform = match value {
+            &OutputValue::On(velocity) => {
+                if let Some(mapped) = self.mapping.get(&Coords::from(id)) {
+                    let offset = if self.repeat_off_beat {
+                        self.rate / 2
+                    } else {
+                        MidiTime::zero()
+                    };
+                    match self
+                        .chunk_repeat_mode
+                        .get(&mapped.chunk_index)
+                        .unwrap_or(&RepeatMode::Global)
+                    {
+                        RepeatMode::None | RepeatMode::OnlyQuant => {
+                            LoopTransform::Value(OutputValue::On(velocity))
+                        }
+                        RepeatMode::NoCycle => match self.trigger_mode {
+                            TriggerMode::Repeat | TriggerMode::Cycle => LoopTransform::Repeat {
+                                rate: self.rate,
+                                offset,
+                                value: OutputValue::On(velocity),
+                            },
+                            _ => LoopTransform::Value(OutputValue::On(velocity)),
+                        },
+                        RepeatMode::Global => match self.trigger_mode {
+                            TriggerMode::Repeat => LoopTransform::Repeat {
+                                rate: self.rate,
+                                offset,
+                                value: OutputValue::On(velocity),
+                            },
+                            TriggerMode::Cycle => LoopTransform::Cycle {
+                                rate: self.rate,
+                                offset,
+                                value: OutputValue::On(velocity),
+                            },
+                            _ => LoopTransform::Value(OutputValue::On(velocity)),
+                        },
+                    }
+                } else {
+                    LoopTransform::None
+                }
+            }
+            &OutputValue::Off => LoopTransform::None,
+        };
+
+        let changed = match self.override_values.entry(id) {
+            Occupied(mut entry) => {
+                let different = entry.get() != &transform;
+                entry.insert(transform.clone());
+                different
+            }
+            Vacant(entry) => {
+                let different = transform != LoopTransform::None;
+                entry.insert(transform.clone());
+                different
+            }
+        };
+
+        if changed {
+            match transform {
+                LoopTransform::Repeat {
+                    rate,
+                    offset,
+                    value,
+                    ..
+                } => {
+                    if !matches!(original_value, Some(LoopTransform::Repeat { .. })) {
+                        // we want to make sure this repeat does full gate cycle, calculate end time from current position
+                        let to = next_repeat(self.last_pos + rate, rate, offset);
+                        self.queue_repeat_trigger(id, transform.clone(), to)
+                    } else if let Some(repeat_state) = self.repeat_states.get_mut(&id) {
+                        // handle changing velocity
+                        if repeat_state.phase == RepeatPhase::Pending
+                            && matches!(repeat_state.transform, LoopTransform::Repeat { .. })
+                        {
+                            // wow, this is not good rust code :'(
+                            if let LoopTransform::Repeat {
+                                value: current_value,
+                                ..
+                            } = repeat_state.transform
+                            {
+                                if value > current_value {
+                                    repeat_state.transform = transform
+                                }
+                            }
+                        } else {
+                            repeat_state.transform = transform
+                        }
+                    }
+                }
+                LoopTransform::Cycle {
+                    rate,
+                    offset,
+                    value,
+                    ..
+                } => {
+                    if !matches!(original_value, Some(LoopTransform::Cycle { .. })) {
+                        // we want to make sure this repeat does full gate cycle, calculate end time from current position
+                        let to = next_repeat(self.last_pos + rate, rate, offset);
+                        self.queue_repeat_trigger(id, transform.clone(), to)
+                    } else if let Some(repeat_state) = self.repeat_states.get_mut(&id) {
+                        // handle changing velocity
+                        if repeat_state.phase == RepeatPhase::Pending
+                            && matches!(repeat_state.transform, LoopTransform::Cycle { .. })
+                        {
+                            // wow, this is not good rust code :'(
+                            if let LoopTransform::Cycle {
+                                value: current_value,
+                                ..
+                            } = repeat_state.transform
+                            {
+                                if value > current_value {
+                                    repeat_state.transform = transform
+                                }
+                            }
+                        } else {
+                            repeat_state.transform = transform
+                        }
+                    }
+                }
+                LoopTransform::Value { .. } => {
+                    let repeat_mode = if let Some(chunk_index) = self.chunk_index_for_id(id) {
+                        self.chunk_repeat_mode
+                            .get(&chunk_index)
+                            .unwrap_or(&RepeatMode::Global)
+                    } else {
+                        &RepeatMode::Global
+                    };
+
+                    if self.trigger_mode == TriggerMode::Quantized
+                        || (repeat_mode == &RepeatMode::OnlyQuant
+                            && self.trigger_mode != TriggerMode::Immediate)
+                    {
+                        if !matches!(original_value, Some(LoopTransform::Value { .. })) {
+                            // we want to make sure this repeat does full gate cycle, calculate end time from current position
+                            let offset = if self.repeat_off_beat {
+                                self.rate / 2
+                            } else {
+                                MidiTime::zero()
+                            };
+                            let to = next_repeat(self.last_pos, self.rate, offset);
+                            self.queue_quantized_trigger(id, transform.clone(), to);
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            if get_schedule_mode(id, &self.chunks, &self.mapping) == ScheduleMode::Monophonic {
+                // refresh all in this chunk if monophonic
+                for id in get_all_ids_in_this_chunk(id, &self.mapping, &self.chunk_trigger_ids) {
+                    self.refresh_override(id);
+                }
+            } else if self.selection.contains(&id) {
+                // refresh all in selection if part of selection
+                for id in self.selection.clone() {
+                    self.refresh_override(id);
+                }
+            } else {
+                self.refresh_override(id);
+            }
+        }
+    }
+
+    fn queue_repeat_trigger(&mut self, id: u32, transform: LoopTransform, to: MidiTime) {
+        self.repeat_states.insert(
+            id,
+            RepeatState {
+                phase: RepeatPhase::Pending,
+                transform,
+                to,
+            },
+        );
+    }
+
+    fn queue_quantized_trigger(&mut self, id: u32, transform: LoopTransform, to: MidiTime) {
+        self.repeat_states.insert(
+            id,
+            RepeatState {
+                phase: RepeatPhase::QuantizePending,
+                transform,
+                to,
+            },
+        );
+    }
+
+    fn refresh_override(&mut self, id: u32) {
+        // use frozen loop if present
+
+        let loop_collection = if let Some(frozen_loop) = &self.frozen_loop {
+            frozen_loop
+        } else {
+            self.loop_state.get()
+        };
+
+        let selection_override_loop_collection = if self.frozen_loop.is_some() {
+            None
+        } else if let Some(offset) = self.selection_override_offset {
+            self.loop_state.retrieve(offset)
+        } else {
+            None
+        };
+
+        let mut transform =
+            self.get_transform(id, &loop_collection, selection_override_loop_collection);
+
+        // suppress if there are inputs held and monophonic scheduling
+        if get_schedule_mode(id, &self.chunks, &self.mapping) == ScheduleMode::Monophonic
+            && transform.is_active()
+        {
+            if !self
+                .override_values
+                .get(&id)
+                .unwrap_or(&LoopTransform::None)
+                .is_active()
+            {
+                // now check to see if any other triggers in the chunk have overrides
+                let ids = get_all_ids_in_this_chunk(id, &self.mapping, &self.chunk_trigger_ids);
+                let chunk_has_override = ids.iter().any(|id| {
+                    self.override_values
+                        .get(id)
+                        .unwrap_or(&LoopTransform::None)
+                        .is_active()
+                });
+                if chunk_has_override {
+                    // suppress this override
+                    transform = LoopTransform::Value(OutputValue::Off);
+                }
+            }
+        }
+
+        // if this note is part of selection, and other notes in selection are being overridden, then suppress this trigger
+        let selection_active = self.selection.iter().any(|x| {
+            self.override_values
+                .get(x)
+                .unwrap_or(&LoopTransform::None)
+                .is_active()
+        });
+        if transform.is_active()
+            && !self
+                .override_values
+                .get(&id)
+                .unwrap_or(&LoopTransform::None)
+                .is_active()
+            && self.selection.contains(&id)
+            && selection_active
+        {
+            transform = LoopTransform::Value(OutputValue::Off);
+        }
+
+        if self
+            .out_transforms
+            .get(&id)
+            .unwrap_or(&LoopTransform::None)
+            .unwrap_or(&LoopTransform::Value(OutputValue::Off))
+            != transform.unwrap_or(&LoopTransform::Value(OutputValue::Off))
+        {
+            let last_transform = self.out_transforms.get(&id).cloned();
+
+            // mark all cycles as changed if one changes
+            if matches!(transform, LoopTransform::Cycle { .. })
+                || matches!(last_transform, Some(LoopTransform::Cycle { .. }))
+            {
+                self.mark_cycle_group_changed(id);
+            }
+
+            let pos = self.input_pos.unwrap_or(self.last_pos);
+            self.last_changed_triggers.insert(id, pos);
+            self.out_transforms.insert(id, transform);
+
+            // send new value
+            if let Some(value) = self.get_value(id, self.last_pos, last_transform) {
+                self.event(LoopEvent { id, value, pos });
+            }
+
+            self.refresh_cycle_group_for(id);
+        }
+    }
+
+    fn refresh_cycle_groups(&mut self) {
+        for id in 0..136 {
+            if let Some(chunk_index) = self.chunk_index_for_id(id) {
+                let repeat_mode = self
+                    .chunk_repeat_mode
+                    .get(&chunk_index)
+                    .unwrap_or(&RepeatMode::None);
+                if repeat_mode == &RepeatMode::Global {
+                    self.refresh_cycle_group_for(id);
+                }
+            }
+        }
+    }
+
+    fn mark_cycle_group_changed(&mut self, id: u32) {
+        if let Some(chunk_index) = self.chunk_index_for_id(id) {
+            if let Some(steps) = self.cycle_groups.get(&chunk_index) {
+                for step in steps {
+                    self.last_changed_triggers.insert(step.id, self.last_pos);
+                }
+            }
+        }
+    }
+
+    fn refresh_bottom_button(&mut self, base_id: u32) {
+        let id = base_id + 128;
+
+        let mapped = self.mapping.get(&Coords::from(id));
+
+        let triggering = if self
+            .out_values
+            .get(&id)
+            .unwrap_or(&OutputValue::Off)
+            .is_on()
+        {
+            true
+        } else if mapped.is_some()
+            && self
+                .trigger_latch_for
+                .contains_key(&mapped.unwrap().chunk_index)
+        {
+            self.trigger_latch_for
+                .get(&mapped.unwrap().chunk_index)
+                .unwrap()
+                == &id
+        } else {
+            false
+        };
+
+        let old_value = self
+            .bottom_button_out
+            .remove(&base_id)
+            .unwrap_or(ButtonLight::Constant(Light::Off));
+
+        let color = if let Some(mapped) = mapped {
+            self.chunk_colors[mapped.chunk_index]
+        } else {
+            Light::Off
+        };
+
+        let selection_color = Light::Green;
+
+        let new_value = if triggering && self.selection.contains(&id) {
+            ButtonLight::Pulsing(Light::White)
+        } else if triggering {
+            ButtonLight::Constant(Light::White)
+        } else if self.selection.contains(&id) {
+            ButtonLight::Pulsing(selection_color)
+        } else if self.recording.contains(&id) {
+            ButtonLight::Pulsing(Light::RedLow)
+        } else if self.active.contains(&id) {
+            ButtonLight::Pulsing(color)
+        } else if self.frozen_loop.is_some() {
+            ButtonLight::Pulsing(Light::Orange)
+        } else {
+            ButtonLight::Constant(color)
+        };
+
+        if new_value != old_value {
+            self.set_light(Button::Bottom(base_id as usize), new_value);
+        }
+
+        self.bottom_button_out.insert(base_id, new_value);
+    }
+
+    fn refresh_grid_button(&mut self, id: u32) {
+        if id >= 128 {
+            return self.refresh_bottom_button(id - 128);
+        }
+
+        let base_id = id % 64;
+
+        let in_scale_view = (self.selecting_scale
+            && (self.selection.len() == 0 || !self.selection.contains(&id)))
+            || (self.shift_held && self.selecting_scale_held)
+            || self.selection.contains(&(base_id + 64));
+
+        let (id, background_id) = if in_scale_view {
+            (base_id + 64, base_id)
+        } else {
+            (base_id, base_id + 64)
+        };
+
+        let mapped = self.mapping.get(&Coords::from(id));
+        let background_mapped = self.mapping.get(&Coords::from(background_id));
+
+        let triggering = if self
+            .out_values
+            .get(&id)
+            .unwrap_or(&OutputValue::Off)
+            .is_on()
+        {
+            true
+        } else if mapped.is_some()
+            && self
+                .trigger_latch_for
+                .contains_key(&mapped.unwrap().chunk_index)
+        {
+            self.trigger_latch_for
+                .get(&mapped.unwrap().chunk_index)
+                .unwrap()
+                == &id
+        } else {
+            false
+        };
+
+        let background_triggering = if self
+            .out_values
+            .get(&background_id)
+            .unwrap_or(&OutputValue::Off)
+            .is_on()
+        {
+            true
+        } else if background_mapped.is_some()
+            && self
+                .trigger_latch_for
+                .contains_key(&background_mapped.unwrap().chunk_index)
+        {
+            self.trigger_latch_for
+                .get(&background_mapped.unwrap().chunk_index)
+                .unwrap()
+                == &background_id
+        } else {
+            false
+        };
+
+        let old_value = self
+            .grid_out
+            .remove(&base_id)
+            .unwrap_or(ButtonLight::Constant(Light::Off));
+
+        let color = if let Some(mapped) = mapped {
+            self.chunk_colors[mapped.chunk_index]
+        } else {
+            Light::Off
+        };
+
+        let selection_color = if in_scale_view {
+            Light::Purple
+        } else {
+            Light::Green
+        };
+
+        let background_color = if let Some(background_mapped) = background_mapped {
+            self.chunk_colors[background_mapped.chunk_index]
+        } else {
+            Light::Off
+        };
+
+        let current_loop = self.loop_state.get();
+        let frozen_differs = if let Some(frozen_loop) = &self.frozen_loop {
+            frozen_loop.transforms.contains_key(&id)
+                && frozen_loop.transforms.get(&id) != current_loop.transforms.get(&id)
+        } else {
+            false
+        };
+
+        let new_value = if triggering && self.selection.contains(&id) {
+            ButtonLight::Pulsing(Light::White)
+        } else if triggering {
+            ButtonLight::Constant(Light::White)
+        } else if self.selection.contains(&id) {
+            ButtonLight::Pulsing(selection_color)
+        } else if frozen_differs {
+            ButtonLight::Pulsing(Light::White)
+        } else if self.recording.contains(&id) {
+            ButtonLight::Pulsing(Light::RedLow)
+        } else if background_triggering {
+            ButtonLight::Constant(background_color)
+        } else if self.active.contains(&id) {
+            ButtonLight::Pulsing(color)
+        } else {
+            ButtonLight::Constant(color)
+        };
+
+        if new_value != old_value {
+            self.set_light(Button::Grid(base_id), new_value);
+        }
+
+        self.grid_out.insert(base_id, new_value);
+    }
+
+    fn refresh_selection_override(&mut self) {
+        self.selection_override = if self.suppressing {
+            LoopTransform::Value(OutputValue::Off)
+        } else if self.holding {
+            LoopTransform::Range {
+                pos: self.holding_at,
+                length: self.rate,
+            }
+        } else {
+            LoopTransform::None
+        };
+
+        for id in 0..136 {
+            self.refresh_override(id);
+        }
+    }
+
+    fn refresh_active(&mut self) {
+        let current_loop = self.loop_state.get();
+        let selection_override_loop_collection = if self.frozen_loop.is_some() {
+            None
+        } else if let Some(offset) = self.selection_override_offset {
+            self.loop_state.retrieve(offset)
+        } else {
+            None
+        };
+
+        let mut ids = HashSet::new();
+        for (id, transform) in &current_loop.transforms {
+            if is_active(transform, id, &self.recorder) {
+                ids.insert(*id);
+            }
+        }
+
+        for id in &self.selection {
+            if let Some(override_loop) = selection_override_loop_collection {
+                if is_active(
+                    override_loop
+                        .transforms
+                        .get(id)
+                        .unwrap_or(&LoopTransform::None),
+                    id,
+                    &self.recorder,
+                ) {
+                    ids.insert(*id);
+                } else {
+                    ids.remove(id);
+                }
+            }
+        }
+
+        let (added, removed) = update_ids(&ids, &mut self.active);
+
+        for id in added {
+            self.refresh_grid_button(id);
+        }
+
+        for id in removed {
+            self.refresh_grid_button(id);
+        }
+    }
+
+    fn refresh_recording(&mut self) {
+        let mut ids = HashSet::new();
+
+        let from = if self.loop_held {
+            self.loop_from
+        } else {
+            self.last_pos - self.loop_length
+        };
+
+        for (id, last_changed) in &self.last_changed_triggers {
+            if last_changed >= &from {
+                ids.insert(*id);
+            }
+        }
+
+        // for (id, value) in &self.override_values  {
+        //     if value != &LoopTransform::None {
+        //         ids.insert(*id);
+        //     }
+        // }
+
+        let (added, removed) = update_ids(&ids, &mut self.recording);
+
+        for id in added {
+            self.refresh_grid_button(id);
+        }
+
+        for id in removed {
+            self.refresh_grid_button(id);
+        }
+    }
+
+    fn refresh_select_state(&mut self) {
+        let new_state = if self.shift_held {
+            Light::Green
+        } else if self.selection.len() > 0 {
+            Light::GreenLow
+        } else {
+            Light::Off
+        };
+
+        if self.select_out != new_state {
+            self.send_light(SHIFT_BUTTON, new_state, true);
+            self.select_out = new_state;
+        }
+    }
+
+    fn event(&mut self, event: LoopEvent) {
+        if let Some(mapped) = self.mapping.get(&Coords::from(event.id)).copied() {
+            let new_value = event.value.clone();
+            // if new_value.is_on() && new_value.value() < 25 {
+            //     // reject less than 10 velocity
+            //     return
+            // }
+            match maybe_update(&mut self.out_values, event.id, new_value) {
+                Some(_) => {
+                    if let Some(chunk) = self.chunks.get(mapped.chunk_index) {
+                        if chunk.latch_mode() == LatchMode::LatchSingle && new_value.is_on() {
+                            // track last triggered, refresh previous latch
+                            let last_id = self.trigger_latch_for.get(&mapped.chunk_index).copied();
+                            self.trigger_latch_for.insert(mapped.chunk_index, event.id);
+
+                            if let Some(id) = last_id {
+                                self.refresh_grid_button(id);
+                            }
+                        }
+                    }
+
+                    self.refresh_grid_button(event.id);
+                    self.trigger_chunk(mapped, new_value);
+
+                    self.handle_repeat_trigger(event.id, new_value);
+                }
+                None => (),
+            };
+
+            self.recorder.add(event);
+
+            // ensuring that repeat state completes a single cycle even if button is released early
+        }
+    }
+
+    fn handle_repeat_trigger(&mut self, id: u32, value: OutputValue) {
+        if let Some(repeat_state) = self.repeat_states.get_mut(&id) {
+            if value.is_on() && repeat_state.phase == RepeatPhase::Pending {
+                repeat_state.phase = RepeatPhase::Current;
+            } else if !value.is_on() && repeat_state.phase == RepeatPhase::Current {
+                repeat_state.phase = RepeatPhase::Triggered;
+            }
+        }
+    }
+
+    fn clear_recording(&mut self) {
+        self.last_changed_triggers.clear();
+    }
+
+    fn tap_tempo(&mut self) {
+        if let Some(clock) = &self.clock {
+            if clock.is_external() {
+                // the tempo belongs to whatever is sending the clock
+                println!("[WARN] ignoring tap tempo while following an external clock");
+            } else if let Some(bpm) = self.tap_tempo.tap(Instant::now()) {
+                clock.set_bpm(bpm);
+            }
+        }
+    }
+
+    // how the beat light flashes while tapping (shift + scale held): red if the tempo is coming
+    // from an external clock, see refresh_side_buttons
+    fn refresh_tap_light(&mut self) {
+        let tapping = self.selecting_scale_held && self.shift_held;
+        self.tap_light = if !tapping {
+            Light::None
+        } else {
+            match &self.clock {
+                Some(clock) if clock.is_external() => Light::Red,
+                _ => Light::White,
+            }
+        };
+    }
+
+    fn start_loop(&mut self) {
+        self.commit_selection_override();
+        self.loop_held = true;
+        self.loop_from = self.last_pos.round();
+        self.send_light(LOOP_BUTTON, Light::Green, false);
+    }
+
+    fn end_loop(&mut self) {
+        self.loop_held = false;
+        self.refresh_loop_button();
+        let since_press = self.last_pos - self.loop_from;
+        let threshold = MidiTime::from_ticks(20);
+        let mut new_loop = self.loop_state.get().clone();
+
+        if since_press > threshold {
+            // loop range between loop button down and up
+            let quantized_length = MidiTime::quantize_length(self.last_pos - self.loop_from);
+            self.set_loop_length(quantized_length);
+        } else {
+            // loop range to loop button down using last loop_length
+            self.loop_from = self.loop_from - self.loop_length
+        }
+
+        let mut recording_ids = HashSet::new();
+
+        for (id, last_change) in &self.last_changed_triggers {
+            if last_change > &self.loop_from {
+                recording_ids.insert(*id);
+            }
+        }
+
+        for (id, value) in &self.override_values {
+            if value != &LoopTransform::None {
+                recording_ids.insert(*id);
+            }
+        }
+
+        for id in &self.selection {
+            // include events in selection when looping
+            recording_ids.insert(*id);
+        }
+
+        for id in 0..136 {
+            // include ids that are recording, or if self.shift_held, all active IDs!
+            let selected = self.shift_held || self.selection.contains(&id);
+            if recording_ids.contains(&id) || (selected && self.active.contains(&id)) {
+                // only include in loop if there are items in the range
+                let current_event = self.recorder.get_event_at(id, self.loop_from);
+                let has_events =
+                    self.recorder
+                        .has_events(id, self.loop_from, self.loop_from + self.loop_length);
+                if has_events || current_event.is_some() {
+                    new_loop.transforms.insert(
+                        id,
+                        LoopTransform::Range {
+                            pos: self.loop_from,
+                            length: self.loop_length,
+                        },
+                    );
+                } else {
+                    new_loop.transforms.insert(id, LoopTransform::None);
+                }
+            }
+        }
+
+        if new_loop.transforms.len() > 0 {
+            new_loop.length = self.loop_length;
+            self.loop_state.set(new_loop);
+            self.clear_recording();
+        }
+
+        self.clear_selection();
+    }
+
+    fn clear_selection(&mut self) {
+        self.commit_selection_override();
+
+        if !self.selecting_scale {
+            self.selecting_scale = false;
+            self.refresh_selecting_scale();
+        }
+
+        let selection = self.selection.clone();
+        self.selection.clear();
+
+        for id in selection {
+            self.refresh_grid_button(id);
+        }
+
+        self.refresh_select_state();
+        self.refresh_selection_override();
+    }
+
+    fn refresh_should_flatten(&mut self) {
+        let loop_collection = self.loop_state.get();
+        let is_sustained = self.sustained_values.iter().any(|(key, value)| {
+            value
+                != loop_collection
+                    .transforms
+                    .get(key)
+                    .unwrap_or(&LoopTransform::None)
+        });
+        let is_overridden = self
+            .override_values
+            .values()
+            .any(|value| value != &LoopTransform::None);
+        let new_value =
+            &self.selection_override != &LoopTransform::None || is_overridden || is_sustained;
+        if new_value != self.should_flatten {
+            self.should_flatten = new_value;
+            let color = if self.should_flatten {
+                Light::GreenLow
+            } else {
+                Light::Off
+            };
+            self.send_light(FLATTEN_BUTTON, color, false);
+        }
+    }
+
+    fn flatten(&mut self) {
+        let mut new_loop = self.loop_state.get().clone();
+
+        for id in 0..136 {
+            let transform = self
+                .out_transforms
+                .get(&id)
+                .unwrap_or(&LoopTransform::None)
+                .clone();
+
+            // check if there are actually events available for this range
+            let is_empty = if let LoopTransform::Range { pos, length } = transform {
+                !self.recorder.has_events(id, pos, pos + length)
+            } else {
+                false
+            };
+
+            if is_empty {
+                new_loop.transforms.insert(id.clone(), LoopTransform::None);
+            } else {
+                new_loop.transforms.insert(id.clone(), transform);
+            }
+        }
+
+        self.loop_state.set(new_loop);
+    }
+
+    fn clear_loops(&mut self, target: TransformTarget, clear_permanent: bool) {
+        let mut new_loop = self.loop_state.get().clone();
+
+        let ids: Vec<u32> = match target {
+            TransformTarget::All => (0..136).collect(),
+            TransformTarget::Main => (0..64).collect(),
+            TransformTarget::Scale => (64..136).collect(),
+            TransformTarget::Selected => self.selection.iter().cloned().collect(),
+        };
+
+        for id in ids {
+            if clear_permanent || !self.no_suppress.contains(&id) {
+                new_loop
+                    .transforms
+                    .insert(id, LoopTransform::Value(OutputValue::Off));
+            }
+        }
+
+        self.loop_state.set(new_loop);
+    }
+
+    fn clear_automation(&mut self) {
+        let mut params = self.params.lock().unwrap();
+        params.reset_automation = true;
+    }
+
+    fn double_loop_length(&mut self) {
+        self.set_loop_length(
+            get_double_loop_length(self.loop_length).min(MidiTime::from_beats(32)),
+        );
+    }
+
+    fn halve_loop_length(&mut self) {
+        self.set_loop_length(
+            get_half_loop_length(self.loop_length).max(MidiTime::from_measure(1, 4)),
+        );
+    }
+
+    fn undo_selection(&mut self) {
+        if let Some(next_offset) = self
+            .loop_state
+            .previous_index_for(self.selection_override_offset.unwrap_or(0), &self.selection)
+        {
+            self.selection_override_offset = Some(next_offset);
+            self.refresh_selection_override();
+        }
+    }
+
+    fn redo_selection(&mut self) {
+        if let Some(next_offset) = self
+            .loop_state
+            .next_index_for(self.selection_override_offset.unwrap_or(0), &self.selection)
+        {
+            self.selection_override_offset = Some(next_offset);
+            self.refresh_selection_override();
+        }
+    }
+
+    fn set_loop_length(&mut self, loop_length: MidiTime) {
+        self.loop_length = loop_length;
+        self.refresh_loop_length();
+    }
+
+    fn sustain_button(&mut self, pressed: bool) {
+        // send frozen to twister
+        if pressed {
+            let current_loop = self.loop_state.get();
+            self.frozen_loop = Some(current_loop.clone());
+
+            for (id, value) in &self.out_transforms {
+                if value != &LoopTransform::None {
+                    self.sustained_values.insert(*id, value.clone());
+                }
+            }
+        } else {
+            self.frozen_loop = None;
+            self.sustained_values.clear();
+        }
+
+        for id in 0..136 {
+            self.refresh_override(id);
+        }
+
+        for id in 0..8 {
+            self.refresh_bottom_button(id);
+        }
+
+        self.refresh_should_flatten();
+
+        let mut params = self.params.lock().unwrap();
+        params.frozen = pressed;
+        params.cueing = false;
+    }
+
+    fn refresh_selecting_scale(&mut self) {
+        if self.selecting_scale {
+            self.send_light(SESSION_BUTTON, Light::Yellow, true);
+        } else {
+            self.send_light(SESSION_BUTTON, Light::Value(45), false);
+        };
+
+        for id in 0..64 {
+            self.refresh_grid_button(id);
+        }
+    }
+
+    fn set_rate(&mut self, value: MidiTime) {
+        self.rate = value;
+        self.refresh_side_buttons();
+        self.refresh_selection_override();
+        self.refresh_override_repeat();
+    }
+
+    fn set_trigger_mode(&mut self, value: TriggerMode) {
+        self.trigger_mode = value;
+        self.refresh_override_repeat();
+        self.refresh_selected_trigger_mode();
+        self.refresh_all_inputs();
+    }
+
+    fn refresh_override_repeat(&mut self) {
+        let mut to_update = HashMap::new();
+        let mut to_refresh = HashSet::new();
+
+        for (id, value) in &self.override_values {
+            if let Some(_) = self.mapping.get(&Coords::from(*id)) {
+                if let &LoopTransform::Repeat { offset, value, .. } = value {
+                    to_update.insert(
+                        *id,
+                        LoopTransform::Repeat {
+                            rate: self.rate,
+                            offset,
+                            value,
+                        },
+                    );
+                } else if let &LoopTransform::Cycle { offset, value, .. } = value {
+                    to_update.insert(
+                        *id,
+                        LoopTransform::Cycle {
+                            rate: self.rate,
+                            offset,
+                            value,
+                        },
+                    );
+                }
+            }
+        }
+
+        for (id, value) in to_update {
+            if let Some(repeat_state) = self.repeat_states.get_mut(&id) {
+                repeat_state.transform = value.clone();
+            }
+            self.override_values.insert(id, value);
+            to_refresh.insert(id);
+        }
+
+        let mut to_update_sustained = HashMap::new();
+
+        for (id, transform) in &self.sustained_values {
+            if let &LoopTransform::Cycle { offset, value, .. } = transform {
+                to_update_sustained.insert(
+                    *id,
+                    LoopTransform::Cycle {
+                        rate: self.rate,
+                        offset,
+                        value,
+                    },
+                );
+            }
+        }
+
+        for (id, value) in to_update_sustained {
+            self.sustained_values.insert(id, value);
+            to_refresh.insert(id);
+        }
+
+        for id in to_refresh {
+            self.refresh_override(id);
+        }
+    }
+
+    fn initial_loop(&mut self) {
+        for id in 0..136 {
+            let loop_collection = if let Some(frozen_loop) = &self.frozen_loop {
+                frozen_loop
+            } else {
+                self.loop_state.get()
+            };
+
+            let selection_override_loop_collection =
+                if let Some(offset) = self.selection_override_offset {
+                    self.loop_state.retrieve(offset)
+                } else {
+                    None
+                };
+            let transform =
+                self.get_transform(id, &loop_collection, selection_override_loop_collection);
+
+            if self.out_transforms.get(&id).unwrap_or(&LoopTransform::None) != &transform {
+                self.out_transforms.insert(id, transform);
+                self.last_changed_triggers.insert(id, self.last_pos);
+
+                // send new value
+                if let Some(value) = self.get_value(id, self.last_pos, None) {
+                    self.event(LoopEvent {
+                        id: id,
+                        value,
+                        pos: self.last_pos,
+                    });
+                }
+            }
+        }
+    }
+
+    // silence everything on transport stop, forgetting the transforms so that a later jump
+    // (Continue or Song Position) sends the current values again
+    fn release_all(&mut self) {
+        let active: Vec<u32> = self
+            .out_values
+            .iter()
+            .filter(|(_, value)| value.is_on())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in active {
+            if let Some(mapped) = self.mapping.get(&Coords::from(id)).copied() {
+                self.trigger_chunk(mapped, OutputValue::Off);
+            }
+            self.out_values.insert(id, OutputValue::Off);
+            self.refresh_grid_button(id);
+        }
+
+        self.out_transforms.clear();
+    }
+
+    fn trigger_chunk(&mut self, map: MidiMap, value: OutputValue) {
+        if let Some(chunk) = self.chunks.get_mut(map.chunk_index) {
+            chunk.trigger(map.id, value);
+            if value.is_on() {
+                if let Some(channel) = self.chunk_channels.get(&map.chunk_index) {
+                    let mut params = self.params.lock().unwrap();
+                    params.channel_triggered.insert(*channel);
+                }
+            }
+        }
+    }
+
+    fn chunk_tick(&mut self) {
+        for chunk in &mut self.chunks {
+            chunk.on_tick(self.last_raw_pos);
+        }
+    }
+
+    fn commit_selection_override(&mut self) {
+        // commit selection override offset
+        if let Some(offset) = self.selection_override_offset {
+            if offset != 0 {
+                let new_loop = if let Some(offset_loop) = self.loop_state.retrieve(offset) {
+                    let mut new_loop = self.loop_state.get().clone();
+                    for id in &self.selection {
+                        if let Some(transform) = offset_loop.transforms.get(id) {
+                            new_loop.transforms.insert(*id, transform.clone());
+                        } else {
+                            new_loop.transforms.remove(id);
+                        }
+                        self.last_changed_triggers
+                            .insert(*id, self.last_pos.clone());
+                    }
+                    Some(new_loop)
+                } else {
+                    None
+                };
+
+                if let Some(new_loop) = new_loop {
+                    self.loop_state.set(new_loop);
+                }
+            }
+
+            self.selection_override_offset = None;
+        }
+    }
+
+    fn chunk_index_for_id(&self, id: u32) -> Option<usize> {
+        let map = self.mapping.get(&Coords::from(id));
+        if let Some(MidiMap { chunk_index, .. }) = map {
+            Some(*chunk_index)
+        } else {
+            None
+        }
+    }
+
+    fn refresh_cycle_group_for(&mut self, id: u32) {
+        if let Some(chunk_index) = self.chunk_index_for_id(id) {
+            let transform = self.out_transforms.get(&id);
+            let list: &mut Vec<CycleStep> =
+                self.cycle_groups.entry(chunk_index).or_insert(Vec::new());
+
+            if let Some(LoopTransform::Cycle {
+                rate,
+                offset,
+                value,
+            }) = transform.cloned()
+            {
+                // make sure it is in cycle group
+                let step = CycleStep {
+                    id,
+                    rate,
+                    offset,
+                    value,
+                };
+                // insert step in ID order
+                match list.binary_search_by(|v| v.id.cmp(&step.id)) {
+                    // ID already exists, replace with new step
+                    Ok(index) => {
+                        list.push(step);
+                        list.swap_remove(index);
+                    }
+
+                    // insert step in order
+                    Err(index) => list.insert(index, step),
+                };
+            } else {
+                // remove it
+                if let Ok(index) = list.binary_search_by(|v| v.id.cmp(&id)) {
+                    list.remove(index);
+
+                    if list.len() > 0 {
+                        // replace current step with new item
+                        if let Entry::Occupied(mut current_step) =
+                            self.chunk_cycle_step.entry(chunk_index)
+                        {
+                            if current_step.get().id == id {
+                                let replacement = list.get(index).unwrap_or(list.get(0).unwrap());
+                                current_step.insert(*replacement);
+                            }
+                        }
+                    }
+                }
+
+                // reset if no more in group
+                if list.len() == 0 {
+                    self.chunk_cycle_step.remove(&chunk_index);
+                    self.chunk_cycle_next_pos.remove(&chunk_index);
+                }
+            }
+        }
+    }
+
+    fn update_cycle_steps(&mut self) {
+        // increment next step and init
+        for (chunk_id, steps) in &self.cycle_groups {
+            if let Some(first_step) = steps.get(0) {
+                if let Some(next_pos) = self.chunk_cycle_next_pos.get_mut(&chunk_id) {
+                    if &self.last_pos > next_pos {
+                        // bump time and increment step
+                        let current_step = self
+                            .chunk_cycle_step
+                            .get(chunk_id)
+                            .unwrap_or(first_step)
+                            .clone();
+                        let current_pos = steps
+                            .iter()
+                            .position(|x| x.id == current_step.id)
+                            .unwrap_or(steps.len() - 1);
+                        let next_step = steps.get(current_pos + 1).unwrap_or(first_step).clone();
+
+                        self.chunk_cycle_step.insert(*chunk_id, next_step);
+                        let pos = next_repeat(self.last_pos, next_step.rate, next_step.offset);
+                        *next_pos = pos;
+                    }
+                } else {
+                    // init time and clear step
+                    let last_step = steps.get(steps.len() - 1).unwrap();
+                    let next_pos = next_repeat(self.last_pos, first_step.rate, first_step.offset);
+                    self.chunk_cycle_step.insert(*chunk_id, *last_step);
+                    self.chunk_cycle_next_pos.insert(*chunk_id, next_pos);
+                }
+            }
+        }
+    }
+
+    fn get_events(&self) -> Vec<LoopEvent> {
+        let mut result = Vec::new();
+        let position = self.last_pos;
+        let length = self.last_length;
+
+        if length > MidiTime::zero() {
+            for (id, transform) in &self.out_transforms {
+                match transform {
+                    &LoopTransform::Range {
+                        pos: range_pos,
+                        length: range_length,
+                    } => {
+                        let playback_offset = range_pos % range_length;
+                        let playback_pos =
+                            range_pos + ((position - playback_offset) % range_length);
+
+                        if range_pos >= playback_pos && range_pos < (playback_pos + length) {
+                            // insert start value
+                            if let Some(value) = self.get_value(*id, range_pos, None) {
+                                LoopEvent {
+                                    id: *id,
+                                    pos: position,
+                                    value,
+                                }
+                                .insert_into(&mut result);
+                            }
+                        }
+
+                        let offset = position - playback_pos;
+                        if let Some(events) =
+                            self.recorder
+                                .get_range_for(*id, playback_pos, playback_pos + length)
+                        {
+                            for event in events {
+                                event.with_pos(event.pos + offset).insert_into(&mut result);
+                            }
+                        }
+                    }
+                    &LoopTransform::Repeat {
+                        rate: repeat_rate,
+                        offset: repeat_offset,
+                        value,
+                    } => {
+                        let next_on = next_repeat(position, repeat_rate, repeat_offset);
+                        let next_off =
+                            next_repeat(position, repeat_rate, repeat_offset + repeat_rate.half());
+                        let to = position + length;
+
+                        if next_on >= position && next_on < to {
+                            LoopEvent {
+                                value,
+                                pos: next_on,
+                                id: id.clone(),
+                            }
+                            .insert_into(&mut result);
+                        }
+
+                        if next_off >= position && next_off < to {
+                            LoopEvent {
+                                value: OutputValue::Off,
+                                pos: next_off,
+                                id: id.clone(),
+                            }
+                            .insert_into(&mut result);
+                        }
+                    }
+                    &LoopTransform::Cycle {
+                        rate: repeat_rate,
+                        offset: repeat_offset,
+                        value,
+                    } => {
+                        let next_on = next_repeat(position, repeat_rate, repeat_offset);
+                        let next_off =
+                            next_repeat(position, repeat_rate, repeat_offset + repeat_rate.half());
+                        let to = position + length;
+
+                        if next_off >= position && next_off < to {
+                            LoopEvent {
+                                value: OutputValue::Off,
+                                pos: next_off,
+                                id: id.clone(),
+                            }
+                            .insert_into(&mut result);
+                        }
+
+                        if next_on >= position && next_on < to {
+                            // only append if is the current trigger for chunk
+                            if let Some(chunk_id) = self.chunk_index_for_id(*id) {
+                                if let Some(step) = self.chunk_cycle_step.get(&chunk_id) {
+                                    if step.id == *id {
+                                        LoopEvent {
+                                            value,
+                                            pos: next_on,
+                                            id: id.clone(),
+                                        }
+                                        .insert_into(&mut result);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        result
+    }
+
+    fn get_transform(
+        &self,
+        id: u32,
+        loop_collection: &LoopCollection,
+        override_collection: Option<&LoopCollection>,
+    ) -> LoopTransform {
+        let mut result = LoopTransform::None;
+
+        let collection = if self.selection.contains(&id) && override_collection.is_some() {
+            override_collection.unwrap()
+        } else {
+            loop_collection
+        };
+
+        if let Some(ref transform) = collection.transforms.get(&id) {
+            result = transform.apply(&result);
+        }
+
+        // we avoid override in these cases unless it is a targeted suppress (in which case it is honored)
+        let avoid_suppress = (self.no_suppress_held.contains(&id)
+            && matches!(result, LoopTransform::Value(..)))
+            || self.no_suppress.contains(&id);
+
+        let sustained_value = self.sustained_values.get(&id);
+
+        // use the sustained value if override value is none
+        // what a mess!
+        if let Some(value) = self.override_values.get(&id) {
+            result = if value == &LoopTransform::None {
+                sustained_value
+                    .unwrap_or(&LoopTransform::None)
+                    .apply(&result)
+            } else {
+                value.apply(&result)
+            }
+        } else if let Some(sustained_value) = sustained_value {
+            result = sustained_value.apply(&result);
+        }
+
+        if ((self.selection.len() == 0 && !avoid_suppress) || self.selection.contains(&id))
+            && result.is_active()
+        {
+            result = self.selection_override.apply(&result);
+        }
+
+        // handle triggering of "early repeat"
+        if let Some(repeat_state) = self.repeat_states.get(&id) {
+            if repeat_state.phase == RepeatPhase::QuantizePending {
+                result = LoopTransform::None
+            } else if repeat_state.phase != RepeatPhase::None {
+                result = repeat_state.transform.clone()
+            }
+        }
+
+        result
+    }
+
+    fn get_value(
+        &self,
+        id: u32,
+        position: MidiTime,
+        compare_value: Option<LoopTransform>,
+    ) -> Option<OutputValue> {
+        match self.out_transforms.get(&id).unwrap_or(&LoopTransform::None) {
+            &LoopTransform::Value(value) => {
+                if let Some(LoopTransform::Value(r_value)) = compare_value {
+                    if value.is_on() == r_value.is_on() {
+                        return None;
+                    }
+                }
+
+                Some(value)
+            }
+            &LoopTransform::Range {
+                pos: range_pos,
+                length: range_length,
+            } => {
+                if let Some(LoopTransform::Range {
+                    pos: r_pos,
+                    length: r_length,
+                }) = compare_value
+                {
+                    if r_pos == range_pos && r_length == range_length {
+                        return None;
+                    }
+                }
+
+                let playback_offset = range_pos % range_length;
+                let playback_pos = range_pos + ((position - playback_offset) % range_length);
+                match self.recorder.get_event_at(id, playback_pos) {
+                    Some(event) if event.is_on() => {
+                        match self.recorder.get_next_event_at(id, playback_pos) {
+                            // don't force an output value if the next event is less than 1 beat away
+                            Some(next_event)
+                                if (next_event.pos - playback_pos) < MidiTime::from_beats(1) =>
+                            {
+                                None
+                            }
+                            _ => Some(event.value),
+                        }
+                    }
+                    _ => Some(OutputValue::Off),
+                }
+            }
+            &LoopTransform::Repeat { rate, offset, .. } => {
+                if let Some(LoopTransform::Repeat {
+                    rate: r_rate,
+                    offset: r_offset,
+                    ..
+                }) = compare_value
+                {
+                    if r_rate == rate && r_offset == offset {
+                        // don't override value if rate and offset are still the same -- it's due to pressure/aftertouch
+                        return None;
+                    }
+                }
+
+                Some(OutputValue::Off)
+            }
+            &LoopTransform::Cycle { rate, offset, .. } => {
+                if let Some(LoopTransform::Cycle {
+                    rate: r_rate,
+                    offset: r_offset,
+                    ..
+                }) = compare_value
+                {
+                    if r_rate == rate && r_offset == offset {
+                        // don't override value if rate and offset are still the same -- it's due to pressure/aftertouch
+                        return None;
+                    }
+                }
+
+                Some(OutputValue::Off)
+            }
+            _ => Some(OutputValue::Off),
+        }
+    }
+}
+
+fn maybe_update(
+    hash_map: &mut HashMap<u32, OutputValue>,
+    key: u32,
+    new_value: OutputValue,
+) -> Option<OutputValue> {
+    let entry = hash_map.entry(key);
+    match entry {
+        Entry::Occupied(mut entry) => {
+            let old_value = entry.insert(new_value);
+
+            // only notify if the value has changed on state (not the specific value) to avoid double triggers with aftertouch
+            if old_value.is_on() != new_value.is_on() {
+                Some(new_value)
+            } else {
+                None
+            }
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(new_value);
+            Some(new_value)
+        }
+    }
+}
+
+fn update_ids<'a>(a: &'a HashSet<u32>, b: &'a mut HashSet<u32>) -> (Vec<u32>, Vec<u32>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+
+    for id in a {
+        if !b.contains(id) {
+            added.push(*id)
+        }
+    }
+
+    for id in b.iter() {
+        if !a.contains(id) {
+            removed.push(*id)
+        }
+    }
+
+    for id in &added {
+        b.insert(*id);
+    }
+
+    for id in &removed {
+        b.remove(id);
+    }
+
+    (added, removed)
+}
+
+// what a button shows, see LoopGrid::take_lights
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ButtonLight {
+    Constant(Light),
+    Pulsing(Light),
+}
+
+// cycle steps take turns in id order, each firing on its own rate (see update_cycle_steps)
+fn render_cycle(steps: &[CycleStep], start: MidiTime, end: MidiTime, result: &mut Vec<LoopEvent>) {
+    let mut index = 0;
+    let mut pos = next_repeat(start, steps[0].rate, steps[0].offset);
+
+    while pos < end {
+        let step = steps[index % steps.len()];
+        result.push(LoopEvent {
+            id: step.id,
+            pos,
+            value: step.value,
+        });
+        result.push(LoopEvent {
+            id: step.id,
+            pos: pos + step.rate.half(),
+            value: OutputValue::Off,
+        });
+
+        index += 1;
+        let next_step = steps[index % steps.len()];
+        pos = next_repeat(pos + MidiTime::tick(), next_step.rate, next_step.offset);
+    }
+}
+
+fn next_repeat(pos: MidiTime, rate: MidiTime, offset: MidiTime) -> MidiTime {
+    let root = pos.quantize(rate) + (offset % rate);
+    let result = if root < pos { root + rate } else { root };
+
+    result
+}
+
+fn get_half_loop_length(time: MidiTime) -> MidiTime {
+    let beats = time.as_float() / 24.0;
+    let prev = prev_power_of_two((beats * 4.0) as u32) as f64 / 4.0;
+    MidiTime::from_float(prev * 24.0)
+}
+
+fn get_double_loop_length(time: MidiTime) -> MidiTime {
+    let beats = time.as_float() / 24.0;
+    let next = next_power_of_two((beats * 4.0) as u32) as f64 / 4.0;
+    MidiTime::from_float(next * 24.0)
+}
+
+fn next_power_of_two(a: u32) -> u32 {
+    let mut b = 1;
+    while b <= a {
+        b = b << 1;
+    }
+    return b;
+}
+
+fn prev_power_of_two(a: u32) -> u32 {
+    let mut b = 1;
+    while b < a {
+        b = b << 1;
+    }
+    return b / 2;
+}
+
+fn get_schedule_mode(
+    id: u32,
+    chunks: &Vec<Box<dyn Triggerable>>,
+    mapping: &HashMap<Coords, MidiMap>,
+) -> ScheduleMode {
+    if let Some(mapping) = mapping.get(&Coords::from(id)) {
+        chunks.get(mapping.chunk_index).unwrap().schedule_mode()
+    } else {
+        ScheduleMode::MostRecent
+    }
+}
+
+fn get_all_ids_in_this_chunk<'a>(
+    id: u32,
+    mapping: &HashMap<Coords, MidiMap>,
+    chunk_trigger_ids: &'a Vec<Vec<u32>>,
+) -> Vec<u32> {
+    if let Some(mapping) = mapping.get(&Coords::from(id)) {
+        chunk_trigger_ids.get(mapping.chunk_index).unwrap().clone()
+    } else {
+        Vec::new()
+    }
+}
+
+fn is_active(transform: &LoopTransform, id: &u32, loop_recorder: &LoopRecorder) -> bool {
+    match transform {
+        LoopTransform::Range { pos, length } => {
+            let has_events = loop_recorder.has_events(*id, *pos, *pos + *length);
+            let has_start_value = if let Some(event) = loop_recorder.get_event_at(*id, *pos) {
+                event.is_on()
+            } else {
+                true
+            };
+
+            has_events || has_start_value
+        }
+        _ => transform.is_active(),
+    }
+}
+
+fn adjust_velocity(input_velocity: u8) -> u8 {
+    if input_velocity < VELOCITY_THRESHOLD {
+        0
+    } else {
+        let range = 127 - VELOCITY_THRESHOLD;
+        let pos = input_velocity - VELOCITY_THRESHOLD;
+        (pos as f64 / range as f64 * 127.0).min(127.0) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::Shape;
+
+    #[test]
+    fn test_adjust_velocity() {
+        assert_eq!(adjust_velocity(0), 0);
+        assert_eq!(adjust_velocity(VELOCITY_THRESHOLD), 0);
+        assert_eq!(adjust_velocity(VELOCITY_THRESHOLD + 1), 1);
+        assert_eq!(adjust_velocity(VELOCITY_THRESHOLD + 2), 2);
+        assert_eq!(adjust_velocity(64), 52);
+        assert_eq!(adjust_velocity(126), 125);
+        assert_eq!(adjust_velocity(127), 127);
+    }
+
+    // remembers every trigger it gets
+    struct Recording(Arc<Mutex<Vec<(u32, OutputValue)>>>);
+
+    impl Triggerable for Recording {
+        fn trigger(&mut self, id: u32, value: OutputValue) {
+            self.0.lock().unwrap().push((id, value));
+        }
+    }
+
+    // a chunk that starts with one of its ids selected (like a scale picker)
+    struct Selecting(u32, Arc<Mutex<Vec<(u32, OutputValue)>>>);
+
+    impl Triggerable for Selecting {
+        fn trigger(&mut self, id: u32, value: OutputValue) {
+            self.1.lock().unwrap().push((id, value));
+        }
+
+        fn get_active(&self) -> Option<HashSet<u32>> {
+            Some(vec![self.0].into_iter().collect())
+        }
+    }
+
+    // plays note 36 + id on channel 2, and maps them back for importing
+    struct Notes;
+
+    impl Triggerable for Notes {
+        fn trigger(&mut self, _id: u32, _value: OutputValue) {}
+
+        fn get_notes(&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+            vec![(2, 36 + id as u8, velocity)]
+        }
+
+        fn get_id_for_note(&self, channel: u8, note: u8) -> Option<u32> {
+            if channel != 2 {
+                return None;
+            }
+            note.checked_sub(36).map(u32::from).filter(|id| *id < 4)
+        }
+    }
+
+    fn notes_looper() -> LoopGrid {
+        with_chunks(vec![ChunkMap::new(
+            Box::new(Notes),
+            Coords::new(0, 0),
+            Shape::new(1, 4),
+            35,
+            None,
+            RepeatMode::Global,
+            None,
+        )])
+    }
+
+    fn note(beats: f64, note: u8, velocity: u8) -> midi_file::NoteEvent {
+        midi_file::NoteEvent {
+            pos: MidiTime::from_float(beats * 24.0),
+            channel: 2,
+            note,
+            velocity,
+        }
+    }
+
+    fn exported(grid: &LoopGrid) -> Vec<midi_file::NoteEvent> {
+        midi_file::parse(&midi_file::to_bytes(&grid.export_loop())).unwrap()
+    }
+
+    // a looper with one 2x2 chunk in the top left corner
+    fn looper() -> (LoopGrid, Arc<Mutex<Vec<(u32, OutputValue)>>>) {
+        let triggered = Arc::new(Mutex::new(Vec::new()));
+        let chunk = ChunkMap::new(
+            Box::new(Recording(Arc::clone(&triggered))),
+            Coords::new(0, 0),
+            Shape::new(2, 2),
+            35,
+            None,
+            RepeatMode::Global,
+            None,
+        );
+        (with_chunks(vec![chunk]), triggered)
+    }
+
+    fn with_chunks(chunks: Vec<Box<ChunkMap>>) -> LoopGrid {
+        let params = Arc::new(Mutex::new(LoopGridParams {
+            swing: 0.0,
+            bank: 0,
+            frozen: false,
+            cueing: false,
+            duck_triggered: false,
+            channel_triggered: HashSet::new(),
+            reset_automation: false,
+        }));
+        LoopGrid::new(chunks, params)
+    }
+
+    // one tick of a running clock
+    fn tick(grid: &mut LoopGrid, ticks: i32) {
+        grid.schedule(scheduler::ScheduleRange {
+            from: MidiTime::from_ticks(ticks),
+            to: MidiTime::from_ticks(ticks + 1),
+            tick_pos: MidiTime::from_ticks(ticks),
+            ticked: true,
+            jumped: false,
+            stopped: false,
+        });
+    }
+
+    fn press(id: u32, value: u8) -> GridEvent {
+        GridEvent::GridInput {
+            id,
+            value,
+            at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn pads_trigger_and_light() {
+        let (mut grid, triggered) = looper();
+        tick(&mut grid, 0);
+        assert_eq!(
+            grid.light(Button::Grid(0)),
+            ButtonLight::Constant(Light::Value(35))
+        );
+        grid.take_lights();
+
+        // the second pad of the chunk is its id 1
+        grid.input(press(1, 127));
+        assert!(triggered.lock().unwrap().is_empty());
+        tick(&mut grid, 1);
+        assert_eq!(*triggered.lock().unwrap(), vec![(1, OutputValue::On(127))]);
+        assert_eq!(
+            grid.light(Button::Grid(1)),
+            ButtonLight::Constant(Light::White)
+        );
+        assert!(grid
+            .take_lights()
+            .contains(&(Button::Grid(1), ButtonLight::Constant(Light::White))));
+
+        grid.input(press(1, 0));
+        tick(&mut grid, 2);
+        assert_eq!(
+            triggered.lock().unwrap().last(),
+            Some(&(1, OutputValue::Off))
+        );
+        // still lit as recording until it's looped (or falls out of the loop length)
+        assert_eq!(
+            grid.light(Button::Grid(1)),
+            ButtonLight::Pulsing(Light::RedLow)
+        );
+
+        // pads outside the chunk don't play anything
+        grid.input(press(63, 127));
+        tick(&mut grid, 3);
+        assert_eq!(triggered.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn loops_what_was_played() {
+        let (mut grid, triggered) = looper();
+        tick(&mut grid, 0);
+        grid.input(press(0, 127));
+        tick(&mut grid, 1);
+        grid.input(press(0, 0));
+        for ticks in 2..192 {
+            tick(&mut grid, ticks);
+        }
+
+        // a tap of the loop button loops the last 8 beats
+        grid.input(GridEvent::LoopButton(true));
+        tick(&mut grid, 192);
+        grid.input(GridEvent::LoopButton(false));
+        triggered.lock().unwrap().clear();
+
+        // the loop starts at 192, so the tap comes back round at 193 and 385
+        for ticks in 193..(192 * 2 + 24) {
+            tick(&mut grid, ticks);
+        }
+        assert_eq!(
+            *triggered.lock().unwrap(),
+            vec![
+                (0, OutputValue::On(127)),
+                (0, OutputValue::Off),
+                (0, OutputValue::On(127)),
+                (0, OutputValue::Off)
+            ]
+        );
+    }
+
+    fn key(note: u8, value: u8) -> GridEvent {
+        GridEvent::KeyboardInput {
+            port_name: String::from("Keys"),
+            channel: 1,
+            note,
+            value,
+            at: Instant::now(),
+        }
+    }
+
+    type Triggered = Arc<Mutex<Vec<(u32, OutputValue)>>>;
+
+    // two 2x2 chunks side by side, played from one keyboard with overlapping notes
+    fn keyboard_looper() -> (LoopGrid, Triggered, Triggered) {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let right = Arc::new(Mutex::new(Vec::new()));
+        let chunk = |triggered: &Triggered, col, first_note| {
+            ChunkMap::new(
+                Box::new(Recording(Arc::clone(triggered))),
+                Coords::new(0, col),
+                Shape::new(2, 2),
+                35,
+                None,
+                RepeatMode::Global,
+                Some(KeyboardInput {
+                    port_name: String::from("Keys"),
+                    is_virtual: false,
+                    channel: None,
+                    first_note,
+                }),
+            )
+        };
+        let grid = with_chunks(vec![chunk(&left, 0, 48), chunk(&right, 2, 50)]);
+        (grid, left, right)
+    }
+
+    #[test]
+    fn keys_play_the_first_chunk_listening_for_them() {
+        let (grid, _, _) = keyboard_looper();
+        // row by row from first_note
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 48), Some(0));
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 50), Some(8));
+        // 50 and 51 are the left chunk's, the right one gets the notes after them
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 52), Some(10));
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 53), Some(11));
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 54), None);
+        assert_eq!(grid.keyboard_trigger_id("Keys", 1, 47), None);
+        assert_eq!(grid.keyboard_trigger_id("Other", 1, 48), None);
+    }
+
+    #[test]
+    fn keys_play_and_loop_while_shift_picks_pads() {
+        let (mut grid, left, right) = keyboard_looper();
+        tick(&mut grid, 0);
+        // shift makes grid presses select, but the keyboard still plays its chunk
+        grid.input(GridEvent::ShiftButton(true));
+        grid.input(key(49, 100));
+        tick(&mut grid, 1);
+        grid.input(key(49, 0));
+        grid.input(GridEvent::ShiftButton(false));
+        for ticks in 2..192 {
+            tick(&mut grid, ticks);
+        }
+        assert!(grid.selection.is_empty());
+        let played = vec![(1, OutputValue::On(100)), (1, OutputValue::Off)];
+        assert_eq!(*left.lock().unwrap(), played);
+        left.lock().unwrap().clear();
+
+        // and what it played is in the loop
+        grid.input(GridEvent::LoopButton(true));
+        tick(&mut grid, 192);
+        grid.input(GridEvent::LoopButton(false));
+        for ticks in 193..(192 + 24) {
+            tick(&mut grid, ticks);
+        }
+        assert_eq!(*left.lock().unwrap(), played);
+        assert!(right.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stopping_releases_everything() {
+        let (mut grid, triggered) = looper();
+        tick(&mut grid, 0);
+        // the first pad of the second row is the chunk's id 2
+        grid.input(press(8, 127));
+        tick(&mut grid, 1);
+        grid.schedule(scheduler::ScheduleRange {
+            from: MidiTime::from_ticks(2),
+            to: MidiTime::from_ticks(2),
+            tick_pos: MidiTime::from_ticks(2),
+            ticked: false,
+            jumped: false,
+            stopped: true,
+        });
+        assert_eq!(
+            *triggered.lock().unwrap(),
+            vec![(2, OutputValue::On(127)), (2, OutputValue::Off)]
+        );
+    }
+
+    #[test]
+    fn replaced_chunks_hold_their_active_ids() {
+        let (mut grid, triggered) = looper();
+        tick(&mut grid, 0);
+
+        // a reload turns the chunk into one with its last id selected
+        let chunk = ChunkMap::new(
+            Box::new(Selecting(3, Arc::clone(&triggered))),
+            Coords::new(0, 0),
+            Shape::new(2, 2),
+            35,
+            None,
+            RepeatMode::Global,
+            None,
+        );
+        grid.replace_chunks(vec![(0, chunk)], 1);
+        tick(&mut grid, 1);
+        assert_eq!(*triggered.lock().unwrap(), vec![(3, OutputValue::On(100))]);
+    }
+
+    #[test]
+    fn reloads_leave_other_loops_and_undo_alone() {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let right = Arc::new(Mutex::new(Vec::new()));
+        let recording = |triggered: &Triggered, col| {
+            ChunkMap::new(
+                Box::new(Recording(Arc::clone(triggered))),
+                Coords::new(0, col),
+                Shape::new(2, 2),
+                35,
+                None,
+                RepeatMode::Global,
+                None,
+            )
+        };
+        let mut grid = with_chunks(vec![recording(&left, 0), recording(&right, 2)]);
+
+        // loop a tap on the left chunk
+        tick(&mut grid, 0);
+        grid.input(press(0, 127));
+        tick(&mut grid, 1);
+        grid.input(press(0, 0));
+        for ticks in 2..192 {
+            tick(&mut grid, ticks);
+        }
+        grid.input(GridEvent::LoopButton(true));
+        tick(&mut grid, 192);
+        grid.input(GridEvent::LoopButton(false));
+        tick(&mut grid, 193);
+        let looped = grid.loop_state.get().transforms.get(&0).cloned();
+        assert!(looped.is_some());
+        let undos = grid.loop_state.undos().len();
+
+        // the reload rebuilds the right chunk with its last id (button 11) selected
+        let chunk = ChunkMap::new(
+            Box::new(Selecting(3, Arc::clone(&right))),
+            Coords::new(0, 2),
+            Shape::new(2, 2),
+            35,
+            None,
+            RepeatMode::Global,
+            None,
+        );
+        grid.replace_chunks(vec![(1, chunk)], 2);
+        tick(&mut grid, 194);
+        assert_eq!(*right.lock().unwrap(), vec![(3, OutputValue::On(100))]);
+        assert_eq!(grid.loop_state.undos().len(), undos);
+        assert_eq!(grid.loop_state.get().transforms.get(&0).cloned(), looped);
+
+        // undo takes back the loop, and the rebuilt chunk stays selected
+        grid.input(GridEvent::UndoButton(true));
+        tick(&mut grid, 195);
+        grid.input(GridEvent::UndoButton(false));
+        tick(&mut grid, 196);
+        assert_eq!(grid.loop_state.undos().len(), undos - 1);
+        assert_eq!(grid.loop_state.get().transforms.get(&0), None);
+        assert_eq!(*right.lock().unwrap(), vec![(3, OutputValue::On(100))]);
+    }
+
+    #[test]
+    fn cycles_take_turns() {
+        let step = |id| CycleStep {
+            id,
+            value: OutputValue::On(100),
+            rate: MidiTime::from_ticks(6),
+            offset: MidiTime::zero(),
+        };
+        let mut events = Vec::new();
+        render_cycle(
+            &[step(0), step(1)],
+            MidiTime::zero(),
+            MidiTime::from_beats(1),
+            &mut events,
+        );
+
+        let ons: Vec<(u32, i32)> = events
+            .iter()
+            .filter(|event| event.value.is_on())
+            .map(|event| (event.id, event.pos.ticks()))
+            .collect();
+        assert_eq!(ons, vec![(0, 0), (1, 6), (0, 12), (1, 18)]);
+        assert_eq!(events.len(), 8);
+    }
+
+    #[test]
+    fn exports_repeats_for_one_pass_of_the_loop() {
+        let mut grid = notes_looper();
+        tick(&mut grid, 0);
+        let mut collection = grid.loop_state.get().clone();
+        collection.transforms.insert(
+            1,
+            LoopTransform::Repeat {
+                rate: MidiTime::from_beats(2),
+                offset: MidiTime::zero(),
+                value: OutputValue::On(80),
+            },
+        );
+        grid.loop_state.set(collection);
+
+        let events = exported(&grid);
+        let ons: Vec<f64> = events
+            .iter()
+            .filter(|event| event.velocity > 0)
+            .map(|event| event.pos.as_float() / 24.0)
+            .collect();
+        assert_eq!(ons, vec![0.0, 2.0, 4.0, 6.0]);
+        assert!(events
+            .iter()
+            .all(|event| event.note == 37 && event.channel == 2));
+        assert_eq!(events[1], note(1.0, 37, 0));
+    }
+
+    #[test]
+    fn imports_and_exports_the_same_notes() {
+        let notes = vec![
+            note(0.0, 36, 90),
+            note(0.5, 36, 0),
+            note(2.0, 38, 30),
+            note(5.0, 38, 0),
+        ];
+
+        let mut grid = notes_looper();
+        tick(&mut grid, 0);
+        // 40 has no pad
+        let mut with_stray = notes.clone();
+        with_stray.push(note(1.0, 40, 100));
+        assert_eq!(grid.import_notes(0, &with_stray), (2, 1));
+
+        // velocities come back as they went in, the device's velocity map is for playback only
+        assert_eq!(exported(&grid), notes);
+
+        let mut reimported = notes_looper();
+        tick(&mut reimported, 0);
+        reimported.import_notes(0, &exported(&grid));
+        assert_eq!(exported(&reimported), notes);
+    }
+
+    #[test]
+    fn imports_a_bar_that_ends_on_the_bar_as_one_bar() {
+        let notes = vec![
+            note(0.0, 36, 100),
+            note(2.0, 36, 0),
+            note(2.0, 37, 100),
+            note(4.0, 37, 0),
+        ];
+
+        let mut grid = notes_looper();
+        tick(&mut grid, 0);
+        assert_eq!(grid.import_notes(0, &notes), (2, 0));
+
+        match grid.loop_state.get().transforms.get(&1) {
+            Some(LoopTransform::Range { length, .. }) => {
+                assert_eq!(*length, MidiTime::from_beats(4))
+            }
+            other => panic!("unexpected transform {:?}", other),
+        }
+    }
+
+    #[test]
+    fn imports_only_the_chunk_channel() {
+        let mut other_channel = note(1.0, 37, 80);
+        other_channel.channel = 3;
+        let mut other_channel_off = note(6.0, 37, 0);
+        other_channel_off.channel = 3;
+        let notes = vec![
+            note(0.0, 36, 90),
+            other_channel,
+            note(1.0, 36, 0),
+            other_channel_off,
+        ];
+
+        let mut grid = notes_looper();
+        tick(&mut grid, 0);
+        assert_eq!(grid.import_notes(0, &notes), (1, 1));
+
+        // the other channel's pad stays empty, and its notes don't stretch the loop
+        assert!(exported(&grid)
+            .iter()
+            .all(|event| event.note == 36 && event.channel == 2));
+        match grid.loop_state.get().transforms.get(&1) {
+            Some(LoopTransform::Range { length, .. }) => {
+                assert_eq!(*length, MidiTime::from_beats(1))
+            }
+            other => panic!("unexpected transform {:?}", other),
+        }
+    }
+
+    #[test]
+    fn beat_light_flashes_longer_while_tapping_the_tempo() {
+        let (mut grid, _) = looper();
+        for ticks in 0..28 {
+            tick(&mut grid, ticks);
+        }
+        // the second beat: white on the beat, back to green after 3 ticks
+        assert_eq!(
+            grid.light(Button::Right(1)),
+            ButtonLight::Constant(Light::GreenLow)
+        );
+
+        grid.input(GridEvent::ShiftButton(true));
+        grid.input(GridEvent::ScaleButton(true));
+        for ticks in 28..52 {
+            tick(&mut grid, ticks);
+        }
+        assert_eq!(
+            grid.light(Button::Right(2)),
+            ButtonLight::Constant(Light::White)
+        );
+        for ticks in 52..54 {
+            tick(&mut grid, ticks);
+        }
+        assert_eq!(
+            grid.light(Button::Right(2)),
+            ButtonLight::Constant(Light::White)
+        );
+        tick(&mut grid, 54);
+        assert_eq!(
+            grid.light(Button::Right(2)),
+            ButtonLight::Constant(Light::GreenLow)
+        );
+    }
+
+    #[test]
+    fn panic_combo_doesnt_suppress() {
+        let (mut grid, _) = looper();
+        grid.input(GridEvent::ShiftButton(true));
+        grid.input(GridEvent::ScaleButton(true));
+        grid.input(GridEvent::SuppressButton(true));
+        tick(&mut grid, 0);
+        assert!(grid.take_panic_request());
+        assert!(!grid.suppressing);
+
+        grid.input(GridEvent::SuppressButton(false));
+        grid.input(GridEvent::ShiftButton(false));
+        grid.input(GridEvent::ScaleButton(false));
+        grid.input(GridEvent::SuppressButton(true));
+        tick(&mut grid, 1);
+        assert!(!grid.take_panic_request());
+        assert!(grid.suppressing);
+    }
+
+    #[test]
+    fn presses_land_where_they_happened_since_the_last_tick() {
+        let (mut grid, _) = looper();
+        for ticks in 0..10 {
+            tick(&mut grid, ticks);
+        }
+
+        let tick_duration = Duration::from_millis(20);
+        let pos = |ms| grid.pos_before(Duration::from_millis(ms), tick_duration);
+        assert_eq!(pos(0), MidiTime::from_ticks(9));
+        assert_eq!(pos(10), MidiTime::from_float(8.5));
+        // never further back than the previous schedule, that's already been played
+        assert_eq!(pos(50), MidiTime::from_ticks(8));
+    }
+}
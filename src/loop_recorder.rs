@@ -4,13 +4,23 @@ pub use ::loop_event::LoopEvent;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
 pub struct LoopRecorder {
-    per_id: HashMap<u32, Vec<LoopEvent>>
+    per_id: HashMap<u32, Vec<LoopEvent>>,
+
+    // events added since the last take_recorded (None until something asks)
+    added: Option<Vec<LoopEvent>>
+}
+
+// what was recorded since the last take_recorded
+pub enum RecordedEvents {
+    All(Vec<LoopEvent>),
+    Added(Vec<LoopEvent>)
 }
 
 impl LoopRecorder {
     pub fn new () -> Self {
         Self {
-            per_id: HashMap::new()
+            per_id: HashMap::new(),
+            added: None
         }
     }
 
@@ -25,7 +35,41 @@ impl LoopRecorder {
         }
     }
 
+    pub fn events (&self) -> Vec<LoopEvent> {
+        self.per_id.values().flat_map(|collection| collection.iter().cloned()).collect()
+    }
+
+    pub fn first_pos (&self) -> Option<MidiTime> {
+        self.per_id.values().filter_map(|collection| collection.first()).map(|event| event.pos).min()
+    }
+
+    // everything the first time, then only the events added since (so a copy can follow along)
+    pub fn take_recorded (&mut self) -> RecordedEvents {
+        match self.added.replace(Vec::new()) {
+            Some(added) => RecordedEvents::Added(added),
+            None => RecordedEvents::All(self.events())
+        }
+    }
+
+    pub fn restore (&mut self, events: Vec<LoopEvent>) {
+        // anyone following along needs a full copy again
+        self.added = None;
+
+        // keep the preallocated capacity around
+        for collection in self.per_id.values_mut() {
+            collection.clear();
+        }
+
+        for event in events {
+            self.add(event);
+        }
+    }
+
     pub fn add (&mut self, event: LoopEvent) {
+        if let Some(added) = &mut self.added {
+            added.push(event);
+        }
+
         // record events per slot
         let collection = self.per_id.entry(event.id).or_insert(Vec::new());
         event.insert_into(collection);
@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 
 use ::midi_time::MidiTime;
+use serde::{Deserialize, Serialize};
 pub use ::loop_transform::LoopTransform;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoopCollection {
     pub length: MidiTime,
     pub transforms: HashMap<u32, LoopTransform>
@@ -13,7 +14,9 @@ pub struct LoopCollection {
 
 #[derive(Eq, PartialEq)]
 pub enum LoopStateChange {
-    Undo, Redo, Set
+    Undo, Redo, Set,
+    // every loop in the history was changed in place (e.g. chunks rebuilt on a config reload)
+    Amend
 }
 
 impl LoopCollection {
@@ -29,8 +32,9 @@ pub struct LoopState {
     pub change_queue: mpsc::Receiver<LoopStateChange>,
     change_queue_tx: mpsc::Sender<LoopStateChange>,
 
-    undos: Vec<LoopCollection>,
-    redos: Vec<LoopCollection>
+    // shared so that a session snapshot doesn't have to copy the history
+    undos: Vec<Arc<LoopCollection>>,
+    redos: Vec<Arc<LoopCollection>>
 }
 
 impl LoopState {
@@ -38,7 +42,7 @@ impl LoopState {
         let default_loop = LoopCollection::new(default_length);
         let (change_queue_tx, change_queue) = mpsc::channel();
         LoopState {
-            undos: vec![default_loop],
+            undos: vec![Arc::new(default_loop)],
             redos: Vec::new(),
             change_queue_tx,
             change_queue
@@ -53,14 +57,14 @@ impl LoopState {
         if offset < 0 {
             let resolved_offset = self.undos.len() as isize - 1 + offset;
             if resolved_offset > 0 {
-                self.undos.get(resolved_offset as usize)
+                self.undos.get(resolved_offset as usize).map(|collection| &**collection)
             } else {
                 None
             }
         } else if offset > 0 {
             let resolved_offset = self.redos.len() as isize - 1 - offset;
             if resolved_offset > 0 {
-                self.redos.get(resolved_offset as usize)
+                self.redos.get(resolved_offset as usize).map(|collection| &**collection)
             } else {
                 None
             }
@@ -78,10 +82,34 @@ impl LoopState {
     }
 
     pub fn set (&mut self, value: LoopCollection) {
-        self.undos.push(value);
+        self.undos.push(Arc::new(value));
         self.on_change(LoopStateChange::Set);
     }
 
+    // changes every loop, undos and redos alike, without adding a step to undo
+    pub fn amend<F> (&mut self, mut f: F) where F: FnMut(&mut LoopCollection) {
+        for collection in self.undos.iter_mut().chain(self.redos.iter_mut()) {
+            f(Arc::make_mut(collection));
+        }
+        self.on_change(LoopStateChange::Amend);
+    }
+
+    pub fn undos (&self) -> &[Arc<LoopCollection>] {
+        &self.undos
+    }
+
+    pub fn redos (&self) -> &[Arc<LoopCollection>] {
+        &self.redos
+    }
+
+    pub fn restore (&mut self, undos: Vec<LoopCollection>, redos: Vec<LoopCollection>) {
+        if !undos.is_empty() {
+            self.undos = undos.into_iter().map(Arc::new).collect();
+            self.redos = redos.into_iter().map(Arc::new).collect();
+            self.on_change(LoopStateChange::Set);
+        }
+    }
+
     pub fn undo (&mut self) {
         if self.undos.len() > 1 {
             match self.undos.pop() {
@@ -1,8 +1,9 @@
 use ::output_value::OutputValue;
 use ::midi_time::MidiTime;
+use serde::{Deserialize, Serialize};
 
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum LoopTransform {
     Value(OutputValue),
     Repeat { rate: MidiTime, offset: MidiTime, value: OutputValue },
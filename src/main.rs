@@ -2,66 +2,113 @@
 extern crate lazy_static;
 extern crate indexmap;
 extern crate rand;
+extern crate regex;
 extern crate serde;
 extern crate serde_json;
 
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::process;
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::{Duration, Instant};
 
+mod args;
 mod chunk;
 mod config;
 mod controllers;
 mod devices;
+mod grid_surfaces;
 mod lfo;
 mod loop_event;
+mod loop_grid;
 mod loop_grid_launchpad;
 mod loop_recorder;
 mod loop_state;
 mod loop_transform;
+mod midi_backend;
 mod midi_connection;
+mod midi_file;
+mod midi_message;
 mod midi_time;
+mod monitor;
 mod output_value;
+mod render;
 mod scale;
 mod scheduler;
+mod session;
+mod signals;
+mod tap_tempo;
+mod terminal_grid;
 mod throttled_output;
+mod thru;
 mod trigger_envelope;
 
 use chunk::{ChunkMap, Triggerable};
-use loop_grid_launchpad::{LoopGridLaunchpad, LoopGridParams};
+use grid_surfaces::GridSurface;
+use loop_grid_launchpad::{LoopGridLaunchpad, LoopGridParams, LoopGridRemoteEvent};
+use midi_connection::MidiMessage;
 use midi_time::MidiTime;
 use scale::{Offset, Scale};
-use scheduler::Scheduler;
+use scheduler::{Scheduler, SchedulerHandle};
+use session::{Session, SessionSnapshot, SessionWriter};
+use thru::Thru;
 
-const APP_NAME: &str = "Loop Drop";
-const CONFIG_FILEPATH: &str = "./loopdrop-config.json";
+const AUTOSAVE_INTERVAL_BEATS: i32 = 32;
 
 type PortLookup = HashMap<String, midi_connection::SharedMidiOutputConnection>;
 type OffsetLookup = HashMap<String, Arc<Mutex<Offset>>>;
 
+struct Rig {
+    output_ports: PortLookup,
+    offset_lookup: OffsetLookup,
+    scale: Arc<Mutex<Scale>>,
+    params: Arc<Mutex<LoopGridParams>>,
+    controllers: Vec<Box<dyn controllers::Schedulable>>,
+    thru: Vec<Thru>,
+    clock_outputs: Vec<midi_connection::SharedMidiOutputConnection>,
+    keep_alive_outputs: Vec<midi_connection::SharedMidiOutputConnection>,
+    resync_outputs: Vec<midi_connection::SharedMidiOutputConnection>,
+
+    // every output is a virtual port, whatever its config says
+    virtual_outputs: bool,
+}
+
 fn main() {
-    let mut chunks = Vec::new();
-    let mut myconfig = config::Config::default();
+    let args = args::Args::parse();
+    let myconfig = load_config(&args);
 
-    // TODO: enable config persistence when loaded with filepath
-    // if Path::new(CONFIG_FILEPATH).exists() {
-    //     myconfig = config::Config::read(CONFIG_FILEPATH).unwrap();
-    //     println!("Read config from {}", CONFIG_FILEPATH);
-    // } else {
-    //     myconfig.write(CONFIG_FILEPATH).unwrap();
-    //     println!("Wrote config to {}", CONFIG_FILEPATH);
-    // }
+    if let Err(errors) = myconfig.validate() {
+        eprintln!("Invalid config {}:", args.config_path);
+        for error in errors {
+            eprintln!("  {}", error);
+        }
+        process::exit(1);
+    }
 
-    let output = midi_connection::MidiOutput::new(APP_NAME).unwrap();
-    let input = midi_connection::MidiInput::new(APP_NAME).unwrap();
+    midi_connection::set_port_aliases(myconfig.port_matchers());
 
-    println!("Midi Outputs: {:?}", midi_connection::get_outputs(&output));
-    println!("Midi Inputs: {:?}", midi_connection::get_inputs(&input));
+    // a render plays the devices too, keep them quiet
+    if args.dry_run || args.render_path.is_some() {
+        midi_backend::set_backend(Arc::new(midi_backend::NullBackend::new()));
+    }
 
-    let clock_input_name = &myconfig.clock_input_port_name;
+    if args.monitor || args.midi_log_path.is_some() {
+        let log_path = args.midi_log_path.as_ref().map(String::as_str);
+        if let Err(err) = monitor::start(args.monitor, log_path) {
+            eprintln!(
+                "Failed to open MIDI log {}: {}",
+                log_path.unwrap_or(""),
+                err
+            );
+            process::exit(1);
+        }
+    }
+
+    println!("Midi Outputs: {:?}", midi_connection::get_outputs());
+    println!("Midi Inputs: {:?}", midi_connection::get_inputs());
 
-    let scale = Scale::new(60, 0);
+    let clock_input_name = myconfig.clock_input_port_name.clone();
 
     let params = Arc::new(Mutex::new(LoopGridParams {
         swing: 0.0,
@@ -73,63 +120,307 @@ fn main() {
         reset_automation: false,
     }));
 
-    let launchpad_io_name = if cfg!(target_os = "linux") {
-        "Launchpad Pro MK3"
+    let mut rig = Rig {
+        output_ports: HashMap::new(),
+        offset_lookup: HashMap::new(),
+        scale: Scale::new(60, 0),
+        params: Arc::clone(&params),
+        controllers: Vec::new(),
+        thru: Vec::new(),
+        clock_outputs: Vec::new(),
+        keep_alive_outputs: Vec::new(),
+        resync_outputs: Vec::new(),
+        virtual_outputs: args.virtual_outputs,
+    };
+
+    let chunks = myconfig
+        .chunks
+        .iter()
+        .map(|chunk| rig.make_chunk(chunk.clone()))
+        .collect();
+
+    let renderer = args.render_path.as_ref().map(|_| render::Renderer::new());
+
+    let mut launchpad = if args.tui {
+        match LoopGridLaunchpad::terminal(chunks, Arc::clone(&params)) {
+            Ok(launchpad) => launchpad,
+            Err(err) => {
+                eprintln!("Can't start the terminal grid: {}", err);
+                process::exit(1);
+            }
+        }
+    } else if let Some(renderer) = &renderer {
+        LoopGridLaunchpad::offline(renderer.capture(chunks), Arc::clone(&params))
     } else {
-        "Launchpad Pro MK3 LPProMK3 MIDI"
+        LoopGridLaunchpad::new(
+            myconfig.grid.port_name(),
+            myconfig.grid.is_virtual(),
+            make_grid_surface(&myconfig.grid),
+            chunks,
+            Arc::clone(&params),
+        )
     };
 
-    let mut output_ports = HashMap::new();
-    let mut offset_lookup = HashMap::new();
+    for controller in &myconfig.controllers {
+        let controller = rig.make_controller(controller.clone(), &launchpad.remote_tx);
+        rig.controllers.push(controller);
+    }
 
-    for chunk in myconfig.chunks {
-        chunks.push(ChunkMap::new(
+    for thru in &myconfig.thru {
+        let thru = rig.make_thru(thru);
+        rig.thru.push(thru);
+    }
+
+    rig.clock_outputs = get_ports(&mut rig.output_ports, &myconfig.clock_output_port_names);
+    rig.keep_alive_outputs = get_ports(&mut rig.output_ports, &myconfig.keep_alive_port_names);
+    rig.resync_outputs = get_ports(&mut rig.output_ports, &myconfig.resync_port_names);
+    rig.apply_port_config(&myconfig);
+
+    let session_writer = args.session_path.as_ref().map(|session_path| {
+        if Path::new(session_path).exists() {
+            match Session::read(session_path) {
+                Ok(session) => {
+                    rig.load_session(session, &mut launchpad);
+                    println!("Restored session from {}", session_path);
+                }
+                Err(err) => {
+                    eprintln!("Failed to read session from {}: {}", session_path, err);
+                    process::exit(1);
+                }
+            }
+        }
+        SessionWriter::spawn(session_path)
+    });
+
+    for (chunk_index, import_path) in &args.imports {
+        if *chunk_index >= myconfig.chunks.len() {
+            eprintln!(
+                "Can't import {}: there is no chunk {} ({} configured)",
+                import_path,
+                chunk_index,
+                myconfig.chunks.len()
+            );
+            process::exit(1);
+        }
+        match midi_file::read(import_path) {
+            Ok(notes) => {
+                let (imported, skipped) = launchpad.import_notes(*chunk_index, &notes);
+                println!(
+                    "Imported {} notes from {} into chunks[{}] ({} had no matching pad)",
+                    imported, import_path, chunk_index, skipped
+                );
+            }
+            Err(err) => {
+                eprintln!("Failed to import {}: {}", import_path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(export_path) = &args.export_path {
+        let tracks = launchpad.export_loop();
+        if let Err(err) = midi_file::write(export_path, &tracks) {
+            eprintln!("Failed to export to {}: {}", export_path, err);
+            process::exit(1);
+        }
+        println!("Exported {} tracks to {}", tracks.len(), export_path);
+        process::exit(0);
+    }
+
+    if let (Some(renderer), Some(render_path)) = (&renderer, &args.render_path) {
+        let beats = args.stop_after.unwrap_or(0);
+        let clock = scheduler::VirtualClock::new(MidiTime::from_beats(beats));
+        let script = match &args.script_path {
+            Some(script_path) => match render::read_script(script_path) {
+                Ok(script) => script,
+                Err(err) => {
+                    eprintln!("Failed to read script {}: {}", script_path, err);
+                    process::exit(1);
+                }
+            },
+            None => Vec::new(),
+        };
+        let notes = renderer.render(&mut launchpad, clock, &script);
+        // a text file gets the list of notes (as in the golden tests) rather than a MIDI file
+        let result = if render_path.ends_with(".txt") {
+            fs::write(render_path, render::to_text(&notes))
+        } else {
+            midi_file::write(render_path, &render::to_tracks(&notes))
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to render to {}: {}", render_path, err);
+            process::exit(1);
+        }
+        println!(
+            "Rendered {} beats ({} notes) to {}",
+            beats,
+            notes.len(),
+            render_path
+        );
+        process::exit(0);
+    }
+
+    let mut current_config = myconfig;
+    let config_updates = config::Config::watch(&args.config_path);
+
+    let scheduler = Scheduler::start(
+        &clock_input_name,
+        current_config.clock.virtual_input,
+        current_config.clock.bpm,
+        Duration::from_millis(current_config.clock.external_timeout_ms),
+    );
+    let clock = scheduler.handle();
+    launchpad.set_clock(clock.clone());
+
+    // Ctrl-C / SIGTERM finish the current range and fall out of the loop below
+    let shutdown_clock = clock.clone();
+    signals::on_shutdown(move || shutdown_clock.shutdown());
+
+    let stop_at = args.stop_after.map(MidiTime::from_beats);
+
+    for range in scheduler {
+        midi_connection::set_tap_pos(range.to);
+
+        if let Some(stop_at) = stop_at {
+            if range.to >= stop_at {
+                clock.shutdown();
+            }
+        }
+
+        // sending clock is the highest priority, so lets do these first
+        if range.ticked {
+            if (range.tick_pos - MidiTime::tick()) % MidiTime::from_beats(32) == MidiTime::zero() {
+                for output in &mut rig.resync_outputs {
+                    output.send(MidiMessage::Start);
+                }
+            }
+
+            for output in &mut rig.clock_outputs {
+                output.send(MidiMessage::Clock);
+            }
+        }
+
+        // if range.ticked && range.from.ticks() != range.to.ticks() {
+        //     // HACK: straighten out missing sub ticks into separate schedules
+        //     let mut a = range.clone();
+        //     a.to = MidiTime::new(a.to.ticks(), 0);
+        //     a.tick_pos = MidiTime::new(a.from.ticks(), 0);
+        //     a.ticked = false;
+        //     let mut b = range.clone();
+        //     b.from = MidiTime::new(b.to.ticks(), 0);
+        //     launchpad.schedule(a);
+        //     launchpad.schedule(b);
+        // } else {
+        let start = Instant::now();
+        launchpad.schedule(range);
+        if start.elapsed() > Duration::from_millis(15) {
+            println!("[WARN] SCHEDULE TIME {:?}", start.elapsed());
+        }
+        // }
+
+        if launchpad.take_panic_request() {
+            rig.panic(&mut launchpad);
+            println!("Panic: all notes off");
+        }
+
+        // now for the lower priority stuff
+        if range.ticked {
+            let length = MidiTime::tick();
+            for controller in &mut rig.controllers {
+                controller.schedule(range.tick_pos, length)
+            }
+
+            for output in &mut rig.keep_alive_outputs {
+                output.send(MidiMessage::ActiveSensing);
+            }
+
+            if let Some(session_writer) = &session_writer {
+                if range.tick_pos > MidiTime::zero()
+                    && range.tick_pos % MidiTime::from_beats(AUTOSAVE_INTERVAL_BEATS)
+                        == MidiTime::zero()
+                {
+                    session_writer.save(rig.save_session(&mut launchpad));
+                }
+            }
+
+            // apply config edits between ticks, the clock keeps running
+            if let Ok(new_config) = config_updates.try_recv() {
+                rig.reload_config(&current_config, &new_config, &mut launchpad, &clock);
+                println!("Reloaded config from {}", args.config_path);
+                current_config = new_config;
+            }
+        }
+    }
+
+    println!("Shutting down");
+    rig.panic(&mut launchpad);
+
+    if let Some(session_writer) = session_writer {
+        session_writer.finish(rig.save_session(&mut launchpad));
+    }
+
+    for port in rig.output_ports.values_mut() {
+        port.close();
+    }
+}
+
+impl Rig {
+    fn make_chunk(&mut self, chunk: config::ChunkConfig) -> Box<ChunkMap> {
+        ChunkMap::new(
             make_device(
                 chunk.device,
-                &mut output_ports,
-                &mut offset_lookup,
-                &scale,
-                &params,
+                &mut self.output_ports,
+                &mut self.offset_lookup,
+                &self.scale,
+                &self.params,
             ),
             chunk.coords,
             chunk.shape,
             chunk.color,
             chunk.channel,
             chunk.repeat_mode,
-        ))
+            chunk.keyboard,
+        )
     }
 
-    let mut launchpad = LoopGridLaunchpad::new(launchpad_io_name, chunks, Arc::clone(&params));
-
-    let mut controller_references: Vec<Box<dyn controllers::Schedulable>> = Vec::new();
-
-    for controller in myconfig.controllers {
-        controller_references.push(match controller {
+    fn make_controller(
+        &mut self,
+        controller: config::ControllerConfig,
+        remote_tx: &mpsc::Sender<LoopGridRemoteEvent>,
+    ) -> Box<dyn controllers::Schedulable> {
+        let output_ports = &mut self.output_ports;
+        match controller {
             config::ControllerConfig::Twister {
                 port_name,
+                is_virtual,
                 mixer_port,
                 modulators,
             } => Box::new(controllers::Twister::new(
                 &port_name,
-                get_port(&mut output_ports, &mixer_port.name),
+                is_virtual,
+                get_port(output_ports, &mixer_port.name),
                 mixer_port.channel,
-                resolve_modulators(&mut output_ports, &modulators),
-                Arc::clone(&params),
+                resolve_modulators(output_ports, &modulators),
+                Arc::clone(&self.params),
             )),
-            config::ControllerConfig::Umi3 { port_name } => Box::new(controllers::Umi3::new(
+            config::ControllerConfig::Umi3 {
+                port_name,
+                is_virtual,
+            } => Box::new(controllers::Umi3::new(
                 &port_name,
-                launchpad.remote_tx.clone(),
+                is_virtual,
+                remote_tx.clone(),
             )),
             config::ControllerConfig::VT4Key { output } => {
-                let device_port = get_port(&mut output_ports, &output.name);
+                let device_port = get_port(output_ports, &output.name);
                 Box::new(controllers::VT4Key::new(
                     device_port,
                     output.channel,
-                    scale.clone(),
+                    self.scale.clone(),
                 ))
             }
             config::ControllerConfig::ClockPulse { output, divider } => {
-                let device_port = get_port(&mut output_ports, &output.name);
+                let device_port = get_port(output_ports, &output.name);
                 Box::new(controllers::ClockPulse::new(
                     device_port,
                     output.channel,
@@ -137,73 +428,200 @@ fn main() {
                 ))
             }
             config::ControllerConfig::Init { modulators } => Box::new(controllers::Init::new(
-                resolve_modulators(&mut output_ports, &modulators),
+                resolve_modulators(output_ports, &modulators),
             )),
-        })
+        }
     }
 
-    let mut clock_outputs: Vec<midi_connection::SharedMidiOutputConnection> = Vec::new();
-    for name in myconfig.clock_output_port_names {
-        clock_outputs.push(get_port(&mut output_ports, &name))
+    // stops everything sounding, including notes held on devices by something other than us
+    fn panic(&mut self, launchpad: &mut LoopGridLaunchpad) {
+        launchpad.panic();
+        for port in self.output_ports.values_mut() {
+            port.all_notes_off();
+        }
     }
 
-    let mut keep_alive_outputs: Vec<midi_connection::SharedMidiOutputConnection> = Vec::new();
-    for name in myconfig.keep_alive_port_names {
-        keep_alive_outputs.push(get_port(&mut output_ports, &name))
+    fn make_thru(&mut self, thru: &config::ThruConfig) -> Thru {
+        let outputs = thru
+            .outputs
+            .iter()
+            .map(|output| {
+                (
+                    get_port(&mut self.output_ports, &output.name),
+                    output.channel,
+                )
+            })
+            .collect();
+        Thru::new(thru, outputs, Arc::clone(&self.scale))
     }
 
-    let mut resync_outputs: Vec<midi_connection::SharedMidiOutputConnection> = Vec::new();
-    for name in myconfig.resync_port_names {
-        resync_outputs.push(get_port(&mut output_ports, &name))
+    // cheap enough for the scheduler thread, the writer thread turns it into the saved session
+    fn save_session(&self, launchpad: &mut LoopGridLaunchpad) -> SessionSnapshot {
+        SessionSnapshot {
+            loops: launchpad.save_session(),
+            scale: self.scale.lock().unwrap().clone(),
+            offsets: self
+                .offset_lookup
+                .iter()
+                .map(|(id, offset)| (id.clone(), offset.lock().unwrap().clone()))
+                .collect(),
+            automation: self
+                .controllers
+                .iter()
+                .filter_map(|controller| {
+                    let key = controller.session_key()?;
+                    Some((String::from(key), controller.save_automation()?))
+                })
+                .collect(),
+        }
     }
 
-    for range in Scheduler::start(clock_input_name) {
-        // sending clock is the highest priority, so lets do these first
-        if range.ticked {
-            if (range.tick_pos - MidiTime::tick()) % MidiTime::from_beats(32) == MidiTime::zero() {
-                for output in &mut resync_outputs {
-                    output.send(&[250]).unwrap();
-                }
+    fn load_session(&mut self, session: Session, launchpad: &mut LoopGridLaunchpad) {
+        launchpad.load_session(session.loops);
+        *self.scale.lock().unwrap() = session.scale;
+
+        for (id, saved) in session.offsets {
+            if let Some(offset) = self.offset_lookup.get(&id) {
+                // base comes from the config (note_offset), the rest was played live
+                let mut offset = offset.lock().unwrap();
+                offset.offset = saved.offset;
+                offset.pitch = saved.pitch;
             }
+        }
 
-            for output in &mut clock_outputs {
-                output.send(&[248]).unwrap();
+        let mut automation = session.automation;
+        for controller in &mut self.controllers {
+            let saved = match controller.session_key() {
+                Some(key) => automation.remove(key),
+                None => None,
+            };
+            if let Some(saved) = saved {
+                controller.load_automation(saved);
             }
         }
+    }
 
-        // if range.ticked && range.from.ticks() != range.to.ticks() {
-        //     // HACK: straighten out missing sub ticks into separate schedules
-        //     let mut a = range.clone();
-        //     a.to = MidiTime::new(a.to.ticks(), 0);
-        //     a.tick_pos = MidiTime::new(a.from.ticks(), 0);
-        //     a.ticked = false;
-        //     let mut b = range.clone();
-        //     b.from = MidiTime::new(b.to.ticks(), 0);
-        //     launchpad.schedule(a);
-        //     launchpad.schedule(b);
-        // } else {
-        let start = Instant::now();
-        launchpad.schedule(range);
-        if start.elapsed() > Duration::from_millis(15) {
-            println!("[WARN] SCHEDULE TIME {:?}", start.elapsed());
+    // rebuilds only the chunks and controllers whose config changed, so unchanged
+    // devices keep their state (and anything they are currently playing)
+    fn reload_config(
+        &mut self,
+        old: &config::Config,
+        new: &config::Config,
+        launchpad: &mut LoopGridLaunchpad,
+        clock: &SchedulerHandle,
+    ) {
+        if old.clock_input_port_name != new.clock_input_port_name {
+            println!(
+                "[WARN] clock_input_port_name changed to {:?}, restart to apply",
+                new.clock_input_port_name
+            );
         }
-        // }
 
-        // now for the lower priority stuff
-        if range.ticked {
-            let length = MidiTime::tick();
-            for controller in &mut controller_references {
-                controller.schedule(range.tick_pos, length)
+        if old.grid != new.grid {
+            println!("[WARN] grid changed, restart to apply");
+        }
+
+        if old.clock.virtual_input != new.clock.virtual_input {
+            println!(
+                "[WARN] clock.virtual_input changed to {}, restart to apply",
+                new.clock.virtual_input
+            );
+        }
+
+        if old.port_aliases != new.port_aliases {
+            midi_connection::set_port_aliases(new.port_matchers());
+        }
+
+        if old.clock.bpm != new.clock.bpm {
+            clock.set_bpm(new.clock.bpm);
+        }
+        clock.set_external_timeout(Duration::from_millis(new.clock.external_timeout_ms));
+
+        let mut replacements = Vec::new();
+        for (index, chunk) in new.chunks.iter().enumerate() {
+            if old.chunks.get(index) != Some(chunk) {
+                replacements.push((index, self.make_chunk(chunk.clone())));
+            }
+        }
+        launchpad.replace_chunks(replacements, new.chunks.len());
+
+        // drop each outdated controller before creating its replacement so ports are released
+        let mut existing = std::mem::take(&mut self.controllers).into_iter();
+        for (index, controller) in new.controllers.iter().enumerate() {
+            let current = existing.next();
+            if old.controllers.get(index) == Some(controller) {
+                if let Some(current) = current {
+                    self.controllers.push(current);
+                    continue;
+                }
             }
+            drop(current);
+            let replacement = self.make_controller(controller.clone(), &launchpad.remote_tx);
+            self.controllers.push(replacement);
+        }
 
-            for output in &mut keep_alive_outputs {
-                output.send(&[254]).unwrap();
+        // same for thru routes (an unchanged route keeps the notes it is holding)
+        let mut existing = std::mem::take(&mut self.thru).into_iter();
+        for (index, thru) in new.thru.iter().enumerate() {
+            let current = existing.next();
+            if old.thru.get(index) == Some(thru) {
+                if let Some(current) = current {
+                    self.thru.push(current);
+                    continue;
+                }
             }
+            drop(current);
+            let replacement = self.make_thru(thru);
+            self.thru.push(replacement);
+        }
+
+        self.clock_outputs = get_ports(&mut self.output_ports, &new.clock_output_port_names);
+        self.keep_alive_outputs = get_ports(&mut self.output_ports, &new.keep_alive_port_names);
+        self.resync_outputs = get_ports(&mut self.output_ports, &new.resync_port_names);
+        self.apply_port_config(new);
+    }
+
+    fn apply_port_config(&mut self, config: &config::Config) {
+        let port_configs = config.output_ports();
+        let delays = config.output_delays();
+        for (name, port) in &mut self.output_ports {
+            let is_virtual = port_configs.get(name).map_or(false, |port| port.is_virtual);
+            port.set_delay(delays.get(name).cloned().unwrap_or_default());
+            port.set_virtual(is_virtual || self.virtual_outputs);
         }
     }
 }
 
 // Helper functions
+fn load_config(args: &args::Args) -> config::Config {
+    if args.write_config {
+        let config = config::Config::default();
+        if let Err(err) = config.write(&args.config_path) {
+            eprintln!("Failed to write config to {}: {}", args.config_path, err);
+            process::exit(1);
+        }
+        println!("Wrote config to {}", args.config_path);
+        config
+    } else if Path::new(&args.config_path).exists() {
+        match config::Config::read(&args.config_path) {
+            Ok(config) => {
+                println!("Read config from {}", args.config_path);
+                config
+            }
+            Err(err) => {
+                eprintln!("Failed to read config from {}: {}", args.config_path, err);
+                process::exit(1);
+            }
+        }
+    } else {
+        println!(
+            "No config at {}, using default rig (run with --write-config to save it)",
+            args.config_path
+        );
+        config::Config::default()
+    }
+}
+
 fn resolve_modulators(
     output_ports: &mut PortLookup,
     modulators: &Vec<Option<config::ModulatorConfig>>,
@@ -239,6 +657,16 @@ fn get_port(
     ports_lookup.get(port_name).unwrap().clone()
 }
 
+fn get_ports(
+    ports_lookup: &mut PortLookup,
+    port_names: &[String],
+) -> Vec<midi_connection::SharedMidiOutputConnection> {
+    port_names
+        .iter()
+        .map(|name| get_port(ports_lookup, name))
+        .collect()
+}
+
 fn get_offset(offset_lookup: &mut OffsetLookup, id: &str) -> Arc<Mutex<Offset>> {
     if !offset_lookup.contains_key(id) {
         offset_lookup.insert(String::from(id), Offset::new(0));
@@ -253,6 +681,31 @@ fn set_offset(offset: Arc<Mutex<Offset>>, note_offset: &i32) {
     value.base = *note_offset;
 }
 
+fn make_grid_surface(grid: &config::GridConfig) -> Box<dyn GridSurface> {
+    match grid.clone() {
+        config::GridConfig::LaunchpadProMk3 { .. } => {
+            Box::new(grid_surfaces::LaunchpadProMk3::new())
+        }
+        config::GridConfig::LaunchpadX { .. } => Box::new(grid_surfaces::LaunchpadX::new()),
+        config::GridConfig::LaunchpadMiniMk3 { .. } => Box::new(grid_surfaces::LaunchpadX::mini()),
+        config::GridConfig::LaunchpadMk2 { .. } => Box::new(grid_surfaces::LaunchpadMk2::new()),
+        config::GridConfig::NoteGrid {
+            channel,
+            first_note,
+            bottom_up,
+            top_notes,
+            right_notes,
+            ..
+        } => Box::new(grid_surfaces::NoteGrid::new(
+            channel,
+            first_note,
+            bottom_up,
+            top_notes,
+            right_notes,
+        )),
+    }
+}
+
 fn make_device(
     device: config::DeviceConfig,
     output_ports: &mut PortLookup,
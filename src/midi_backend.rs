@@ -0,0 +1,290 @@
+extern crate midir;
+
+#[cfg(unix)]
+use self::midir::os::unix::{VirtualInput, VirtualOutput};
+use self::midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use midi_message::MidiMessage;
+#[cfg(test)]
+use std::sync::MutexGuard;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+
+const APP_NAME: &str = "Loop Drop";
+
+#[cfg(not(unix))]
+const VIRTUAL_UNSUPPORTED: &str = "virtual ports need ALSA or CoreMIDI";
+
+// raw bytes of one or more messages, with the stamp the port gave them
+pub type InputCallback = Box<dyn FnMut(u64, &[u8]) + Send + 'static>;
+
+// where ports come from: the system (through midir), or nowhere for dry runs and tests
+pub trait Backend: Send + Sync {
+    // port names as the system reports them, midi_connection normalizes them
+    fn output_names(&self) -> Vec<String>;
+    fn input_names(&self) -> Vec<String>;
+
+    // index is into the names, virtual ports are our own for other software to connect to
+    fn open_output(&self, index: usize, port_name: &str) -> Result<Box<dyn OutputPort>, String>;
+    fn create_virtual_output(&self, port_name: &str) -> Result<Box<dyn OutputPort>, String>;
+    fn open_input(
+        &self,
+        index: usize,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String>;
+    fn create_virtual_input(
+        &self,
+        port_name: &str,
+        callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String>;
+}
+
+pub trait OutputPort: Send {
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String>;
+}
+
+// keeps the input connected until dropped
+pub trait InputPort {}
+
+lazy_static! {
+    static ref BACKEND: RwLock<Arc<dyn Backend>> = RwLock::new(Arc::new(SystemBackend));
+}
+
+// call before opening any ports, ports already open stay on the old backend
+pub fn set_backend(backend: Arc<dyn Backend>) {
+    *BACKEND.write().unwrap() = backend;
+}
+
+pub fn backend() -> Arc<dyn Backend> {
+    Arc::clone(&BACKEND.read().unwrap())
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref TEST_BACKEND: Mutex<()> = Mutex::new(());
+}
+
+// the backend is global, so tests take turns with it and put the system one back after
+#[cfg(test)]
+pub struct TestBackend {
+    _turn: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+pub fn set_test_backend(backend: Arc<dyn Backend>) -> TestBackend {
+    let turn = TEST_BACKEND.lock().unwrap_or_else(|err| err.into_inner());
+    set_backend(backend);
+    TestBackend { _turn: turn }
+}
+
+#[cfg(test)]
+impl Drop for TestBackend {
+    fn drop(&mut self) {
+        set_backend(Arc::new(SystemBackend));
+    }
+}
+
+pub struct SystemBackend;
+
+impl Backend for SystemBackend {
+    fn output_names(&self) -> Vec<String> {
+        let output = match MidiOutput::new(APP_NAME) {
+            Ok(output) => output,
+            Err(_) => return Vec::new(),
+        };
+        // for some reason, sometimes the port doesn't exist -- use empty string
+        (0..output.port_count())
+            .map(|index| output.port_name(index).unwrap_or(String::from("")))
+            .collect()
+    }
+
+    fn input_names(&self) -> Vec<String> {
+        let input = match MidiInput::new(APP_NAME) {
+            Ok(input) => input,
+            Err(_) => return Vec::new(),
+        };
+        (0..input.port_count())
+            .map(|index| input.port_name(index).unwrap_or(String::from("")))
+            .collect()
+    }
+
+    fn open_output(&self, index: usize, port_name: &str) -> Result<Box<dyn OutputPort>, String> {
+        let output = MidiOutput::new(APP_NAME).map_err(|err| err.to_string())?;
+        match output.connect(index, port_name) {
+            Ok(port) => Ok(Box::new(port)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn create_virtual_output(&self, port_name: &str) -> Result<Box<dyn OutputPort>, String> {
+        let output = MidiOutput::new(APP_NAME).map_err(|err| err.to_string())?;
+        match output.create_virtual(port_name) {
+            Ok(port) => Ok(Box::new(port)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn create_virtual_output(&self, _port_name: &str) -> Result<Box<dyn OutputPort>, String> {
+        Err(String::from(VIRTUAL_UNSUPPORTED))
+    }
+
+    fn open_input(
+        &self,
+        index: usize,
+        port_name: &str,
+        mut callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String> {
+        let input = MidiInput::new(APP_NAME).map_err(|err| err.to_string())?;
+        match input.connect(
+            index,
+            port_name,
+            move |stamp, msg, _| callback(stamp, msg),
+            (),
+        ) {
+            Ok(port) => Ok(Box::new(port)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn create_virtual_input(
+        &self,
+        port_name: &str,
+        mut callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String> {
+        let input = MidiInput::new(APP_NAME).map_err(|err| err.to_string())?;
+        match input.create_virtual(port_name, move |stamp, msg, _| callback(stamp, msg), ()) {
+            Ok(port) => Ok(Box::new(port)),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn create_virtual_input(
+        &self,
+        _port_name: &str,
+        _callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String> {
+        Err(String::from(VIRTUAL_UNSUPPORTED))
+    }
+}
+
+impl OutputPort for MidiOutputConnection {
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String> {
+        MidiOutputConnection::send(self, bytes).map_err(|err| err.to_string())
+    }
+}
+
+impl<T> InputPort for MidiInputConnection<T> {}
+
+// a port name and a message sent to it
+pub type SentMessage = (String, MidiMessage);
+
+// no system ports and nothing ever comes in, but virtual outputs can be made (and tests can
+// record what they are sent)
+pub struct NullBackend {
+    record_tx: Arc<Mutex<Option<mpsc::Sender<SentMessage>>>>,
+}
+
+impl NullBackend {
+    pub fn new() -> Self {
+        NullBackend {
+            record_tx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // from now on every message sent to any port goes to tx
+    #[cfg(test)]
+    pub fn record(&self, tx: mpsc::Sender<SentMessage>) {
+        *self.record_tx.lock().unwrap() = Some(tx);
+    }
+}
+
+impl Backend for NullBackend {
+    fn output_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn input_names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn open_output(&self, _index: usize, port_name: &str) -> Result<Box<dyn OutputPort>, String> {
+        Err(format!("{} doesn't exist in a dry run", port_name))
+    }
+
+    fn create_virtual_output(&self, port_name: &str) -> Result<Box<dyn OutputPort>, String> {
+        Ok(Box::new(NullOutput {
+            port_name: String::from(port_name),
+            record_tx: Arc::clone(&self.record_tx),
+        }))
+    }
+
+    fn open_input(
+        &self,
+        _index: usize,
+        port_name: &str,
+        _callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String> {
+        Err(format!("{} doesn't exist in a dry run", port_name))
+    }
+
+    fn create_virtual_input(
+        &self,
+        _port_name: &str,
+        _callback: InputCallback,
+    ) -> Result<Box<dyn InputPort>, String> {
+        Ok(Box::new(NullInput))
+    }
+}
+
+struct NullOutput {
+    port_name: String,
+    record_tx: Arc<Mutex<Option<mpsc::Sender<SentMessage>>>>,
+}
+
+impl OutputPort for NullOutput {
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let message = MidiMessage::parse(bytes).ok_or_else(|| String::from("invalid message"))?;
+        if let Some(ref tx) = *self.record_tx.lock().unwrap() {
+            // fails once the recording has been dropped, nothing to do
+            let _ = tx.send((self.port_name.clone(), message));
+        }
+        Ok(())
+    }
+}
+
+struct NullInput;
+
+impl InputPort for NullInput {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_outputs_record_what_they_are_sent() {
+        let backend = NullBackend::new();
+        assert!(backend.output_names().is_empty());
+        assert!(backend.open_output(0, "Synth").is_err());
+
+        let mut port = backend.create_virtual_output("Synth").unwrap();
+        port.send(&[0x90, 60, 100]).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        backend.record(tx);
+        port.send(&[0x91, 62, 0]).unwrap();
+        assert!(port.send(&[0x62]).is_err());
+
+        let note_off = MidiMessage::NoteOn {
+            channel: 2,
+            note: 62,
+            velocity: 0,
+        };
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![(String::from("Synth"), note_off)]
+        );
+    }
+}
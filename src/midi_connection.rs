@@ -1,33 +1,63 @@
-extern crate midir;
 extern crate regex;
 
-pub use self::midir::{
-    ConnectError, ConnectErrorKind, MidiInput, MidiInputConnection, MidiOutput,
-    MidiOutputConnection, PortInfoError, SendError,
-};
 use self::regex::Regex;
-use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use midi_backend::{self, InputPort, OutputPort};
+pub use midi_message::MidiMessage;
+use midi_message::MidiParser;
+use midi_time::MidiTime;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 
 use std::thread;
-use std::time::Duration;
 pub use std::time::SystemTime;
-type Listener = Box<dyn Fn(&mut MidiOutputConnection) + Send + 'static>;
+use std::time::{Duration, Instant};
+type Listener = Box<dyn Fn(&mut dyn OutputPort) + Send + 'static>;
 
-const APP_NAME: &str = "Loop Drop";
+// messages waiting per port before anything that can be dropped is
+const QUEUE_SIZE: usize = 256;
 
 struct OutputState {
-    port: Option<MidiOutputConnection>,
+    name: String,
+    port: Option<Box<dyn OutputPort>>,
     listeners: Vec<Listener>,
     current_values: HashMap<(u8, u8), u8>,
+
+    // how long async sends are held back, to line this port up with slower devices
+    delay: Duration,
+
+    // expose our own port for other software to connect to, instead of finding a device
+    is_virtual: bool,
+
+    // disconnected for good, stop reconnecting
+    closed: bool,
 }
 
 impl OutputState {
     fn notify_listeners(&mut self) {
         if let Some(ref mut port) = self.port {
             for listener in &self.listeners {
-                listener(port)
+                listener(port.as_mut())
+            }
+        }
+    }
+
+    fn send_now(&mut self, message: &MidiMessage) -> Result<(), String> {
+        let bytes = message.to_bytes();
+
+        // remember the latest value of each note/control, to restore on reconnect
+        if bytes.len() == 3 && bytes[0] < 0xF0 {
+            self.current_values.insert((bytes[0], bytes[1]), bytes[2]);
+        }
+
+        match self.port {
+            Some(ref mut port) => {
+                port.send(&bytes)?;
+                tap(&self.name, Direction::Out, message);
+                Ok(())
             }
+            None => Ok(()),
         }
     }
 
@@ -36,7 +66,9 @@ impl OutputState {
             for ((msg, id), value) in self.current_values.clone() {
                 // resend 0 for CCs, but not for anything else
                 if (msg >= 176 && msg < 192) || value > 0 {
-                    port.send(&[msg, id, value]).unwrap();
+                    if let Err(err) = port.send(&[msg, id, value]) {
+                        println!("[WARN] failed to resend {:?}: {}", [msg, id, value], err);
+                    }
                 }
             }
         }
@@ -45,145 +77,410 @@ impl OutputState {
 
 pub fn get_shared_output(port_name: &str) -> SharedMidiOutputConnection {
     let state = Arc::new(Mutex::new(OutputState {
+        name: String::from(port_name),
         port: None,
         listeners: Vec::new(),
         current_values: HashMap::new(),
+        delay: Duration::from_millis(0),
+        is_virtual: false,
+        closed: false,
     }));
 
+    // wakes the reconnect loop when the port is switched to or from virtual, or closed
+    let changed = Arc::new(Condvar::new());
+    let changed_l = changed.clone();
+
     let state_l = state.clone();
     let port_name_notify = String::from(port_name);
     let port_name_msg = String::from(port_name);
 
     let rx_state = state.clone();
 
-    // midi send queue
-    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(256);
+    // midi send queue, each message carries the time it is due to go out
+    let queue = Arc::new((Mutex::new(SendQueue::default()), Condvar::new()));
+    let queue_s = queue.clone();
+    let port_name_report = String::from(port_name);
     thread::spawn(move || {
-        for message in rx {
-            let mut state = rx_state.lock().unwrap();
-            if message.len() == 3 {
-                state
-                    .current_values
-                    .insert((message[0], message[1]), message[2]);
+        let (lock, ready) = &*queue_s;
+        let mut reported = SendStats::default();
+        let mut last_report = Instant::now();
+        loop {
+            let (due, message) = {
+                let mut queue = lock.lock().unwrap();
+                loop {
+                    if queue.stats != reported && last_report.elapsed() >= Duration::from_secs(1) {
+                        queue.report(&port_name_report, &reported);
+                        reported = queue.stats;
+                        last_report = Instant::now();
+                    }
+
+                    if let Some(item) = queue.pending.pop_front() {
+                        queue.sending = true;
+                        break item;
+                    }
+
+                    if queue.closed {
+                        return;
+                    }
+
+                    // wake up now and then so that drops still get reported when it goes quiet
+                    queue = ready.wait_timeout(queue, Duration::from_secs(1)).unwrap().0;
+                }
+            };
+
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
             }
 
-            if let Some(ref mut port) = state.port {
-                port.send(&message).unwrap();
+            let result = rx_state.lock().unwrap().send_now(&message);
+
+            let mut queue = lock.lock().unwrap();
+            queue.sending = false;
+            if let Err(err) = result {
+                queue.stats.failed += 1;
+                queue.last_error = Some(err);
             }
         }
     });
 
     // reconnect loop
     thread::spawn(move || {
-        let mut has_port = false;
+        let mut bound: Option<String> = None;
+        let mut reported: Option<String> = None;
+        let mut was_virtual = false;
         loop {
-            let output = MidiOutput::new(APP_NAME).unwrap();
-            let current_port_id = get_outputs(&output)
-                .iter()
-                .position(|item| item == &port_name_notify);
-            if current_port_id.is_some() != has_port {
-                let mut state = state_l.lock().unwrap();
-                state.port = get_output(&port_name_msg);
-                state.notify_listeners();
-                state.resend();
-                has_port = state.port.is_some();
+            let (is_virtual, closed) = {
+                let state = state_l.lock().unwrap();
+                (state.is_virtual, state.closed)
+            };
+            if closed {
+                break;
             }
-            thread::sleep(Duration::from_secs(1));
+
+            if is_virtual != was_virtual {
+                // switched between a device and our own port, start over
+                state_l.lock().unwrap().port = None;
+                bound = None;
+                was_virtual = is_virtual;
+            }
+
+            if is_virtual {
+                if bound.is_none() {
+                    let mut state = state_l.lock().unwrap();
+                    state.port = get_virtual_output(&port_name_msg);
+                    state.notify_listeners();
+                    state.resend();
+                    if state.port.is_some() {
+                        bound = Some(port_name_msg.clone());
+                    }
+                }
+            } else {
+                let current = find_port_name(&get_outputs(), &port_name_notify);
+
+                // rebind when the port comes or goes, or an alias now matches a different port
+                if current != bound {
+                    let mut state = state_l.lock().unwrap();
+                    state.port = get_output(&port_name_msg);
+                    state.notify_listeners();
+                    state.resend();
+                    bound = if state.port.is_some() { current } else { None };
+                    report_binding(&port_name_msg, &bound, &mut reported);
+                }
+            }
+
+            let state = state_l.lock().unwrap();
+            let _ = changed_l
+                .wait_timeout_while(state, Duration::from_secs(1), |state| {
+                    state.is_virtual == was_virtual && !state.closed
+                })
+                .unwrap();
         }
     });
 
-    SharedMidiOutputConnection { state, tx }
+    SharedMidiOutputConnection {
+        state,
+        changed,
+        queue,
+    }
 }
 
 pub fn get_input<F>(port_name: &str, callback: F) -> ThreadReference
 where
-    F: FnMut(u64, &[u8]) + Send + 'static,
+    F: FnMut(u64, MidiMessage) + Send + 'static,
 {
     let port_name_notify = String::from(port_name);
-    let (tx, rx) = mpsc::channel::<MidiInputMessage>();
-
-    thread::spawn(move || {
-        let mut callback = callback;
-        for msg in rx {
-            callback(msg.stamp, &msg.data)
-        }
-    });
+    let running = Arc::new(AtomicBool::new(true));
+    let running_l = running.clone();
+    let tx = spawn_input_callback(port_name, running.clone(), callback);
 
     thread::spawn(move || {
         let mut last_port = None;
-        let mut current_input: Option<MidiInputConnection<()>> = None;
-        loop {
-            let input = MidiInput::new(APP_NAME).unwrap();
-            let current_port = get_inputs(&input)
-                .iter()
-                .position(|item| item == &port_name_notify);
-            if last_port.is_some() != current_port.is_some() {
-                if let Some(current_input) = current_input {
-                    current_input.close();
-                }
+        let mut reported = None;
+        let mut current_input: Option<Box<dyn InputPort>> = None;
+        while running_l.load(Ordering::Relaxed) {
+            let names = get_inputs();
+            let current_port = find_port(&names, &port_name_notify);
+            let current_name = current_port.map(|index| names[index].clone());
+            if last_port != current_name {
+                // disconnect the old port before connecting the new one
+                drop(current_input.take());
                 current_input = match current_port {
-                    Some(current_port) => {
-                        let tx_input = tx.clone();
-
-                        input
-                            .connect(
-                                current_port,
-                                &port_name_notify,
-                                move |stamp, msg, _| {
-                                    tx_input
-                                        .send(MidiInputMessage {
-                                            stamp,
-                                            data: Vec::from(msg),
-                                        })
-                                        .unwrap();
-                                },
-                                (),
-                            )
-                            .ok()
-                    }
+                    Some(current_port) => midi_backend::backend()
+                        .open_input(current_port, &port_name_notify, forward_input(&tx))
+                        .ok(),
                     None => None,
                 };
-                last_port = current_port;
+                last_port = if current_input.is_some() {
+                    current_name
+                } else {
+                    None
+                };
+                report_binding(&port_name_notify, &last_port, &mut reported);
             }
             thread::sleep(Duration::from_secs(1));
         }
+
+        // reference dropped: disconnect, which also ends the callback thread
+        drop(current_input);
     });
 
-    ThreadReference {}
+    ThreadReference { running }
 }
 
-pub fn get_output(port_name: &str) -> Option<MidiOutputConnection> {
-    let output = MidiOutput::new(APP_NAME).unwrap();
-    let port_number = match get_outputs(&output)
-        .iter()
-        .position(|item| item == port_name)
-    {
+// get_virtual_input if the config asks for a port of our own, otherwise get_input
+pub fn get_input_port<F>(port_name: &str, is_virtual: bool, callback: F) -> ThreadReference
+where
+    F: FnMut(u64, MidiMessage) + Send + 'static,
+{
+    if is_virtual {
+        get_virtual_input(port_name, callback)
+    } else {
+        get_input(port_name, callback)
+    }
+}
+
+// like get_input, but creates a port of our own that other software can send to
+pub fn get_virtual_input<F>(port_name: &str, callback: F) -> ThreadReference
+where
+    F: FnMut(u64, MidiMessage) + Send + 'static,
+{
+    let port_name = String::from(port_name);
+    let running = Arc::new(AtomicBool::new(true));
+    let running_l = running.clone();
+    let tx = spawn_input_callback(&port_name, running.clone(), callback);
+
+    thread::spawn(move || {
+        let connection = midi_backend::backend()
+            .create_virtual_input(&port_name, forward_input(&tx))
+            .map_err(|err| println!("[WARN] can't create virtual input {}: {}", port_name, err))
+            .ok();
+
+        while running_l.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        drop(connection);
+    });
+
+    ThreadReference { running }
+}
+
+fn spawn_input_callback<F>(
+    port_name: &str,
+    running: Arc<AtomicBool>,
+    callback: F,
+) -> mpsc::Sender<MidiInputMessage>
+where
+    F: FnMut(u64, MidiMessage) + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<MidiInputMessage>();
+    let port_name = String::from(port_name);
+
+    thread::spawn(move || {
+        let mut callback = callback;
+        let mut parser = MidiParser::new();
+        for msg in rx {
+            // stop delivering as soon as the reference is dropped, the owner may be gone
+            if !running.load(Ordering::Relaxed) {
+                break;
+            }
+            parser.feed(&msg.data, |message| {
+                tap(&port_name, Direction::In, &message);
+                callback(msg.stamp, message)
+            });
+        }
+    });
+
+    tx
+}
+
+// hands the raw bytes from the port over to the callback thread
+fn forward_input(tx: &mpsc::Sender<MidiInputMessage>) -> midi_backend::InputCallback {
+    let tx = tx.clone();
+    Box::new(move |stamp, msg| {
+        // fails once the callback thread has stopped, nothing to do
+        let _ = tx.send(MidiInputMessage {
+            stamp,
+            data: Vec::from(msg),
+        });
+    })
+}
+
+pub fn get_virtual_output(port_name: &str) -> Option<Box<dyn OutputPort>> {
+    match midi_backend::backend().create_virtual_output(port_name) {
+        Ok(port) => Some(port),
+        Err(err) => {
+            println!("[WARN] can't create virtual output {}: {}", port_name, err);
+            None
+        }
+    }
+}
+
+pub fn get_output(port_name: &str) -> Option<Box<dyn OutputPort>> {
+    let port_number = match find_port(&get_outputs(), port_name) {
         None => return None,
         Some(value) => value,
     };
-    output.connect(port_number, port_name).ok()
+    midi_backend::backend()
+        .open_output(port_number, port_name)
+        .ok()
 }
 
-pub fn get_outputs(output: &MidiOutput) -> Vec<String> {
-    let mut result = Vec::new();
+pub enum PortMatcher {
+    Name(String),
+    Contains(String),
+    Regex(Regex),
+}
 
-    for i in 0..output.port_count() {
-        // for some reason, sometimes the port doesn't exist -- use empty string
-        result.push(output.port_name(i).unwrap_or(String::from("")));
+impl PortMatcher {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            PortMatcher::Name(value) => name == value,
+            PortMatcher::Contains(value) => name.to_lowercase().contains(&value.to_lowercase()),
+            PortMatcher::Regex(re) => re.is_match(name),
+        }
     }
+}
 
-    normalize_port_names(&result)
+lazy_static! {
+    // alias -> matchers in priority order, anywhere a port name is used it can be an alias
+    static ref PORT_ALIASES: Mutex<HashMap<String, Vec<PortMatcher>>> = Mutex::new(HashMap::new());
 }
 
-pub fn get_inputs(input: &MidiInput) -> Vec<String> {
-    let mut result = Vec::new();
+// ports already open pick up the new aliases on their next reconnect poll
+pub fn set_port_aliases(aliases: HashMap<String, Vec<PortMatcher>>) {
+    *PORT_ALIASES.lock().unwrap() = aliases;
+}
 
-    for i in 0..input.port_count() {
-        // for some reason, sometimes the port doesn't exist -- use empty string
-        result.push(input.port_name(i).unwrap_or(String::from("")));
+pub fn find_port(names: &[String], port_name: &str) -> Option<usize> {
+    let aliases = PORT_ALIASES.lock().unwrap();
+    match_port(names, port_name, aliases.get(port_name).map(Vec::as_slice))
+}
+
+// an alias tries its matchers in order, the first one matching any port wins
+fn match_port(
+    names: &[String],
+    port_name: &str,
+    matchers: Option<&[PortMatcher]>,
+) -> Option<usize> {
+    match matchers {
+        Some(matchers) => matchers
+            .iter()
+            .filter_map(|matcher| names.iter().position(|name| matcher.matches(name)))
+            .next(),
+        None => names.iter().position(|name| name == port_name),
     }
+}
 
-    normalize_port_names(&result)
+fn find_port_name(names: &[String], port_name: &str) -> Option<String> {
+    find_port(names, port_name).map(|index| names[index].clone())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficEvent {
+    pub at: SystemTime,
+    // song position when the message went through
+    pub pos: MidiTime,
+    pub port: String,
+    pub direction: Direction,
+    pub message: MidiMessage,
+}
+
+struct Tap {
+    tx: mpsc::Sender<TrafficEvent>,
+    pos: MidiTime,
+}
+
+lazy_static! {
+    static ref TAP: Mutex<Option<Tap>> = Mutex::new(None);
+}
+
+// skips the lock on every message while nothing is listening
+static TAPPED: AtomicBool = AtomicBool::new(false);
+
+// from now on a copy of every message in and out of every port goes to tx
+pub fn set_tap(tx: mpsc::Sender<TrafficEvent>) {
+    *TAP.lock().unwrap() = Some(Tap {
+        tx,
+        pos: MidiTime::zero(),
+    });
+    TAPPED.store(true, Ordering::Relaxed);
+}
+
+// the position to stamp tapped messages with, call as the scheduler moves
+pub fn set_tap_pos(pos: MidiTime) {
+    if TAPPED.load(Ordering::Relaxed) {
+        if let Some(ref mut tap) = *TAP.lock().unwrap() {
+            tap.pos = pos;
+        }
+    }
+}
+
+fn tap(port_name: &str, direction: Direction, message: &MidiMessage) {
+    if !TAPPED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(ref tap) = *TAP.lock().unwrap() {
+        // fails once the listener has gone away, nothing to do
+        let _ = tap.tx.send(TrafficEvent {
+            at: SystemTime::now(),
+            pos: tap.pos,
+            port: String::from(port_name),
+            direction,
+            message: message.clone(),
+        });
+    }
+}
+
+// says where an alias is bound when that changes, not on every retry of a port that won't open
+fn report_binding(port_name: &str, bound: &Option<String>, reported: &mut Option<String>) {
+    if bound == reported {
+        return;
+    }
+    *reported = bound.clone();
+
+    if PORT_ALIASES.lock().unwrap().contains_key(port_name) {
+        match bound {
+            Some(bound) => println!("Port alias {} bound to {}", port_name, bound),
+            None => println!("Port alias {} is not connected", port_name),
+        }
+    }
+}
+
+pub fn get_outputs() -> Vec<String> {
+    normalize_port_names(&midi_backend::backend().output_names())
+}
+
+pub fn get_inputs() -> Vec<String> {
+    normalize_port_names(&midi_backend::backend().input_names())
 }
 
 fn normalize_port_names(names: &Vec<String>) -> Vec<String> {
@@ -222,43 +519,243 @@ fn build_name(base: &str, device_id: u32, port_id: u32) -> String {
     result
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputError {
+    // the port can't keep up, the message was dropped
+    QueueFull,
+    // the connection was closed on shutdown
+    Closed,
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::QueueFull => write!(f, "send queue is full"),
+            OutputError::Closed => write!(f, "connection is closed"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SendStats {
+    // thrown away because the queue was full
+    pub dropped: u64,
+    // replaced by a newer value for the same control before they were sent
+    pub coalesced: u64,
+    // rejected by the port
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MessageClass {
+    // note offs, all notes off, sysex, clock and transport: never dropped, or notes hang and
+    // clocks drift
+    Critical,
+    // only the latest value matters (CCs, pitch bend, aftertouch)
+    Coalesce(u8, u8),
+    // note ons and the rest: better late than never, but dropped under pressure
+    Droppable,
+}
+
+fn classify(message: &MidiMessage) -> MessageClass {
+    match *message {
+        _ if message.is_note_off() => MessageClass::Critical,
+        // channel mode messages (all notes off and friends)
+        MidiMessage::ControlChange { control, .. } if control >= 120 => MessageClass::Critical,
+        MidiMessage::ControlChange { control: id, .. }
+        | MidiMessage::PolyAftertouch { note: id, .. } => {
+            MessageClass::Coalesce(message.status(), id)
+        }
+        MidiMessage::ChannelAftertouch { .. } | MidiMessage::PitchBend { .. } => {
+            MessageClass::Coalesce(message.status(), 0)
+        }
+        MidiMessage::NoteOn { .. } | MidiMessage::ProgramChange { .. } => MessageClass::Droppable,
+        _ => MessageClass::Critical,
+    }
+}
+
+#[derive(Default)]
+struct SendQueue {
+    pending: VecDeque<(Instant, MidiMessage)>,
+    stats: SendStats,
+    last_error: Option<String>,
+    // a message has been taken off the queue but isn't out yet
+    sending: bool,
+    closed: bool,
+}
+
+impl SendQueue {
+    fn push(&mut self, due: Instant, message: MidiMessage) -> Result<(), OutputError> {
+        if self.closed {
+            return Err(OutputError::Closed);
+        }
+
+        let class = classify(&message);
+
+        // the newer value goes to the back, so it can't overtake messages pushed before it
+        if let MessageClass::Coalesce(..) = class {
+            if let Some(index) = self
+                .pending
+                .iter()
+                .position(|(_, pending)| classify(pending) == class)
+            {
+                self.pending.remove(index);
+                self.pending.push_back((due, message));
+                self.stats.coalesced += 1;
+                return Ok(());
+            }
+        }
+
+        if self.pending.len() >= QUEUE_SIZE && class != MessageClass::Critical {
+            self.stats.dropped += 1;
+            return Err(OutputError::QueueFull);
+        }
+
+        self.pending.push_back((due, message));
+        Ok(())
+    }
+
+    fn report(&mut self, port_name: &str, since: &SendStats) {
+        println!(
+            "[WARN] {}: {} dropped (queue full), {} coalesced, {} failed{}",
+            port_name,
+            self.stats.dropped - since.dropped,
+            self.stats.coalesced - since.coalesced,
+            self.stats.failed - since.failed,
+            match self.last_error.take() {
+                Some(err) => format!(" ({})", err),
+                None => String::new(),
+            }
+        );
+    }
+}
+
 #[derive(Clone)]
 pub struct SharedMidiOutputConnection {
     state: Arc<Mutex<OutputState>>,
-    tx: mpsc::SyncSender<Vec<u8>>,
+    changed: Arc<Condvar>,
+    queue: Arc<(Mutex<SendQueue>, Condvar)>,
 }
 
 impl SharedMidiOutputConnection {
-    pub fn send_sync(&mut self, message: &[u8]) -> Result<(), SendError> {
-        let mut state = self.state.lock().unwrap();
+    pub fn send_sync(&mut self, message: MidiMessage) -> Result<(), String> {
+        self.state.lock().unwrap().send_now(&message)
+    }
 
-        if message.len() == 3 {
-            state
-                .current_values
-                .insert((message[0], message[1]), message[2]);
+    // async send (held back by the latency delay), anything that goes wrong is logged
+    pub fn send(&mut self, message: MidiMessage) {
+        match self.try_send(message) {
+            // counted, and reported at most once a second by the send thread
+            Ok(()) | Err(OutputError::QueueFull) => (),
+            Err(err) => {
+                let name = self.state.lock().unwrap().name.clone();
+                println!("[WARN] {}: {}", name, err);
+            }
         }
+    }
 
-        if let Some(ref mut port) = state.port {
-            port.send(message)
-        } else {
-            Ok(())
+    pub fn try_send(&mut self, message: MidiMessage) -> Result<(), OutputError> {
+        let due = Instant::now() + self.delay();
+        let (lock, ready) = &*self.queue;
+        let result = lock.lock().unwrap().push(due, message);
+        ready.notify_one();
+        result
+    }
+
+    // gives queued messages (up to a second) to go out, then disconnects for good
+    pub fn close(&mut self) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let mut queue = self.queue.0.lock().unwrap();
+            if (queue.pending.is_empty() && !queue.sending) || Instant::now() > deadline {
+                queue.closed = true;
+                self.queue.1.notify_one();
+                break;
+            }
+            drop(queue);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.port = None;
+        self.changed.notify_all();
+    }
+
+    // sends all notes off and all sound off on every channel
+    pub fn all_notes_off(&mut self) {
+        for channel in 1..=16 {
+            for &control in &[123, 120] {
+                self.send(MidiMessage::ControlChange {
+                    channel,
+                    control,
+                    value: 0,
+                });
+            }
         }
     }
 
-    // async send
-    pub fn send(&mut self, message: &[u8]) -> Result<(), mpsc::TrySendError<Vec<u8>>> {
-        self.tx.try_send(message.to_vec())
+    pub fn delay(&self) -> Duration {
+        self.state.lock().unwrap().delay
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    pub fn set_virtual(&mut self, is_virtual: bool) {
+        self.state.lock().unwrap().is_virtual = is_virtual;
+        self.changed.notify_all();
     }
 
     pub fn on_connect<F>(&mut self, callback: F)
     where
-        F: Fn(&mut MidiOutputConnection) + Send + 'static,
+        F: Fn(&mut dyn OutputPort) + Send + 'static,
     {
         let mut state = self.state.lock().unwrap();
         state.listeners.push(Box::new(callback));
     }
 }
 
+// maps the stamps of one input (microseconds since the port was opened) onto Instants
+pub struct StampClock {
+    origin: Option<Instant>,
+    last_stamp: u64,
+}
+
+impl StampClock {
+    pub fn new() -> Self {
+        StampClock {
+            origin: None,
+            last_stamp: 0,
+        }
+    }
+
+    // call as soon as the message arrives, delivery delay only ever makes the origin later,
+    // so the earliest origin seen is the closest to the truth
+    pub fn instant(&mut self, stamp: u64) -> Instant {
+        self.instant_at(stamp, Instant::now())
+    }
+
+    fn instant_at(&mut self, stamp: u64, now: Instant) -> Instant {
+        let origin = now.checked_sub(Duration::from_micros(stamp)).unwrap_or(now);
+
+        // stamps going backwards means the port was reopened
+        if stamp < self.last_stamp {
+            self.origin = None;
+        }
+        self.last_stamp = stamp;
+
+        let origin = match self.origin {
+            Some(current) if current < origin => current,
+            _ => origin,
+        };
+        self.origin = Some(origin);
+
+        origin + Duration::from_micros(stamp)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct MidiInputMessage {
     stamp: u64,
@@ -266,12 +763,149 @@ struct MidiInputMessage {
 }
 
 pub struct ThreadReference {
-    //tx: mpsc::Sender<()>
+    running: Arc<AtomicBool>,
 }
 
 impl Drop for ThreadReference {
     fn drop(&mut self) {
-        println!("DROP NOT IMPLEMENTED")
-        //self.tx.send(()).unwrap();
+        // the reconnect loop notices on its next poll and closes the input
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            channel: 1,
+            note,
+            velocity,
+        }
+    }
+
+    fn cc(channel: u8, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel,
+            control,
+            value,
+        }
+    }
+
+    fn pitch_bend(value: u16) -> MidiMessage {
+        MidiMessage::PitchBend { channel: 1, value }
+    }
+
+    #[test]
+    fn full_queue_keeps_note_offs_and_coalesces_ccs() {
+        let now = Instant::now();
+        let mut queue = SendQueue::default();
+        for note in 0..QUEUE_SIZE {
+            queue.push(now, note_on(note as u8, 100)).unwrap();
+        }
+
+        assert_eq!(
+            queue.push(now, note_on(60, 100)),
+            Err(OutputError::QueueFull)
+        );
+        assert_eq!(queue.push(now, cc(1, 1, 10)), Err(OutputError::QueueFull));
+        let note_off = MidiMessage::NoteOff {
+            channel: 1,
+            note: 60,
+            velocity: 0,
+        };
+        assert_eq!(queue.push(now, note_off), Ok(()));
+        assert_eq!(queue.push(now, note_on(61, 0)), Ok(()));
+        assert_eq!(queue.push(now, MidiMessage::Clock), Ok(()));
+        assert_eq!(queue.push(now, cc(1, 123, 0)), Ok(()));
+        assert_eq!(
+            queue.stats,
+            SendStats {
+                dropped: 2,
+                coalesced: 0,
+                failed: 0
+            }
+        );
+
+        let mut queue = SendQueue::default();
+        queue.push(now, cc(1, 1, 10)).unwrap();
+        queue.push(now, cc(2, 1, 10)).unwrap();
+        queue.push(now, cc(1, 1, 20)).unwrap();
+        queue.push(now, pitch_bend(8192)).unwrap();
+        queue.push(now, pitch_bend(9000)).unwrap();
+        let pending: Vec<MidiMessage> = queue.pending.iter().map(|(_, m)| m.clone()).collect();
+        assert_eq!(pending, vec![cc(2, 1, 10), cc(1, 1, 20), pitch_bend(9000)]);
+        assert_eq!(queue.stats.coalesced, 2);
+    }
+
+    #[test]
+    fn aliases_try_their_patterns_in_order() {
+        let names = vec![
+            String::from("Launchpad X LPX MIDI"),
+            String::from("MicroMonsta 2"),
+            String::from("Launchpad X"),
+        ];
+        let matchers = [
+            PortMatcher::Name(String::from("Launchpad X")),
+            PortMatcher::Contains(String::from("launchpad")),
+            PortMatcher::Regex(Regex::new("^Micro").unwrap()),
+        ];
+
+        // an exact name beats an earlier port the substring would match
+        assert_eq!(match_port(&names, "grid", Some(&matchers[..])), Some(2));
+        assert_eq!(match_port(&names, "grid", Some(&matchers[1..])), Some(0));
+        assert_eq!(match_port(&names, "grid", Some(&matchers[2..])), Some(1));
+        assert_eq!(match_port(&names[..2], "grid", Some(&matchers[..1])), None);
+
+        // without an alias only the exact name will do
+        assert_eq!(match_port(&names, "Launchpad X", None), Some(2));
+        assert_eq!(match_port(&names, "Launchpad", None), None);
+    }
+
+    #[test]
+    fn virtual_outputs_send_through_the_backend() {
+        let backend = Arc::new(midi_backend::NullBackend::new());
+        let (tx, rx) = mpsc::channel();
+        backend.record(tx);
+        let _backend = midi_backend::set_test_backend(backend);
+
+        let mut output = get_shared_output("Dry Run");
+        let (connected_tx, connected) = mpsc::channel();
+        let connected_tx = Mutex::new(connected_tx);
+        output.on_connect(move |_port| connected_tx.lock().unwrap().send(()).unwrap());
+        output.set_virtual(true);
+        assert_eq!(connected.recv_timeout(Duration::from_millis(500)), Ok(()));
+
+        output.send(cc(1, 7, 100));
+        output.close();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok((String::from("Dry Run"), cc(1, 7, 100)))
+        );
+    }
+
+    #[test]
+    fn stamp_clock_keeps_the_earliest_origin() {
+        let now = Instant::now();
+        let mut clock = StampClock::new();
+        assert_eq!(clock.instant_at(1_000, now), now);
+
+        // delivered 4ms late: placed where the stamp says, not when it arrived
+        let late = now + Duration::from_millis(5);
+        assert_eq!(
+            clock.instant_at(2_000, late),
+            now + Duration::from_millis(1)
+        );
+
+        // and on time again after it
+        assert_eq!(
+            clock.instant_at(3_000, now + Duration::from_millis(2)),
+            now + Duration::from_millis(2)
+        );
+
+        // stamps going backwards: the port was reopened, start over
+        let reopened = now + Duration::from_secs(1);
+        assert_eq!(clock.instant_at(500, reopened), reopened);
     }
 }
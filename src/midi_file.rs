@@ -0,0 +1,346 @@
+use midi_connection::MidiMessage;
+use midi_time::{MidiTime, SUB_TICKS};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+// one MidiTime tick is 1/24 beat, keep sub tick precision in the file
+pub const TICKS_PER_BEAT: u16 = 24 * SUB_TICKS as u16;
+
+// a note on (or off when velocity is 0) read from a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteEvent {
+    pub pos: MidiTime,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+pub struct Track {
+    name: String,
+    events: Vec<(u32, Vec<u8>)>,
+}
+
+impl Track {
+    pub fn new(name: &str) -> Self {
+        Track {
+            name: String::from(name),
+            events: Vec::new(),
+        }
+    }
+
+    // pos is relative to the start of the file, channel is 1 - 16
+    pub fn note(&mut self, pos: MidiTime, channel: u8, note: u8, velocity: u8) {
+        let ticks = (pos.ticks() * SUB_TICKS as i32 + pos.sub_ticks() as i32).max(0) as u32;
+        let message = MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        };
+        self.events.push((ticks, message.to_bytes()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut events = self.events.clone();
+
+        // offs (velocity 0) before ons at the same position so retriggers don't get swallowed
+        events.sort_by_key(|(ticks, message)| (*ticks, message[2] > 0));
+
+        let mut data = Vec::new();
+        write_var_len(&mut data, 0);
+        data.extend_from_slice(&[0xFF, 0x03]);
+        write_var_len(&mut data, self.name.len() as u32);
+        data.extend_from_slice(self.name.as_bytes());
+
+        let mut last_ticks = 0;
+        for (ticks, message) in events {
+            write_var_len(&mut data, ticks - last_ticks);
+            data.extend_from_slice(&message);
+            last_ticks = ticks;
+        }
+
+        write_var_len(&mut data, 0);
+        data.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+        let mut bytes = Vec::from(&b"MTrk"[..]);
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend(data);
+        bytes
+    }
+}
+
+// format 1 (simultaneous tracks)
+pub fn to_bytes(tracks: &[Track]) -> Vec<u8> {
+    let mut bytes = Vec::from(&b"MThd"[..]);
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+
+    for track in tracks {
+        bytes.extend(track.to_bytes());
+    }
+
+    bytes
+}
+
+pub fn write(filepath: &str, tracks: &[Track]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filepath)?);
+    writer.write_all(&to_bytes(tracks))?;
+    writer.flush()
+}
+
+pub fn read(filepath: &str) -> Result<Vec<NoteEvent>, Box<dyn Error>> {
+    let bytes = fs::read(filepath)?;
+    Ok(parse(&bytes)?)
+}
+
+// notes from all tracks merged in time order, tempo changes are ignored (positions are in beats)
+pub fn parse(bytes: &[u8]) -> Result<Vec<NoteEvent>, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(4)? != b"MThd" {
+        return Err(String::from("not a standard MIDI file"));
+    }
+    let header_length = reader.u32()? as usize;
+    let header = reader.take(header_length)?;
+    if header_length < 6 {
+        return Err(String::from("truncated header"));
+    }
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(String::from("SMPTE time division is not supported"));
+    }
+
+    let mut result = Vec::new();
+    while reader.pos < bytes.len() {
+        let chunk_type = reader.take(4)?;
+        let chunk_length = reader.u32()? as usize;
+        let data = reader.take(chunk_length)?;
+        if chunk_type == b"MTrk" {
+            parse_track(data, division, &mut result)?;
+        }
+    }
+
+    result.sort_by_key(|event| (event.pos, event.velocity > 0));
+    Ok(result)
+}
+
+fn parse_track(data: &[u8], division: u16, result: &mut Vec<NoteEvent>) -> Result<(), String> {
+    let mut reader = Reader {
+        bytes: data,
+        pos: 0,
+    };
+    let mut ticks: u64 = 0;
+    let mut running_status = None;
+
+    while reader.pos < data.len() {
+        ticks += reader.var_len()? as u64;
+
+        let mut status = reader.u8()?;
+        let first_data = if status < 0x80 {
+            // running status, this byte is already data
+            let data_byte = status;
+            status = running_status.ok_or_else(|| String::from("data byte without status"))?;
+            Some(data_byte)
+        } else {
+            None
+        };
+
+        match status {
+            0xFF => {
+                reader.u8()?;
+                let length = reader.var_len()? as usize;
+                reader.take(length)?;
+            }
+            0xF0 | 0xF7 => {
+                let length = reader.var_len()? as usize;
+                reader.take(length)?;
+            }
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let data_1 = match first_data {
+                    Some(data_byte) => data_byte,
+                    None => reader.u8()?,
+                };
+                let message = match status & 0xF0 {
+                    0xC0 | 0xD0 => MidiMessage::parse(&[status, data_1]),
+                    _ => MidiMessage::parse(&[status, data_1, reader.u8()?]),
+                };
+
+                let pos = MidiTime::from_float(ticks as f64 * 24.0 / division as f64);
+                match message {
+                    Some(MidiMessage::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                    }) => result.push(NoteEvent {
+                        pos,
+                        channel,
+                        note,
+                        velocity,
+                    }),
+                    Some(MidiMessage::NoteOff { channel, note, .. }) => result.push(NoteEvent {
+                        pos,
+                        channel,
+                        note,
+                        velocity: 0,
+                    }),
+                    _ => (),
+                }
+            }
+            _ => return Err(format!("unexpected status byte {:#x}", status)),
+        }
+    }
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.pos + length > self.bytes.len() {
+            return Err(String::from("unexpected end of file"));
+        }
+        let result = &self.bytes[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn var_len(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("variable length value is too long"))
+    }
+}
+
+fn write_var_len(data: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    groups.reverse();
+    data.extend(groups);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn var_len() {
+        let cases: [(u32, &[u8]); 5] = [
+            (0, &[0x00]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x81, 0x00]),
+            (0x3FFF, &[0xFF, 0x7F]),
+            (0x0FFFFFFF, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+
+        for (value, expected) in cases.iter() {
+            let mut data = Vec::new();
+            write_var_len(&mut data, *value);
+            assert_eq!(&data[..], *expected);
+        }
+    }
+
+    #[test]
+    fn single_note_track() {
+        let mut track = Track::new("A");
+        track.note(MidiTime::from_beats(1), 10, 36, 0);
+        track.note(MidiTime::zero(), 10, 36, 100);
+
+        let bytes = to_bytes(&[track]);
+        assert_eq!(
+            &bytes[0..14],
+            &[77, 84, 104, 100, 0, 0, 0, 6, 0, 1, 0, 1, 0, 192]
+        );
+        assert_eq!(
+            &bytes[14..],
+            &[
+                77, 84, 114, 107, 0, 0, 0, 18, // MTrk, length
+                0x00, 0xFF, 0x03, 1, 65, // name
+                0x00, 0x99, 36, 100, // on at 0
+                0x81, 0x40, 0x99, 36, 0, // off after 192 ticks
+                0x00, 0xFF, 0x2F, 0x00 // end of track
+            ][..]
+        );
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let mut track = Track::new("B");
+        track.note(MidiTime::new(12, 4), 2, 60, 90);
+        track.note(MidiTime::from_beats(2), 2, 60, 0);
+
+        let events = parse(&to_bytes(&[track])).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                NoteEvent {
+                    pos: MidiTime::new(12, 4),
+                    channel: 2,
+                    note: 60,
+                    velocity: 90
+                },
+                NoteEvent {
+                    pos: MidiTime::from_beats(2),
+                    channel: 2,
+                    note: 60,
+                    velocity: 0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_running_status_and_note_off() {
+        let mut bytes = Vec::from(&b"MThd"[..]);
+        bytes.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0, 96]);
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&[0, 0, 0, 13]);
+        bytes.extend_from_slice(&[
+            0x00, 0x90, 36, 100, // on
+            0x30, 38, 80, // running status on, half a beat later
+            0x30, 0x80, 36, 64, // note off message
+            0x00, 0x20, // truncated
+        ]);
+
+        assert_eq!(parse(&bytes), Err(String::from("unexpected end of file")));
+
+        let length = bytes.len();
+        bytes.truncate(length - 2);
+        bytes[21] = 11;
+        let events = parse(&bytes).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].pos, MidiTime::from_ticks(12));
+        assert_eq!(events[1].note, 38);
+        assert_eq!(events[2].velocity, 0);
+        assert_eq!(events[2].pos, MidiTime::from_beats(1));
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt;
+
+// channel messages number channels 1 - 16, the same as the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    // velocity 0 is a note off too (see is_note_off)
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, pressure: u8 },
+    // 14 bit, 8192 is the centre
+    PitchBend { channel: u8, value: u16 },
+    // without the 0xF0 / 0xF7 framing
+    SysEx(Vec<u8>),
+    // in 16th notes from the top
+    SongPosition(u16),
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+    // system messages nothing here needs to look inside (timecode, song select, tune request)
+    Other(Vec<u8>),
+}
+
+impl MidiMessage {
+    // one complete message, as delivered by a port
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut result = None;
+        MidiParser::new().feed(bytes, |message| {
+            if result.is_none() {
+                result = Some(message)
+            }
+        });
+        result
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![channel_status(0x80, channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![channel_status(0x90, channel), note & 0x7F, velocity & 0x7F],
+            MidiMessage::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            } => vec![channel_status(0xA0, channel), note & 0x7F, pressure & 0x7F],
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => vec![channel_status(0xB0, channel), control & 0x7F, value & 0x7F],
+            MidiMessage::ProgramChange { channel, program } => {
+                vec![channel_status(0xC0, channel), program & 0x7F]
+            }
+            MidiMessage::ChannelAftertouch { channel, pressure } => {
+                vec![channel_status(0xD0, channel), pressure & 0x7F]
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                let (lsb, msb) = split_14bit(value);
+                vec![channel_status(0xE0, channel), lsb, msb]
+            }
+            MidiMessage::SysEx(ref data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend(data.iter().map(|byte| byte & 0x7F));
+                bytes.push(0xF7);
+                bytes
+            }
+            MidiMessage::SongPosition(sixteenths) => {
+                let (lsb, msb) = split_14bit(sixteenths);
+                vec![0xF2, lsb, msb]
+            }
+            MidiMessage::Clock => vec![0xF8],
+            MidiMessage::Start => vec![0xFA],
+            MidiMessage::Continue => vec![0xFB],
+            MidiMessage::Stop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+            MidiMessage::Other(ref bytes) => bytes.clone(),
+        }
+    }
+
+    // the status byte this message is sent with
+    pub fn status(&self) -> u8 {
+        match *self {
+            MidiMessage::SysEx(..) => 0xF0,
+            MidiMessage::Other(ref bytes) => bytes.first().cloned().unwrap_or(0),
+            _ => self.to_bytes()[0],
+        }
+    }
+
+    // the same message on another channel (anything without a channel is left alone)
+    pub fn with_channel(mut self, new_channel: u8) -> Self {
+        match self {
+            MidiMessage::NoteOff {
+                ref mut channel, ..
+            }
+            | MidiMessage::NoteOn {
+                ref mut channel, ..
+            }
+            | MidiMessage::PolyAftertouch {
+                ref mut channel, ..
+            }
+            | MidiMessage::ControlChange {
+                ref mut channel, ..
+            }
+            | MidiMessage::ProgramChange {
+                ref mut channel, ..
+            }
+            | MidiMessage::ChannelAftertouch {
+                ref mut channel, ..
+            }
+            | MidiMessage::PitchBend {
+                ref mut channel, ..
+            } => *channel = new_channel,
+            _ => (),
+        }
+        self
+    }
+
+    pub fn is_note_off(&self) -> bool {
+        match *self {
+            MidiMessage::NoteOff { .. } | MidiMessage::NoteOn { velocity: 0, .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => write!(f, "ch {:>2} note off {:>3} vel {}", channel, note, velocity),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => write!(f, "ch {:>2} note on  {:>3} vel {}", channel, note, velocity),
+            MidiMessage::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            } => write!(f, "ch {:>2} poly AT  {:>3} val {}", channel, note, pressure),
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => write!(f, "ch {:>2} cc       {:>3} val {}", channel, control, value),
+            MidiMessage::ProgramChange { channel, program } => {
+                write!(f, "ch {:>2} program  {:>3}", channel, program)
+            }
+            MidiMessage::ChannelAftertouch { channel, pressure } => {
+                write!(f, "ch {:>2} AT           val {}", channel, pressure)
+            }
+            MidiMessage::PitchBend { channel, value } => write!(
+                f,
+                "ch {:>2} bend         val {:+}",
+                channel,
+                value as i32 - 8192
+            ),
+            MidiMessage::SysEx(ref data) => write!(f, "sysex {}", hex(data)),
+            MidiMessage::SongPosition(sixteenths) => write!(f, "song position {}", sixteenths),
+            MidiMessage::Clock => write!(f, "clock"),
+            MidiMessage::Start => write!(f, "start"),
+            MidiMessage::Continue => write!(f, "continue"),
+            MidiMessage::Stop => write!(f, "stop"),
+            MidiMessage::ActiveSensing => write!(f, "active sensing"),
+            MidiMessage::Reset => write!(f, "reset"),
+            MidiMessage::Other(ref bytes) => write!(f, "{}", hex(bytes)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    bytes.join(" ")
+}
+
+// turns a stream of bytes into messages: follows running status, collects sysex and lets
+// realtime messages (clock) through from the middle of anything else
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: Vec<u8>,
+    sysex: Option<Vec<u8>>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed<F>(&mut self, bytes: &[u8], mut emit: F)
+    where
+        F: FnMut(MidiMessage),
+    {
+        for &byte in bytes {
+            if byte >= 0xF8 {
+                if let Some(message) = decode(byte, &[]) {
+                    emit(message);
+                }
+                continue;
+            }
+
+            if let Some(mut sysex) = self.sysex.take() {
+                if byte < 0x80 {
+                    sysex.push(byte);
+                    self.sysex = Some(sysex);
+                    continue;
+                } else if byte == 0xF7 {
+                    emit(MidiMessage::SysEx(sysex));
+                    continue;
+                }
+                // any other status ends it early, an unterminated sysex is thrown away
+            }
+
+            if byte == 0xF0 {
+                self.sysex = Some(Vec::new());
+                self.status = None;
+                continue;
+            }
+
+            if byte >= 0x80 {
+                self.status = Some(byte);
+                self.data.clear();
+            } else if self.status.is_some() {
+                self.data.push(byte);
+            } else {
+                // data without a status to run on
+                continue;
+            }
+
+            let status = self.status.unwrap();
+            if self.data.len() == data_len(status) {
+                if let Some(message) = decode(status, &self.data) {
+                    emit(message);
+                }
+                self.data.clear();
+
+                // only channel messages can be followed by running status
+                if status >= 0xF0 {
+                    self.status = None;
+                }
+            }
+        }
+    }
+}
+
+fn channel_status(status: u8, channel: u8) -> u8 {
+    status | (channel.saturating_sub(1) & 0x0F)
+}
+
+fn split_14bit(value: u16) -> (u8, u8) {
+    let value = value.min(0x3FFF);
+    ((value & 0x7F) as u8, (value >> 7) as u8)
+}
+
+fn join_14bit(lsb: u8, msb: u8) -> u16 {
+    lsb as u16 | (msb as u16) << 7
+}
+
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+fn decode(status: u8, data: &[u8]) -> Option<MidiMessage> {
+    let channel = (status & 0x0F) + 1;
+    Some(match status & 0xF0 {
+        0x80 => MidiMessage::NoteOff {
+            channel,
+            note: data[0],
+            velocity: data[1],
+        },
+        0x90 => MidiMessage::NoteOn {
+            channel,
+            note: data[0],
+            velocity: data[1],
+        },
+        0xA0 => MidiMessage::PolyAftertouch {
+            channel,
+            note: data[0],
+            pressure: data[1],
+        },
+        0xB0 => MidiMessage::ControlChange {
+            channel,
+            control: data[0],
+            value: data[1],
+        },
+        0xC0 => MidiMessage::ProgramChange {
+            channel,
+            program: data[0],
+        },
+        0xD0 => MidiMessage::ChannelAftertouch {
+            channel,
+            pressure: data[0],
+        },
+        0xE0 => MidiMessage::PitchBend {
+            channel,
+            value: join_14bit(data[0], data[1]),
+        },
+        _ => match status {
+            0xF2 => MidiMessage::SongPosition(join_14bit(data[0], data[1])),
+            0xF1 | 0xF3 | 0xF6 => {
+                let mut bytes = vec![status];
+                bytes.extend_from_slice(data);
+                MidiMessage::Other(bytes)
+            }
+            0xF8 => MidiMessage::Clock,
+            0xFA => MidiMessage::Start,
+            0xFB => MidiMessage::Continue,
+            0xFC => MidiMessage::Stop,
+            0xFE => MidiMessage::ActiveSensing,
+            0xFF => MidiMessage::Reset,
+            _ => return None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut result = Vec::new();
+        MidiParser::new().feed(bytes, |message| result.push(message));
+        result
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        let messages = vec![
+            MidiMessage::NoteOff {
+                channel: 1,
+                note: 60,
+                velocity: 64,
+            },
+            MidiMessage::NoteOn {
+                channel: 16,
+                note: 127,
+                velocity: 1,
+            },
+            MidiMessage::PolyAftertouch {
+                channel: 3,
+                note: 36,
+                pressure: 90,
+            },
+            MidiMessage::ControlChange {
+                channel: 10,
+                control: 7,
+                value: 100,
+            },
+            MidiMessage::ProgramChange {
+                channel: 2,
+                program: 5,
+            },
+            MidiMessage::ChannelAftertouch {
+                channel: 4,
+                pressure: 12,
+            },
+            MidiMessage::PitchBend {
+                channel: 1,
+                value: 8192,
+            },
+            MidiMessage::SysEx(vec![0x00, 0x20, 0x29, 0x02, 0x0E, 0x0E, 0x01]),
+            MidiMessage::SongPosition(1000),
+            MidiMessage::Clock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::Other(vec![0xF3, 2]),
+        ];
+
+        for message in messages {
+            assert_eq!(MidiMessage::parse(&message.to_bytes()), Some(message));
+        }
+    }
+
+    #[test]
+    fn channels_are_one_based() {
+        let message = MidiMessage::ControlChange {
+            channel: 1,
+            control: 1,
+            value: 2,
+        };
+        assert_eq!(message.to_bytes(), vec![176, 1, 2]);
+        assert_eq!(
+            MidiMessage::parse(&[191, 1, 2]),
+            Some(message.with_channel(16))
+        );
+        assert_eq!(
+            MidiMessage::PitchBend {
+                channel: 2,
+                value: 0x3FFF + 1
+            }
+            .to_bytes(),
+            vec![225, 127, 127]
+        );
+    }
+
+    #[test]
+    fn follows_running_status_around_realtime_messages() {
+        let note_on = |note, velocity| MidiMessage::NoteOn {
+            channel: 1,
+            note,
+            velocity,
+        };
+        assert_eq!(
+            parse_all(&[144, 60, 100, 62, 0xF8, 100, 60, 0, 0xF2, 8, 0, 64]),
+            vec![
+                note_on(60, 100),
+                MidiMessage::Clock,
+                note_on(62, 100),
+                note_on(60, 0),
+                MidiMessage::SongPosition(8),
+            ]
+        );
+
+        // split across calls, with a clock in the middle of a sysex
+        let mut parser = MidiParser::new();
+        let mut result = Vec::new();
+        parser.feed(&[0xF0, 1, 2], |message| result.push(message));
+        parser.feed(&[0xF8, 3, 0xF7, 0xB0, 1], |message| result.push(message));
+        parser.feed(&[2], |message| result.push(message));
+        assert_eq!(
+            result,
+            vec![
+                MidiMessage::Clock,
+                MidiMessage::SysEx(vec![1, 2, 3]),
+                MidiMessage::ControlChange {
+                    channel: 1,
+                    control: 1,
+                    value: 2
+                },
+            ]
+        );
+    }
+}
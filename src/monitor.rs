@@ -0,0 +1,168 @@
+use midi_connection::{self, Direction, MidiMessage, TrafficEvent};
+use midi_time::MidiTime;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+// each log file is rotated out at this size, keeping the last few
+const LOG_MAX_BYTES: u64 = 8 * 1024 * 1024;
+const LOG_FILES: usize = 4;
+
+// prints all MIDI traffic as it happens (live) and/or appends it to a rotating log file
+pub fn start(live: bool, log_path: Option<&str>) -> io::Result<()> {
+    let mut log = match log_path {
+        Some(path) => Some(RotatingLog::open(path, LOG_MAX_BYTES)?),
+        None => None,
+    };
+
+    let (tx, rx) = mpsc::channel();
+    midi_connection::set_tap(tx);
+
+    thread::spawn(move || {
+        for event in rx {
+            // 24 clocks a beat per port would bury everything else
+            match event.message {
+                MidiMessage::Clock | MidiMessage::ActiveSensing => continue,
+                _ => (),
+            }
+
+            let line = format_event(&event);
+            if live {
+                println!("{}", line);
+            }
+
+            let failed = match log {
+                Some(ref mut log) => log.write_line(&line).err(),
+                None => None,
+            };
+            if let Some(err) = failed {
+                println!("[WARN] stopped writing the MIDI log: {}", err);
+                log = None;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn format_event(event: &TrafficEvent) -> String {
+    format!(
+        "{} {:>10} {:<3} {:<24} {}",
+        format_time_of_day(event),
+        format_pos(event.pos),
+        match event.direction {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+        },
+        event.port,
+        event.message
+    )
+}
+
+// UTC, to the millisecond
+fn format_time_of_day(event: &TrafficEvent) -> String {
+    let since_epoch = event.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % (24 * 60 * 60);
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// beat:tick.sub_tick (24 ticks a beat)
+pub fn format_pos(pos: MidiTime) -> String {
+    format!(
+        "{}:{:02}.{}",
+        pos.ticks().div_euclid(24),
+        pos.ticks().rem_euclid(24),
+        pos.sub_ticks()
+    )
+}
+
+struct RotatingLog {
+    path: String,
+    file: LineWriter<File>,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl RotatingLog {
+    fn open(path: &str, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingLog {
+            path: String::from(path),
+            file: LineWriter::new(file),
+            written,
+            max_bytes,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        // whole lines only, so the file is readable right up to a crash
+        writeln!(self.file, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // path -> path.1 -> path.2 ..., the oldest falls off the end
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for index in (1..LOG_FILES).rev() {
+            let from = match index {
+                1 => self.path.clone(),
+                _ => format!("{}.{}", self.path, index - 1),
+            };
+            if Path::new(&from).exists() {
+                fs::rename(&from, format!("{}.{}", self.path, index))?;
+            }
+        }
+
+        self.file = LineWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_rotates_and_keeps_the_last_files() {
+        let dir = std::env::temp_dir().join(format!("loop-drop-monitor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("midi.log");
+        let path = path.to_str().unwrap();
+
+        // 9 bytes a line, two lines a file
+        let mut log = RotatingLog::open(path, 20).unwrap();
+        for index in 0..10 {
+            log.write_line(&format!("line {:03}", index)).unwrap();
+        }
+
+        let read = |suffix: &str| fs::read_to_string(format!("{}{}", path, suffix)).unwrap();
+        assert_eq!(read(""), "line 008\nline 009\n");
+        assert_eq!(read(".1"), "line 006\nline 007\n");
+        assert_eq!(read(".3"), "line 002\nline 003\n");
+        assert!(!Path::new(&format!("{}.4", path)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn positions_count_beats_from_zero() {
+        assert_eq!(format_pos(MidiTime::new(50, 3)), "2:02.3");
+        assert_eq!(format_pos(MidiTime::from_ticks(-1)), "-1:23.0");
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Ord, PartialOrd, Debug, Eq, PartialEq, Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Ord, PartialOrd, Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum OutputValue {
     // Insert offs after ons when sorting
     On(u8), Off
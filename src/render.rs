@@ -0,0 +1,417 @@
+use chunk::{ChunkMap, LatchMode, ScheduleMode, Triggerable};
+use grid_surfaces::Button;
+use loop_grid_launchpad::LoopGridLaunchpad;
+use midi_connection::MidiMessage;
+use midi_file;
+use midi_time::MidiTime;
+use monitor;
+use output_value::OutputValue;
+use scheduler::ScheduleRange;
+use serde::Deserialize;
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+// a grid button pressed (or released, value 0) at a position of the render
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptedPress {
+    pub pos: MidiTime,
+    pub button: Button,
+    pub value: u8,
+}
+
+// a note on (or off when velocity is 0) a chunk's device played, at the start of the range
+// (one sub tick) it was sent in: swing is in there, but a note can land up to a sub tick late
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderedNote {
+    pub pos: MidiTime,
+    pub chunk_index: usize,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+// one press of a --script file, e.g. { "beats": 1.5, "button": { "Grid": 0 }, "value": 127 }
+#[derive(Deserialize)]
+struct ScriptLine {
+    beats: f64,
+    button: Button,
+    value: u8,
+}
+
+// a JSON list of presses, in any order
+pub fn read_script(filepath: &str) -> Result<Vec<ScriptedPress>, Box<dyn Error>> {
+    parse_script(&fs::read_to_string(filepath)?)
+}
+
+fn parse_script(json: &str) -> Result<Vec<ScriptedPress>, Box<dyn Error>> {
+    let lines: Vec<ScriptLine> = serde_json::from_str(json)?;
+    Ok(lines
+        .into_iter()
+        .map(|line| ScriptedPress {
+            pos: MidiTime::from_float(line.beats * 24.0),
+            button: line.button,
+            value: line.value,
+        })
+        .collect())
+}
+
+struct Output {
+    pos: MidiTime,
+    notes: Vec<RenderedNote>,
+}
+
+// plays a LoopGridLaunchpad from a clock (usually a VirtualClock) and a script of presses,
+// keeping the notes its chunks play instead of depending on when the ports send them
+pub struct Renderer {
+    output: Arc<Mutex<Output>>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            output: Arc::new(Mutex::new(Output {
+                pos: MidiTime::zero(),
+                notes: Vec::new(),
+            })),
+        }
+    }
+
+    // call on the chunks before they go to the launchpad, they still play their devices
+    pub fn capture(&self, chunk_map: Vec<Box<ChunkMap>>) -> Vec<Box<ChunkMap>> {
+        chunk_map
+            .into_iter()
+            .enumerate()
+            .map(|(chunk_index, mut chunk_map)| {
+                let chunk = std::mem::replace(&mut chunk_map.chunk, Box::new(Silent));
+                chunk_map.chunk = Box::new(Capture {
+                    chunk,
+                    chunk_index,
+                    playing: HashMap::new(),
+                    output: Arc::clone(&self.output),
+                });
+                chunk_map
+            })
+            .collect()
+    }
+
+    // everything still sounding at the end of the clock is released there
+    pub fn render<I>(
+        &self,
+        launchpad: &mut LoopGridLaunchpad,
+        clock: I,
+        script: &[ScriptedPress],
+    ) -> Vec<RenderedNote>
+    where
+        I: Iterator<Item = ScheduleRange>,
+    {
+        let mut script = script.to_vec();
+        script.sort_by_key(|press| press.pos);
+        let mut script = script.into_iter().peekable();
+        let mut end = MidiTime::zero();
+
+        for range in clock {
+            while let Some(press) = script.next_if(|press| press.pos <= range.from) {
+                launchpad.press(press.button, press.value);
+            }
+            self.output.lock().unwrap().pos = range.from;
+            launchpad.schedule(range);
+            end = range.to;
+        }
+
+        self.output.lock().unwrap().pos = end;
+        launchpad.panic();
+
+        std::mem::replace(&mut self.output.lock().unwrap().notes, Vec::new())
+    }
+}
+
+// one track per chunk, like an export
+pub fn to_tracks(notes: &[RenderedNote]) -> Vec<midi_file::Track> {
+    let chunk_count = notes
+        .iter()
+        .map(|note| note.chunk_index + 1)
+        .max()
+        .unwrap_or(0);
+    let mut tracks: Vec<midi_file::Track> = (0..chunk_count)
+        .map(|chunk_index| midi_file::Track::new(&format!("Chunk {}", chunk_index)))
+        .collect();
+    for note in notes {
+        tracks[note.chunk_index].note(note.pos, note.channel, note.note, note.velocity);
+    }
+    tracks.retain(|track| !track.is_empty());
+    tracks
+}
+
+// a line per note, laid out like the monitor
+pub fn to_text(notes: &[RenderedNote]) -> String {
+    let mut result = String::new();
+    for note in notes {
+        let message = MidiMessage::NoteOn {
+            channel: note.channel,
+            note: note.note,
+            velocity: note.velocity,
+        };
+        result.push_str(&format!(
+            "{:>10} chunk {:<3} {}\n",
+            monitor::format_pos(note.pos),
+            note.chunk_index,
+            message
+        ));
+    }
+    result
+}
+
+// keeps the notes the chunk's device would send (see Triggerable::get_notes)
+struct Capture {
+    chunk: Box<dyn Triggerable + Send>,
+    chunk_index: usize,
+    playing: HashMap<u32, Vec<(u8, u8, u8)>>,
+    output: Arc<Mutex<Output>>,
+}
+
+impl Capture {
+    fn release(&mut self, id: u32) {
+        let mut output = self.output.lock().unwrap();
+        let pos = output.pos;
+        for (channel, note, _) in self.playing.remove(&id).unwrap_or_default() {
+            output.notes.push(RenderedNote {
+                pos,
+                chunk_index: self.chunk_index,
+                channel,
+                note,
+                velocity: 0,
+            });
+        }
+    }
+}
+
+impl Triggerable for Capture {
+    fn trigger(&mut self, id: u32, value: OutputValue) {
+        self.release(id);
+        if let OutputValue::On(velocity) = value {
+            let notes = self.chunk.get_notes(id, velocity);
+            let mut output = self.output.lock().unwrap();
+            let pos = output.pos;
+            for &(channel, note, velocity) in &notes {
+                output.notes.push(RenderedNote {
+                    pos,
+                    chunk_index: self.chunk_index,
+                    channel,
+                    note,
+                    velocity,
+                });
+            }
+            self.playing.insert(id, notes);
+        }
+        self.chunk.trigger(id, value);
+    }
+
+    fn on_tick(&mut self, time: MidiTime) {
+        self.chunk.on_tick(time)
+    }
+
+    fn get_active(&self) -> Option<HashSet<u32>> {
+        self.chunk.get_active()
+    }
+
+    fn latch_mode(&self) -> LatchMode {
+        self.chunk.latch_mode()
+    }
+
+    fn schedule_mode(&self) -> ScheduleMode {
+        self.chunk.schedule_mode()
+    }
+
+    fn get_notes(&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+        self.chunk.get_notes(id, velocity)
+    }
+
+    fn get_id_for_note(&self, channel: u8, note: u8) -> Option<u32> {
+        self.chunk.get_id_for_note(channel, note)
+    }
+
+    fn all_notes_off(&mut self) {
+        // in id order, so that renders always come out the same
+        let mut ids: Vec<u32> = self.playing.keys().cloned().collect();
+        ids.sort();
+        for id in ids {
+            self.release(id);
+        }
+        self.chunk.all_notes_off();
+    }
+}
+
+// only ever in a ChunkMap for as long as its chunk is being swapped out
+struct Silent;
+
+impl Triggerable for Silent {
+    fn trigger(&mut self, _id: u32, _value: OutputValue) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::{Coords, RepeatMode, Shape};
+    use loop_grid_launchpad::LoopGridParams;
+    use scheduler::VirtualClock;
+    use std::env;
+
+    // plays note 36 + id on channel 1
+    struct Notes;
+
+    impl Triggerable for Notes {
+        fn trigger(&mut self, _id: u32, _value: OutputValue) {}
+
+        fn get_notes(&self, id: u32, velocity: u8) -> Vec<(u8, u8, u8)> {
+            vec![(1, 36 + id as u8, velocity)]
+        }
+    }
+
+    // a launchpad with one 1x4 chunk in the top left corner
+    fn launchpad(renderer: &Renderer, swing: f64) -> LoopGridLaunchpad {
+        let chunk = ChunkMap::new(
+            Box::new(Notes),
+            Coords::new(0, 0),
+            Shape::new(1, 4),
+            35,
+            None,
+            RepeatMode::Global,
+            None,
+        );
+        let params = Arc::new(Mutex::new(LoopGridParams {
+            swing,
+            bank: 0,
+            frozen: false,
+            cueing: false,
+            duck_triggered: false,
+            channel_triggered: HashSet::new(),
+            reset_automation: false,
+        }));
+        LoopGridLaunchpad::offline(renderer.capture(vec![chunk]), params)
+    }
+
+    fn press(beats: f64, button: Button, value: u8) -> ScriptedPress {
+        ScriptedPress {
+            pos: MidiTime::from_float(beats * 24.0),
+            button,
+            value,
+        }
+    }
+
+    // presses and releases the button at the same position
+    fn tap(beats: f64, button: Button) -> Vec<ScriptedPress> {
+        vec![press(beats, button, 127), press(beats, button, 0)]
+    }
+
+    fn render(swing: f64, beats: i32, script: Vec<ScriptedPress>) -> Vec<RenderedNote> {
+        let renderer = Renderer::new();
+        let mut launchpad = launchpad(&renderer, swing);
+        renderer.render(
+            &mut launchpad,
+            VirtualClock::new(MidiTime::from_beats(beats)),
+            &script,
+        )
+    }
+
+    // compares with tests/golden/NAME.txt, run with UPDATE_GOLDEN=1 to write it instead
+    fn assert_golden(name: &str, notes: &[RenderedNote]) {
+        let path = format!("{}/tests/golden/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let actual = to_text(notes);
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+            panic!("can't read {} ({}), run with UPDATE_GOLDEN=1", path, err)
+        });
+        assert_eq!(actual, expected, "render doesn't match {}", path);
+    }
+
+    #[test]
+    fn renders_are_repeatable() {
+        let script = vec![
+            press(0.5, Button::Grid(0), 127),
+            press(1.0, Button::Grid(1), 127),
+            press(1.25, Button::Grid(0), 0),
+            press(3.0, Button::Grid(1), 0),
+        ];
+        let first = render(0.0, 4, script.clone());
+        assert_eq!(first, render(0.0, 4, script));
+
+        // the second pad is still held at the end
+        assert_eq!(first.len(), 4);
+        assert_eq!(first[0].pos, MidiTime::from_ticks(12));
+        assert_eq!(first.last().unwrap().note, 37);
+        assert_eq!(first.last().unwrap().velocity, 0);
+        assert_eq!(to_tracks(&first).len(), 1);
+    }
+
+    #[test]
+    fn scripts_read_as_presses() {
+        let script = parse_script(
+            r#"[
+                { "beats": 1.5, "button": { "Grid": 3 }, "value": 127 },
+                { "beats": 2, "button": { "Footer": 1 }, "value": 0 }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(script.len(), 2);
+        assert_eq!(script[0].pos, MidiTime::from_ticks(36));
+        assert_eq!(script[0].button, Button::Grid(3));
+        assert_eq!(script[0].value, 127);
+        assert_eq!(script[1].pos, MidiTime::from_beats(2));
+        assert_eq!(script[1].button, Button::Footer(1));
+
+        assert!(parse_script(r#"[{ "beats": 1, "button": "Grid" }]"#).is_err());
+    }
+
+    #[test]
+    fn golden_loop() {
+        // play two pads, then loop the last 8 beats and let it go round twice
+        let mut script = vec![
+            press(0.0, Button::Grid(0), 127),
+            press(0.5, Button::Grid(0), 0),
+            press(2.0, Button::Grid(2), 100),
+            press(3.0, Button::Grid(2), 0),
+        ];
+        script.extend(tap(8.0, Button::Top(0)));
+        assert_golden("loop", &render(0.0, 24, script));
+    }
+
+    #[test]
+    fn golden_repeat() {
+        // repeat at 1/4 beat while the pad is held, then 1/3 beat
+        let mut script = tap(0.0, Button::Footer(2));
+        script.extend(tap(0.0, Button::Right(5)));
+        script.push(press(1.0, Button::Grid(0), 127));
+        script.push(press(2.0, Button::Grid(0), 0));
+        script.extend(tap(2.5, Button::Right(4)));
+        script.push(press(3.0, Button::Grid(1), 127));
+        script.push(press(4.0, Button::Grid(1), 0));
+        assert_golden("repeat", &render(0.0, 5, script));
+    }
+
+    #[test]
+    fn golden_cycle() {
+        // holding three pads steps through them at 1/2 beat
+        let mut script = tap(0.0, Button::Footer(3));
+        script.extend(tap(0.0, Button::Right(3)));
+        for &id in &[0, 1, 3] {
+            script.push(press(1.0, Button::Grid(id), 127));
+            script.push(press(4.0, Button::Grid(id), 0));
+        }
+        assert_golden("cycle", &render(0.0, 5, script));
+    }
+
+    #[test]
+    fn golden_swing() {
+        // repeats at 1/4 beat, with the off 16ths pushed late
+        let mut script = tap(0.0, Button::Footer(2));
+        script.extend(tap(0.0, Button::Right(5)));
+        script.push(press(1.0, Button::Grid(0), 127));
+        script.push(press(2.0, Button::Grid(0), 0));
+        assert_golden("swing", &render(0.3, 3, script));
+    }
+}
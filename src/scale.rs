@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Scale {
     pub root: i32,
     pub scale: i32,
@@ -44,7 +45,7 @@ fn modulo(n: i32, m: i32) -> i32 {
     ((n % m) + m) % m
 }
 
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Offset {
     pub base: i32,
     pub offset: i32,
@@ -1,22 +1,37 @@
 extern crate circular_queue;
 use self::circular_queue::CircularQueue;
 
-use midi_connection;
+use midi_connection::{self, MidiMessage};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 pub use midi_time::{MidiTime, SUB_TICKS};
 
+// the tempos the internal clock can be set to (by config or by tapping)
+pub const MIN_BPM: f64 = 20.0;
+pub const MAX_BPM: f64 = 300.0;
+
 pub struct Scheduler {
     next_pos: MidiTime,
     last_tick_at: Instant,
     ticks: i32,
     sub_ticks: u8,
+    stopped: bool,
     rx: mpsc::Receiver<ScheduleTick>,
+    tx: mpsc::SyncSender<ScheduleTick>,
+    tick_notify: mpsc::Sender<(i32, Instant)>,
+    state: Arc<Mutex<RemoteSchedulerState>>,
     _clock_source: Option<midi_connection::ThreadReference>,
 }
 
+// lets the rest of the app read and change the tempo while the scheduler is running
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    state: Arc<Mutex<RemoteSchedulerState>>,
+    tx: mpsc::SyncSender<ScheduleTick>,
+}
+
 struct RemoteSchedulerState {
     tick_durations: CircularQueue<Duration>,
     last_tick_stamp: Option<u64>,
@@ -24,9 +39,47 @@ struct RemoteSchedulerState {
     stamp_offset: u64,
     started: bool,
     last_tick_at: Option<Instant>,
+
+    // set by an external Stop, holds both clocks until Start or Continue (even if the external
+    // clock then goes quiet: plenty of sources stop sending clock while stopped, and the internal
+    // clock taking over would undo the Stop)
+    transport_stopped: bool,
+
+    // internal clock, used whenever the external clock has been quiet for external_timeout
+    internal_bpm: f64,
+    external_timeout: Duration,
+    last_external_at: Option<Instant>,
+    internal_running: bool,
+    created_at: Instant,
+}
+
+// what the internal clock should do next, see RemoteSchedulerState::internal_clock
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum InternalClock {
+    Wait,
+    // taking over: tick now and count from here
+    Start,
+    Tick,
 }
 
 impl RemoteSchedulerState {
+    fn new(bpm: f64, external_timeout: Duration) -> Self {
+        RemoteSchedulerState {
+            tick_durations: CircularQueue::with_capacity(3),
+            last_tick_at: None,
+            started: false,
+            transport_stopped: false,
+            last_tick_stamp: None,
+            tick_start_at: Instant::now(),
+            stamp_offset: 0,
+            internal_bpm: bpm,
+            external_timeout,
+            last_external_at: None,
+            internal_running: false,
+            created_at: Instant::now(),
+        }
+    }
+
     fn restart(&mut self, offset: u64) {
         self.stamp_offset = offset;
         self.last_tick_stamp = None;
@@ -44,6 +97,72 @@ impl RemoteSchedulerState {
         }
     }
 
+    fn is_external(&self) -> bool {
+        self.is_external_at(Instant::now())
+    }
+
+    fn is_external_at(&self, now: Instant) -> bool {
+        match self.last_external_at {
+            Some(at) => now.saturating_duration_since(at) < self.external_timeout,
+            None => false,
+        }
+    }
+
+    // the internal clock stays quiet while the external clock is running (or has stopped us),
+    // and at startup gives an external clock one timeout to show up before taking over
+    fn internal_clock(&mut self, now: Instant) -> InternalClock {
+        if self.is_external_at(now) || self.transport_stopped {
+            self.internal_running = false;
+            return InternalClock::Wait;
+        }
+
+        if self.internal_running {
+            return InternalClock::Tick;
+        }
+
+        if self.last_external_at.is_some() {
+            // carry on at the tempo we were following rather than jumping back
+            if let Some(bpm) = self.external_bpm() {
+                self.internal_bpm = bpm;
+            }
+            println!(
+                "Clock: external clock stopped, running internally at {:.1} BPM",
+                self.internal_bpm
+            );
+        } else if now.saturating_duration_since(self.created_at) < self.external_timeout {
+            return InternalClock::Wait;
+        } else {
+            println!(
+                "Clock: no external clock, running internally at {:.1} BPM",
+                self.internal_bpm
+            );
+        }
+
+        self.internal_running = true;
+        InternalClock::Start
+    }
+
+    fn external_bpm(&self) -> Option<f64> {
+        if self.tick_durations.len() > 1 {
+            Some(60.0 / (self.tick_duration().as_secs_f64() * 24.0))
+        } else {
+            None
+        }
+    }
+
+    fn internal_tick_duration(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.internal_bpm * 24.0))
+    }
+
+    // expected time until the next tick from whichever clock is driving
+    fn current_tick_duration(&self) -> Duration {
+        if self.is_external() {
+            self.tick_duration()
+        } else {
+            self.internal_tick_duration()
+        }
+    }
+
     fn tick(&mut self, stamp: u64) {
         if let Some(last_tick_stamp) = self.last_tick_stamp {
             let duration = Duration::from_micros(stamp - last_tick_stamp);
@@ -63,109 +182,372 @@ impl RemoteSchedulerState {
 }
 
 impl Scheduler {
-    pub fn start(clock_port_name: &str) -> Self {
-        let remote_state = Arc::new(Mutex::new(RemoteSchedulerState {
-            tick_durations: CircularQueue::with_capacity(3),
-            last_tick_at: None,
-            started: false,
-            last_tick_stamp: None,
-            tick_start_at: Instant::now(),
-            stamp_offset: 0,
-        }));
+    pub fn start(
+        clock_port_name: &str,
+        virtual_input: bool,
+        bpm: f64,
+        external_timeout: Duration,
+    ) -> Self {
+        let remote_state = Arc::new(Mutex::new(RemoteSchedulerState::new(bpm, external_timeout)));
 
         let (tx, rx) = mpsc::sync_channel(8);
         let tx_clock = tx.clone();
 
         // track external clock and tick durations (to calculate bpm)
         let state_m = remote_state.clone();
-        let _clock_source = Some(midi_connection::get_input(
-            clock_port_name,
-            move |stamp, message| {
-                if message[0] == 248 {
+        let port_name = String::from(clock_port_name);
+        let on_clock_message = move |stamp: u64, message: MidiMessage| {
+            match message {
+                MidiMessage::Clock => {
                     let mut state: std::sync::MutexGuard<RemoteSchedulerState> =
                         state_m.lock().unwrap();
 
+                    if !state.is_external() {
+                        println!("Clock: following external clock from {}", port_name);
+                    }
+                    state.last_external_at = Some(Instant::now());
+
+                    // some sources keep sending clock while stopped, the position shouldn't move
+                    if state.transport_stopped {
+                        return;
+                    }
+
                     // if we get a tick before clock start, treat as clock start
                     if !state.started {
                         state.restart(stamp);
+                        state.started = true;
                     }
 
                     state.tick(stamp);
                     tx_clock.send(ScheduleTick::MidiTick).unwrap();
-                } else if message[0] == 250 {
-                    // play
+                }
+                MidiMessage::Start => {
+                    // play from the top
                     let mut state: std::sync::MutexGuard<RemoteSchedulerState> =
                         state_m.lock().unwrap();
                     state.restart(stamp);
+                    state.started = true;
+                    state.transport_stopped = false;
+                    tx_clock.send(ScheduleTick::Jump(0)).unwrap();
+                    tx_clock.send(ScheduleTick::Continue).unwrap();
                 }
-            },
+                MidiMessage::Continue => {
+                    // resume from wherever we are (or where the last song position put us)
+                    let mut state: std::sync::MutexGuard<RemoteSchedulerState> =
+                        state_m.lock().unwrap();
+                    state.restart(stamp);
+                    state.started = true;
+                    state.transport_stopped = false;
+                    tx_clock.send(ScheduleTick::Continue).unwrap();
+                }
+                MidiMessage::Stop => {
+                    let mut state: std::sync::MutexGuard<RemoteSchedulerState> =
+                        state_m.lock().unwrap();
+                    state.last_external_at = Some(Instant::now());
+                    if !state.transport_stopped {
+                        println!(
+                            "Clock: stopped by {}, waiting for Start or Continue",
+                            port_name
+                        );
+                    }
+                    state.transport_stopped = true;
+                    tx_clock.send(ScheduleTick::Stop).unwrap();
+                }
+                MidiMessage::SongPosition(sixteenths) => {
+                    // song position is counted in 16th notes (6 ticks each)
+                    tx_clock
+                        .send(ScheduleTick::Jump(sixteenths as i32 * 6))
+                        .unwrap();
+                }
+                _ => (),
+            }
+        };
+
+        let _clock_source = Some(midi_connection::get_input_port(
+            clock_port_name,
+            virtual_input,
+            on_clock_message,
         ));
 
+        // internal clock
+        let state_i = remote_state.clone();
+        let tx_internal = tx.clone();
+        thread::spawn(move || {
+            let mut next_tick_at = Instant::now();
+            loop {
+                let mut state = state_i.lock().unwrap();
+                let now = Instant::now();
+                let duration = state.internal_tick_duration();
+                match state.internal_clock(now) {
+                    InternalClock::Wait => {
+                        drop(state);
+                        thread::sleep(duration);
+                        continue;
+                    }
+                    InternalClock::Start => next_tick_at = now,
+                    InternalClock::Tick => (),
+                }
+                drop(state);
+
+                if tx_internal.send(ScheduleTick::InternalTick).is_err() {
+                    break;
+                }
+
+                // schedule against the ideal grid so that sleep jitter doesn't add up
+                next_tick_at += duration;
+                let now = Instant::now();
+                if next_tick_at > now {
+                    thread::sleep(next_tick_at - now);
+                } else if now - next_tick_at > duration * 4 {
+                    // fell far behind (e.g. suspended), don't try to catch up in a burst
+                    next_tick_at = now;
+                }
+            }
+        });
+
+        // interpolate sub ticks between pulses, restarting the count as soon as the next real
+        // tick is scheduled so that they can never run past it
         let state_s = remote_state.clone();
         let tx_sub_clock = tx.clone();
-        // thread::spawn(move || loop {
-        //     let state: std::sync::MutexGuard<RemoteSchedulerState> = state_s.lock().unwrap();
-        //     let duration = state.tick_duration() / (SUB_TICKS as u32);
-        //     drop(state);
-        //     thread::sleep(duration);
-        //     tx_sub_clock.send(ScheduleTick::SubTick(duration)).unwrap();
-        // });
+        let (tick_notify, tick_notify_rx) = mpsc::channel::<(i32, Instant)>();
+        thread::spawn(move || {
+            let mut pending = None;
+            loop {
+                let (generation, tick_at) = match pending.take() {
+                    Some(tick) => tick,
+                    None => match tick_notify_rx.recv() {
+                        Ok(tick) => tick,
+                        Err(_) => break,
+                    },
+                };
+
+                let step = state_s.lock().unwrap().current_tick_duration() / SUB_TICKS as u32;
+                for sub_tick in 1..SUB_TICKS {
+                    let due = tick_at + step * sub_tick as u32;
+                    match tick_notify_rx.recv_timeout(due.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(tick) => {
+                            pending = Some(tick);
+                            break;
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            let message = ScheduleTick::SubTick {
+                                generation,
+                                sub_tick,
+                            };
+                            if tx_sub_clock.send(message).is_err() {
+                                return;
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+        });
 
         Scheduler {
             ticks: -1,
             sub_ticks: 0,
+            stopped: false,
             rx,
+            tx,
             last_tick_at: Instant::now(),
             next_pos: MidiTime::zero(),
+            tick_notify,
+            state: remote_state,
             _clock_source,
         }
     }
 
-    fn await_next(&mut self) -> ScheduleRange {
+    pub fn handle(&self) -> SchedulerHandle {
+        SchedulerHandle {
+            state: self.state.clone(),
+            tx: self.tx.clone(),
+        }
+    }
+
+    fn await_next(&mut self) -> Option<ScheduleRange> {
         loop {
             let msg = self.rx.recv().unwrap();
             let from = self.next_pos;
 
             match msg {
-                ScheduleTick::MidiTick => {
+                ScheduleTick::MidiTick | ScheduleTick::InternalTick => {
+                    // ticks already queued when the transport stopped
+                    if self.stopped {
+                        continue;
+                    }
+
+                    // or by the internal clock before the external clock took over
+                    if let ScheduleTick::InternalTick = msg {
+                        if self.state.lock().unwrap().is_external() {
+                            continue;
+                        }
+                    }
+
                     self.last_tick_at = Instant::now();
                     self.sub_ticks = 0;
                     self.ticks += 1;
                     self.next_pos = MidiTime::new(self.ticks, self.sub_ticks);
+                    self.tick_notify
+                        .send((self.ticks, self.last_tick_at))
+                        .unwrap();
 
-                    return ScheduleRange {
+                    return Some(ScheduleRange {
                         from,
                         to: self.next_pos,
                         tick_pos: MidiTime::from_ticks(self.ticks),
                         ticked: true,
                         jumped: false,
-                    };
+                        stopped: false,
+                    });
                 }
-                ScheduleTick::SubTick(duration) => {
-                    if from.sub_ticks() < (SUB_TICKS - 1)
-                        && self.last_tick_at.elapsed() > (duration / 2)
-                    {
-                        self.sub_ticks += 1;
+                ScheduleTick::SubTick {
+                    generation,
+                    sub_tick,
+                } => {
+                    // drop sub ticks that belong to a tick we've already moved past
+                    if !self.stopped && generation == self.ticks && sub_tick > self.sub_ticks {
+                        self.sub_ticks = sub_tick;
                         self.next_pos = MidiTime::new(self.ticks, self.sub_ticks);
-                        return ScheduleRange {
+                        return Some(ScheduleRange {
                             from,
                             to: self.next_pos,
                             tick_pos: MidiTime::from_ticks(self.ticks),
                             ticked: false,
                             jumped: false,
-                        };
+                            stopped: false,
+                        });
                     }
                 }
+                ScheduleTick::Jump(ticks) => {
+                    // the next clock pulse lands on the new position
+                    self.ticks = ticks - 1;
+                    self.sub_ticks = SUB_TICKS - 1;
+                    self.next_pos = MidiTime::from_ticks(ticks);
+
+                    return Some(ScheduleRange {
+                        from: self.next_pos,
+                        to: self.next_pos,
+                        tick_pos: MidiTime::from_ticks(ticks),
+                        ticked: false,
+                        jumped: true,
+                        stopped: self.stopped,
+                    });
+                }
+                ScheduleTick::Continue => {
+                    self.stopped = false;
+
+                    // nothing moves, but anything released by the stop needs sending again
+                    return Some(ScheduleRange {
+                        from,
+                        to: from,
+                        tick_pos: MidiTime::from_ticks(self.ticks),
+                        ticked: false,
+                        jumped: true,
+                        stopped: false,
+                    });
+                }
+                ScheduleTick::Shutdown => return None,
+                ScheduleTick::Stop => {
+                    self.stopped = true;
+
+                    return Some(ScheduleRange {
+                        from,
+                        to: from,
+                        tick_pos: MidiTime::from_ticks(self.ticks),
+                        ticked: false,
+                        jumped: false,
+                        stopped: true,
+                    });
+                }
             };
         }
     }
 }
 
+impl SchedulerHandle {
+    // tempo of the internal clock (takes effect on the next tick)
+    pub fn set_bpm(&self, bpm: f64) {
+        self.state.lock().unwrap().internal_bpm = bpm;
+    }
+
+    pub fn set_external_timeout(&self, timeout: Duration) {
+        self.state.lock().unwrap().external_timeout = timeout;
+    }
+
+    pub fn is_external(&self) -> bool {
+        self.state.lock().unwrap().is_external()
+    }
+
+    // ends the schedule iterator after the range it is currently on
+    pub fn shutdown(&self) {
+        // only fails if the scheduler is already gone
+        let _ = self.tx.send(ScheduleTick::Shutdown);
+    }
+
+    // expected time between clock pulses at the current tempo
+    pub fn tick_duration(&self) -> Duration {
+        self.state.lock().unwrap().current_tick_duration()
+    }
+}
+
 impl Iterator for Scheduler {
     type Item = ScheduleRange;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Some(self.await_next())
+        self.await_next()
+    }
+}
+
+// stands in for the Scheduler when nothing needs to happen in real time (rendering and tests):
+// the same ranges a steady clock would give, one tick and its sub ticks after another, as fast as
+// they are asked for
+pub struct VirtualClock {
+    ticks: i32,
+    sub_ticks: u8,
+    next_pos: MidiTime,
+    end: MidiTime,
+}
+
+impl VirtualClock {
+    // runs from zero up to (not including) end
+    pub fn new(end: MidiTime) -> Self {
+        VirtualClock {
+            ticks: -1,
+            sub_ticks: SUB_TICKS - 1,
+            next_pos: MidiTime::zero(),
+            end,
+        }
+    }
+}
+
+impl Iterator for VirtualClock {
+    type Item = ScheduleRange;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let from = self.next_pos;
+        let ticked = self.sub_ticks + 1 == SUB_TICKS;
+        if ticked {
+            self.ticks += 1;
+            self.sub_ticks = 0;
+        } else {
+            self.sub_ticks += 1;
+        }
+        self.next_pos = MidiTime::new(self.ticks, self.sub_ticks);
+
+        if self.next_pos >= self.end {
+            return None;
+        }
+
+        Some(ScheduleRange {
+            from,
+            to: self.next_pos,
+            tick_pos: MidiTime::from_ticks(self.ticks),
+            ticked,
+            jumped: false,
+            stopped: false,
+        })
     }
 }
 
@@ -176,9 +558,140 @@ pub struct ScheduleRange {
     pub tick_pos: MidiTime,
     pub ticked: bool,
     pub jumped: bool,
+
+    // the external transport stopped, nothing should keep sounding
+    pub stopped: bool,
 }
 
 enum ScheduleTick {
     MidiTick,
-    SubTick(Duration),
+    InternalTick,
+    SubTick { generation: i32, sub_tick: u8 },
+    Jump(i32),
+    Continue,
+    Stop,
+    Shutdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_clock_steps_through_every_sub_tick() {
+        let ranges: Vec<ScheduleRange> = VirtualClock::new(MidiTime::from_ticks(2)).collect();
+        assert_eq!(ranges.len(), 2 * SUB_TICKS as usize);
+
+        // the first tick starts the clock, like the first pulse from the Scheduler
+        assert!(ranges[0].ticked);
+        assert_eq!(ranges[0].from, MidiTime::zero());
+        assert_eq!(ranges[0].to, MidiTime::zero());
+
+        assert!(!ranges[1].ticked);
+        assert_eq!(ranges[1].to, MidiTime::new(0, 1));
+
+        let tick = &ranges[SUB_TICKS as usize];
+        assert!(tick.ticked);
+        assert_eq!(tick.from, MidiTime::new(0, SUB_TICKS - 1));
+        assert_eq!(tick.to, MidiTime::from_ticks(1));
+        assert_eq!(tick.tick_pos, MidiTime::from_ticks(1));
+
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].to, pair[1].from);
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // a scheduler without any clock threads, fed through the sender (the receiver stands in for
+    // the sub tick thread)
+    fn scheduler() -> (
+        Scheduler,
+        mpsc::SyncSender<ScheduleTick>,
+        mpsc::Receiver<(i32, Instant)>,
+    ) {
+        let (tx, rx) = mpsc::sync_channel(8);
+        let (tick_notify, tick_notify_rx) = mpsc::channel();
+        let scheduler = Scheduler {
+            ticks: -1,
+            sub_ticks: 0,
+            stopped: false,
+            rx,
+            tx: tx.clone(),
+            last_tick_at: Instant::now(),
+            next_pos: MidiTime::zero(),
+            tick_notify,
+            state: Arc::new(Mutex::new(RemoteSchedulerState::new(120.0, ms(500)))),
+            _clock_source: None,
+        };
+        (scheduler, tx, tick_notify_rx)
+    }
+
+    #[test]
+    fn internal_clock_gives_an_external_clock_a_chance_at_startup() {
+        let mut state = RemoteSchedulerState::new(120.0, ms(500));
+        let start = state.created_at;
+        assert_eq!(state.internal_clock(start), InternalClock::Wait);
+        assert_eq!(state.internal_clock(start + ms(499)), InternalClock::Wait);
+        assert_eq!(state.internal_clock(start + ms(500)), InternalClock::Start);
+        assert_eq!(state.internal_clock(start + ms(520)), InternalClock::Tick);
+    }
+
+    #[test]
+    fn internal_clock_hands_over_to_the_external_clock_and_back() {
+        let mut state = RemoteSchedulerState::new(120.0, ms(500));
+        let start = state.created_at;
+
+        // 150 BPM from the external clock, from before the internal clock got going
+        for pulse in 0..3 {
+            state.tick(pulse * 16_667);
+        }
+        state.last_external_at = Some(start + ms(100));
+        assert_eq!(state.internal_clock(start + ms(500)), InternalClock::Wait);
+        assert_eq!(state.internal_clock(start + ms(599)), InternalClock::Wait);
+
+        // taking over at the tempo it was following
+        assert_eq!(state.internal_clock(start + ms(600)), InternalClock::Start);
+        assert!((state.internal_bpm - 150.0).abs() < 0.1);
+        assert_eq!(state.internal_clock(start + ms(620)), InternalClock::Tick);
+
+        state.last_external_at = Some(start + ms(700));
+        assert_eq!(state.internal_clock(start + ms(700)), InternalClock::Wait);
+        assert_eq!(state.internal_clock(start + ms(1200)), InternalClock::Start);
+    }
+
+    #[test]
+    fn internal_ticks_still_queued_at_a_handover_are_dropped() {
+        let (mut scheduler, tx, _sub_ticks) = scheduler();
+        tx.send(ScheduleTick::InternalTick).unwrap();
+        tx.send(ScheduleTick::InternalTick).unwrap();
+        assert_eq!(scheduler.next().unwrap().tick_pos, MidiTime::from_ticks(0));
+
+        // the first external pulse arrives behind an internal one
+        scheduler.state.lock().unwrap().last_external_at = Some(Instant::now());
+        tx.send(ScheduleTick::MidiTick).unwrap();
+        tx.send(ScheduleTick::Shutdown).unwrap();
+
+        let ranges: Vec<ScheduleRange> = scheduler.collect();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].tick_pos, MidiTime::from_ticks(1));
+    }
+
+    #[test]
+    fn internal_clock_stays_stopped_after_an_external_stop() {
+        let mut state = RemoteSchedulerState::new(120.0, ms(500));
+        let start = state.created_at;
+        state.last_external_at = Some(start);
+        state.transport_stopped = true;
+
+        // the source going quiet after a Stop doesn't start the internal clock
+        assert_eq!(state.internal_clock(start + ms(100)), InternalClock::Wait);
+        assert_eq!(state.internal_clock(start + ms(5000)), InternalClock::Wait);
+
+        // Continue (or Start) clears it, and the internal clock takes over if nothing follows
+        state.transport_stopped = false;
+        assert_eq!(state.internal_clock(start + ms(5000)), InternalClock::Start);
+    }
 }
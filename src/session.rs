@@ -0,0 +1,403 @@
+use loop_event::LoopEvent;
+use loop_recorder::{LoopRecorder, RecordedEvents};
+use loop_state::{LoopCollection, LoopTransform};
+use midi_time::MidiTime;
+use scale::{Offset, Scale};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// how long the writer waits for a controller to hand over its automation
+const AUTOMATION_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub loops: LoopSession,
+    pub scale: Scale,
+    pub offsets: HashMap<String, Offset>,
+
+    // keyed by the controller's port name, so reordering the config doesn't mix them up
+    pub automation: HashMap<String, AutomationSession>,
+}
+
+// what the scheduler hands the writer: cheap to take, turned into a Session on the writer thread
+pub struct SessionSnapshot {
+    pub loops: LoopSnapshot,
+    pub scale: Scale,
+    pub offsets: HashMap<String, Offset>,
+    pub automation: Vec<(String, mpsc::Receiver<AutomationSession>)>,
+}
+
+pub struct LoopSnapshot {
+    pub loop_length: MidiTime,
+    pub recorded: RecordedEvents,
+    pub undos: Vec<Arc<LoopCollection>>,
+    pub redos: Vec<Arc<LoopCollection>>,
+}
+
+// saves on a background thread so that the scheduler never waits on the disk (or on controllers)
+pub struct SessionWriter {
+    tx: mpsc::Sender<SessionSnapshot>,
+    thread: thread::JoinHandle<()>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoopSession {
+    pub loop_length: MidiTime,
+    pub events: Vec<LoopEvent>,
+    pub undos: Vec<LoopCollection>,
+    pub redos: Vec<LoopCollection>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AutomationSession {
+    pub events: Vec<LoopEvent>,
+    pub loops: HashMap<u32, AutomationLoop>,
+    pub values: HashMap<u32, u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AutomationLoop {
+    pub offset: MidiTime,
+    pub length: MidiTime,
+}
+
+impl Session {
+    pub fn read(filepath: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(filepath)?;
+        let reader = BufReader::new(file);
+
+        let session = serde_json::from_reader(reader)?;
+        Ok(session)
+    }
+
+    pub fn write(&self, filepath: &str) -> std::io::Result<()> {
+        // write beside the target and swap it in, so losing power mid-save keeps the last session
+        let tmp_filepath = format!("{}.tmp", filepath);
+        serde_json::to_writer(BufWriter::new(File::create(&tmp_filepath)?), self)?;
+        fs::rename(&tmp_filepath, filepath)
+    }
+}
+
+impl SessionWriter {
+    pub fn spawn(filepath: &str) -> Self {
+        let filepath = String::from(filepath);
+        let (tx, rx) = mpsc::channel::<SessionSnapshot>();
+
+        let thread = thread::spawn(move || {
+            let mut state = SessionState::new();
+            for snapshot in rx {
+                if let Err(err) = state.collect(snapshot).write(&filepath) {
+                    println!("[WARN] failed to save session {}: {}", filepath, err);
+                }
+            }
+        });
+
+        SessionWriter { tx, thread }
+    }
+
+    pub fn save(&self, snapshot: SessionSnapshot) {
+        self.tx.send(snapshot).unwrap();
+    }
+
+    // saves one last time and waits for everything to be on disk
+    pub fn finish(self, snapshot: SessionSnapshot) {
+        self.save(snapshot);
+        drop(self.tx);
+        self.thread.join().unwrap();
+    }
+}
+
+// the writer's copy of the session, kept up to date from each snapshot
+struct SessionState {
+    recorder: LoopRecorder,
+    automation: HashMap<String, AutomationSession>,
+}
+
+impl SessionState {
+    fn new() -> Self {
+        SessionState {
+            recorder: LoopRecorder::new(),
+            automation: HashMap::new(),
+        }
+    }
+
+    fn collect(&mut self, snapshot: SessionSnapshot) -> Session {
+        match snapshot.loops.recorded {
+            RecordedEvents::All(events) => self.recorder.restore(events),
+            RecordedEvents::Added(events) => {
+                for event in events {
+                    self.recorder.add(event);
+                }
+            }
+        }
+
+        // a controller that doesn't answer in time keeps what it saved last time
+        let mut automation = HashMap::new();
+        for (key, reply) in snapshot.automation {
+            match reply.recv_timeout(AUTOMATION_TIMEOUT) {
+                Ok(saved) => {
+                    automation.insert(key, saved);
+                }
+                Err(_) => {
+                    println!(
+                        "[WARN] no automation from {} in time, keeping the last save",
+                        key
+                    );
+                    if let Some(saved) = self.automation.remove(&key) {
+                        automation.insert(key, saved);
+                    }
+                }
+            }
+        }
+        self.automation = automation;
+
+        Session {
+            loops: LoopSession {
+                loop_length: snapshot.loops.loop_length,
+                events: self.recorder.events(),
+                undos: unshare(&snapshot.loops.undos),
+                redos: unshare(&snapshot.loops.redos),
+            },
+            scale: snapshot.scale,
+            offsets: snapshot.offsets,
+            automation: self.automation.clone(),
+        }
+    }
+}
+
+fn unshare(collections: &[Arc<LoopCollection>]) -> Vec<LoopCollection> {
+    collections
+        .iter()
+        .map(|collection| LoopCollection::clone(collection))
+        .collect()
+}
+
+impl LoopSession {
+    // the clock restarts at zero, so move the recorded material to before zero where it
+    // can't collide with anything recorded from now on (keeping every loop in phase)
+    pub fn rebase(&mut self) {
+        let lengths: Vec<MidiTime> = self
+            .undos
+            .iter()
+            .chain(self.redos.iter())
+            .flat_map(|collection| collection.transforms.values())
+            .filter_map(|transform| match transform {
+                LoopTransform::Range { length, .. } => Some(*length),
+                _ => None,
+            })
+            .collect();
+        let shift = rebase_shift(&self.events, &lengths);
+
+        for event in &mut self.events {
+            event.pos = event.pos - shift;
+        }
+
+        for collection in self.undos.iter_mut().chain(self.redos.iter_mut()) {
+            for transform in collection.transforms.values_mut() {
+                if let LoopTransform::Range { pos, .. } = transform {
+                    *pos = *pos - shift;
+                }
+            }
+        }
+    }
+}
+
+impl AutomationSession {
+    // see LoopSession::rebase
+    pub fn rebase(&mut self) {
+        let lengths: Vec<MidiTime> = self.loops.values().map(|item| item.length).collect();
+        let shift = rebase_shift(&self.events, &lengths);
+
+        for event in &mut self.events {
+            event.pos = event.pos - shift;
+        }
+
+        for item in self.loops.values_mut() {
+            item.offset = item.offset - shift;
+        }
+    }
+}
+
+fn rebase_shift(events: &[LoopEvent], lengths: &[MidiTime]) -> MidiTime {
+    let end = match events.iter().map(|event| event.pos.ticks()).max() {
+        Some(ticks) if ticks >= 0 => ticks as i64 + 1,
+        _ => return MidiTime::zero(),
+    };
+
+    // playback phase is pos % length, so shift by a multiple of every loop length (and whole bars)
+    let step = lengths
+        .iter()
+        .fold(MidiTime::from_beats(32).ticks() as i64, |step, length| {
+            lcm(step, length.ticks().max(1) as i64)
+        });
+    let shift = (end + step - 1) / step * step;
+
+    MidiTime::from_ticks(shift.min(i32::MAX as i64 / 2) as i32)
+}
+
+fn lcm(a: i64, b: i64) -> i64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let remainder = x % y;
+        x = y;
+        y = remainder;
+    }
+    (a / x).saturating_mul(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output_value::OutputValue;
+
+    fn event(id: u32, ticks: i32) -> LoopEvent {
+        LoopEvent {
+            id,
+            pos: MidiTime::from_ticks(ticks),
+            value: OutputValue::On(100),
+        }
+    }
+
+    #[test]
+    fn rebase_moves_loops_before_zero_in_phase() {
+        let length = MidiTime::from_beats(3);
+        let mut collection = LoopCollection::new(length);
+        collection.transforms.insert(
+            0,
+            LoopTransform::Range {
+                pos: MidiTime::from_beats(100),
+                length,
+            },
+        );
+        let mut session = LoopSession {
+            loop_length: length,
+            events: vec![event(0, 24 * 100), event(0, 24 * 102)],
+            undos: vec![collection],
+            redos: Vec::new(),
+        };
+
+        session.rebase();
+
+        assert!(session
+            .events
+            .iter()
+            .all(|event| event.pos < MidiTime::zero()));
+        match session.undos[0].transforms.get(&0) {
+            Some(LoopTransform::Range { pos, length }) => {
+                assert_eq!(*pos % *length, MidiTime::from_beats(100) % *length);
+                assert_eq!(*pos % MidiTime::from_beats(4), MidiTime::zero());
+                assert_eq!(session.events[0].pos, *pos);
+            }
+            other => panic!("unexpected transform {:?}", other),
+        }
+
+        // already rebased sessions stay where they are
+        let events = session.events.clone();
+        session.rebase();
+        assert_eq!(session.events, events);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut session = Session {
+            loops: LoopSession {
+                loop_length: MidiTime::from_beats(8),
+                events: vec![event(3, 10), event(3, 20)],
+                undos: vec![LoopCollection::new(MidiTime::from_beats(8))],
+                redos: Vec::new(),
+            },
+            scale: Scale {
+                root: 62,
+                scale: 5,
+                offset: 0,
+            },
+            offsets: HashMap::new(),
+            automation: HashMap::new(),
+        };
+        session
+            .automation
+            .insert(String::from("Twister"), AutomationSession::default());
+        session.offsets.insert(
+            String::from("keys"),
+            Offset {
+                base: -4,
+                offset: 2,
+                pitch: 0,
+            },
+        );
+
+        let json = serde_json::to_string(&session).unwrap();
+        let restored: Session = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.loops.events, session.loops.events);
+        assert_eq!(restored.scale, session.scale);
+        assert_eq!(restored.offsets.get("keys"), session.offsets.get("keys"));
+        assert_eq!(
+            restored.automation.keys().collect::<Vec<_>>(),
+            vec!["Twister"]
+        );
+    }
+
+    fn snapshot(
+        recorded: RecordedEvents,
+        automation: Vec<(String, mpsc::Receiver<AutomationSession>)>,
+    ) -> SessionSnapshot {
+        SessionSnapshot {
+            loops: LoopSnapshot {
+                loop_length: MidiTime::from_beats(8),
+                recorded,
+                undos: vec![Arc::new(LoopCollection::new(MidiTime::from_beats(8)))],
+                redos: Vec::new(),
+            },
+            scale: Scale {
+                root: 62,
+                scale: 5,
+                offset: 0,
+            },
+            offsets: HashMap::new(),
+            automation,
+        }
+    }
+
+    fn reply(automation: Option<AutomationSession>) -> mpsc::Receiver<AutomationSession> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(automation) = automation {
+            tx.send(automation).unwrap();
+        }
+        rx
+    }
+
+    #[test]
+    fn writer_follows_the_recording_and_keeps_unanswered_automation() {
+        let mut state = SessionState::new();
+        let saved = AutomationSession {
+            values: vec![(4, 100)].into_iter().collect(),
+            ..AutomationSession::default()
+        };
+
+        let session = state.collect(snapshot(
+            RecordedEvents::All(vec![event(3, 10)]),
+            vec![(String::from("Twister"), reply(Some(saved)))],
+        ));
+        assert_eq!(session.loops.events, vec![event(3, 10)]);
+        assert_eq!(session.automation["Twister"].values.get(&4), Some(&100));
+
+        // only the new events come through, and the controller never answers this time
+        let session = state.collect(snapshot(
+            RecordedEvents::Added(vec![event(3, 20)]),
+            vec![(String::from("Twister"), reply(None))],
+        ));
+        assert_eq!(session.loops.events, vec![event(3, 10), event(3, 20)]);
+        assert_eq!(session.automation["Twister"].values.get(&4), Some(&100));
+
+        // controllers that are gone from the config are dropped
+        let session = state.collect(snapshot(RecordedEvents::Added(Vec::new()), Vec::new()));
+        assert!(session.automation.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+extern crate libc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    // a second Ctrl-C means the clean shutdown is stuck, give up on it
+    if SIGNALLED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+// calls callback (on its own thread) the first time SIGINT or SIGTERM comes in
+pub fn on_shutdown<F>(callback: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    // not much is safe to do inside a signal handler, so poll for it instead
+    thread::spawn(move || {
+        while !SIGNALLED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        callback();
+    });
+}
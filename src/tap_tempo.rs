@@ -0,0 +1,94 @@
+use scheduler::{MAX_BPM, MIN_BPM};
+use std::time::{Duration, Instant};
+
+const MAX_TAPS: usize = 6;
+
+// intervals further than this from the median are treated as mistaps
+const OUTLIER_TOLERANCE: f64 = 0.25;
+
+pub struct TapTempo {
+    taps: Vec<Instant>,
+}
+
+impl TapTempo {
+    pub fn new() -> Self {
+        TapTempo { taps: Vec::new() }
+    }
+
+    // returns the new tempo once there are enough consistent taps
+    pub fn tap(&mut self, at: Instant) -> Option<f64> {
+        let max_interval = Duration::from_secs_f64(60.0 / MIN_BPM);
+        if let Some(last) = self.taps.last() {
+            if at.duration_since(*last) > max_interval {
+                // long pause, start counting again
+                self.taps.clear();
+            }
+        }
+
+        self.taps.push(at);
+        if self.taps.len() > MAX_TAPS {
+            self.taps.remove(0);
+        }
+
+        let mut intervals: Vec<f64> = self
+            .taps
+            .windows(2)
+            .map(|pair| pair[1].duration_since(pair[0]).as_secs_f64())
+            .collect();
+
+        if intervals.len() < 2 {
+            return None;
+        }
+
+        let mut sorted = intervals.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = sorted[sorted.len() / 2];
+
+        intervals.retain(|interval| (interval - median).abs() <= median * OUTLIER_TOLERANCE);
+        if intervals.len() < 2 {
+            return None;
+        }
+
+        let average = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        Some((60.0 / average).max(MIN_BPM).min(MAX_BPM))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap_at(tap_tempo: &mut TapTempo, start: Instant, millis: &[u64]) -> Option<f64> {
+        let mut result = None;
+        for offset in millis {
+            result = tap_tempo.tap(start + Duration::from_millis(*offset));
+        }
+        result
+    }
+
+    #[test]
+    fn needs_three_taps() {
+        let mut tap_tempo = TapTempo::new();
+        let start = Instant::now();
+        assert_eq!(tap_at(&mut tap_tempo, start, &[0, 500]), None);
+        assert_eq!(tap_at(&mut tap_tempo, start, &[1000]), Some(120.0));
+    }
+
+    #[test]
+    fn ignores_mistap() {
+        let mut tap_tempo = TapTempo::new();
+        let start = Instant::now();
+        let bpm = tap_at(&mut tap_tempo, start, &[0, 500, 1000, 1250, 1500, 2000]).unwrap();
+        assert!((bpm - 120.0).abs() < 0.001, "{}", bpm);
+    }
+
+    #[test]
+    fn restarts_after_pause() {
+        let mut tap_tempo = TapTempo::new();
+        let start = Instant::now();
+        tap_at(&mut tap_tempo, start, &[0, 500, 1000]);
+        assert_eq!(tap_at(&mut tap_tempo, start, &[10000, 10400]), None);
+        let bpm = tap_at(&mut tap_tempo, start, &[10800]).unwrap();
+        assert!((bpm - 150.0).abs() < 0.001, "{}", bpm);
+    }
+}
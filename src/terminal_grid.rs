@@ -0,0 +1,476 @@
+extern crate libc;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use grid_surfaces::{Button, Light};
+use loop_grid::{ButtonLight, GridEvent};
+
+// the velocity of a mouse click or key press (before the grid's velocity curve)
+const PRESS_VELOCITY: u8 = 100;
+
+// how long a tapped key holds its button down
+const TAP_LENGTH: Duration = Duration::from_millis(120);
+
+// each button is drawn 4 characters wide with a gap, one line high
+const CELL_WIDTH: u16 = 5;
+
+// line 1 is the title, the buttons start below it
+const FIRST_LINE: u16 = 2;
+
+// columns: the left side, 8 across, the right side
+const COLUMNS: usize = 10;
+
+// rows: the top buttons, 8 grid rows, the bottom row and the footer
+const ROWS: usize = 11;
+
+// log output scrolls underneath the buttons and the help
+const LOG_LINE: u16 = FIRST_LINE + ROWS as u16 + 2;
+
+// the launchpad palette as RGB (http://launchpaddr.com/mk2palette/)
+const PALETTE: [u32; 128] = [
+    0x000000, 0x1C1C1C, 0x7C7C7C, 0xFCFCFC, 0xFF4E48, 0xFE0A00, 0x5A0000, 0x180002, 0xFFBC63,
+    0xFF5700, 0x5A1D00, 0x241802, 0xFDFD21, 0xFDFD00, 0x585800, 0x181800, 0x81FD2B, 0x40FD01,
+    0x165800, 0x132801, 0x35FD2B, 0x00FE00, 0x005801, 0x001800, 0x35FC47, 0x00FE00, 0x005801,
+    0x001800, 0x32FD7F, 0x00FD3A, 0x015814, 0x001C0E, 0x2FFCB1, 0x00FB91, 0x015732, 0x011810,
+    0x39BEFF, 0x00A7FF, 0x014051, 0x001018, 0x4186FF, 0x0050FF, 0x011A5A, 0x010619, 0x4747FF,
+    0x0000FE, 0x00005A, 0x000018, 0x8347FF, 0x5000FF, 0x160067, 0x0A0032, 0xFF48FE, 0xFF00FE,
+    0x5A005A, 0x180018, 0xFB4E83, 0xFF0753, 0x5A011B, 0x210110, 0xFF1901, 0x9A3500, 0x7A5101,
+    0x3E6500, 0x013800, 0x005432, 0x00537F, 0x0000FE, 0x01444D, 0x1A00D1, 0x7C7C7C, 0x202020,
+    0xFF0A00, 0xBAFD00, 0xACEB00, 0x56FD00, 0x008800, 0x01FC7B, 0x00A7FF, 0x021AFF, 0x3500FF,
+    0x7800FF, 0xB4177E, 0x412000, 0xFF4A01, 0x82E100, 0x66FD00, 0x00FE00, 0x00FE00, 0x45FD61,
+    0x01FBCB, 0x5086FF, 0x274DC8, 0x847AED, 0xD30CFF, 0xFF065A, 0xFF7D01, 0xB8B100, 0x8AFD00,
+    0x815D00, 0x3A2802, 0x0D4C05, 0x005037, 0x131429, 0x101F5A, 0x6A3C18, 0xAC0401, 0xE15136,
+    0xDC6900, 0xFEE100, 0x99E101, 0x60B500, 0x1B1C31, 0xDCFD54, 0x76FBB9, 0x9698FF, 0x8B62FF,
+    0x404040, 0x747474, 0xDEFCFC, 0xA20401, 0x340100, 0x00D201, 0x004101, 0xB8B100, 0x3C3000,
+    0xB45D00, 0x4C1300,
+];
+
+lazy_static! {
+    // how the terminal was before start, put back on drop or exit
+    static ref ORIGINAL_MODE: Mutex<Option<libc::termios>> = Mutex::new(None);
+}
+
+// draws the grid in the terminal and plays it with the keyboard and mouse, for working on
+// the looper without a launchpad
+pub struct TerminalGrid {
+    screen: Arc<Mutex<Screen>>,
+}
+
+impl TerminalGrid {
+    // fails if stdin isn't a terminal
+    pub fn start(input_tx: mpsc::Sender<GridEvent>) -> io::Result<Self> {
+        let original_mode = raw_mode()?;
+        *ORIGINAL_MODE.lock().unwrap() = Some(original_mode);
+        unsafe {
+            // process::exit skips drop
+            libc::atexit(restore_terminal);
+        }
+        let height = terminal_height();
+
+        let screen = Arc::new(Mutex::new(Screen {
+            lights: HashMap::new(),
+            cursor: (1, 1),
+            held: HashSet::new(),
+            clicked: None,
+        }));
+
+        {
+            // no cursor, mouse presses and releases (as SGR), logs scroll below the buttons
+            let mut out = String::from("\x1b[?25l\x1b[?1000h\x1b[?1006h\x1b[2J");
+            out.push_str(&format!("\x1b[{};{}r", LOG_LINE, height.max(LOG_LINE + 1)));
+            out.push_str(&format!(
+                "\x1b[1;1HLoop Drop\x1b[{};1Harrows/hjkl move, space taps, enter holds (again to \
+                 release), click to press. ~~ looping (red: recording), Ctrl-C quits",
+                FIRST_LINE + ROWS as u16
+            ));
+            out.push_str(&screen.lock().unwrap().draw_all());
+            out.push_str(&format!("\x1b[{};1H", height));
+            write_out(&out);
+        }
+
+        let input_screen = Arc::clone(&screen);
+        thread::spawn(move || {
+            let mut bytes = io::stdin().bytes().filter_map(Result::ok);
+            while let Some(input) = parse_input(&mut bytes) {
+                let presses = input_screen.lock().unwrap().handle(input);
+                for (button, value) in presses {
+                    let event = GridEvent::from_button(button, value, Instant::now());
+                    if input_tx.send(event).is_err() {
+                        return;
+                    }
+
+                    // terminals don't report key releases, so a tap lets go on its own
+                    if input == Input::Tap {
+                        let release_tx = input_tx.clone();
+                        thread::spawn(move || {
+                            thread::sleep(TAP_LENGTH);
+                            let event = GridEvent::from_button(button, 0, Instant::now());
+                            release_tx.send(event).ok();
+                        });
+                    }
+                }
+            }
+        });
+
+        Ok(TerminalGrid { screen })
+    }
+
+    pub fn show(&mut self, button: Button, light: ButtonLight) {
+        let mut screen = self.screen.lock().unwrap();
+        screen.lights.insert(button, light);
+        let out = screen.draw(button);
+        write_out(&out);
+    }
+}
+
+impl Drop for TerminalGrid {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+// the logs stay on screen, the shell carries on under them
+extern "C" fn restore_terminal() {
+    if let Some(original_mode) = ORIGINAL_MODE.lock().unwrap().take() {
+        write_out(&format!(
+            "\x1b[r\x1b[?1006l\x1b[?1000l\x1b[?25h\x1b[{};1H\n",
+            terminal_height()
+        ));
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original_mode);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Input {
+    Move(i32, i32),
+    Tap,
+    Hold,
+    // 1-based terminal column and line
+    MouseDown(u16, u16),
+    MouseUp(u16, u16),
+    Other,
+}
+
+struct Screen {
+    lights: HashMap<Button, ButtonLight>,
+    cursor: (usize, usize),
+
+    // buttons held down with enter, and the one the mouse is holding
+    held: HashSet<Button>,
+    clicked: Option<Button>,
+}
+
+impl Screen {
+    // the presses (value > 0) and releases to send
+    fn handle(&mut self, input: Input) -> Vec<(Button, u8)> {
+        match input {
+            Input::Move(x, y) => {
+                let previous = self.cursor;
+                let col = (self.cursor.0 as i32 + x).max(0).min(COLUMNS as i32 - 1);
+                let row = (self.cursor.1 as i32 + y).max(0).min(ROWS as i32 - 1);
+                self.cursor = (col as usize, row as usize);
+                self.redraw(&[previous, self.cursor]);
+                Vec::new()
+            }
+            Input::Tap => match button_at(self.cursor.0, self.cursor.1) {
+                Some(button) => vec![(button, PRESS_VELOCITY)],
+                None => Vec::new(),
+            },
+            Input::Hold => match button_at(self.cursor.0, self.cursor.1) {
+                Some(button) => {
+                    let value = if self.held.remove(&button) {
+                        0
+                    } else {
+                        self.held.insert(button);
+                        PRESS_VELOCITY
+                    };
+                    self.redraw(&[self.cursor]);
+                    vec![(button, value)]
+                }
+                None => Vec::new(),
+            },
+            Input::MouseDown(x, y) => match cell_at(x, y) {
+                Some((col, row)) => {
+                    let previous = self.cursor;
+                    self.cursor = (col, row);
+                    self.redraw(&[previous, self.cursor]);
+                    let button = button_at(col, row);
+                    self.clicked = button;
+                    button
+                        .map(|button| (button, PRESS_VELOCITY))
+                        .into_iter()
+                        .collect()
+                }
+                None => Vec::new(),
+            },
+            // released wherever the mouse has moved to
+            Input::MouseUp(..) => self
+                .clicked
+                .take()
+                .map(|button| (button, 0))
+                .into_iter()
+                .collect(),
+            Input::Other => Vec::new(),
+        }
+    }
+
+    fn redraw(&self, cells: &[(usize, usize)]) {
+        let out: String = cells
+            .iter()
+            .filter_map(|&(col, row)| button_at(col, row))
+            .map(|button| self.draw(button))
+            .collect();
+        write_out(&out);
+    }
+
+    fn draw_all(&self) -> String {
+        let mut out = String::new();
+        for row in 0..ROWS {
+            for col in 0..COLUMNS {
+                if let Some(button) = button_at(col, row) {
+                    out.push_str(&self.draw(button));
+                }
+            }
+        }
+        out
+    }
+
+    // draws the button and puts the cursor back where the logs are printing
+    fn draw(&self, button: Button) -> String {
+        let (col, row) = position(button);
+        let light = self
+            .lights
+            .get(&button)
+            .cloned()
+            .unwrap_or(ButtonLight::Constant(Light::Off));
+        let (light, pulsing) = match light {
+            ButtonLight::Constant(light) => (light, false),
+            ButtonLight::Pulsing(light) => (light, true),
+        };
+
+        let (r, g, b) = match light.value() {
+            // show unlit buttons, a black cell is lost on most terminals
+            0 => (40, 40, 40),
+            value => rgb(PALETTE[value as usize % PALETTE.len()]),
+        };
+        let foreground = if r as u32 * 3 + g as u32 * 6 + b as u32 > 1200 {
+            "30"
+        } else {
+            "97"
+        };
+        let (open, close) = if self.cursor == (col, row) {
+            ('[', ']')
+        } else if self.held.contains(&button) {
+            ('{', '}')
+        } else {
+            (' ', ' ')
+        };
+        let inner = if pulsing { "~~" } else { "  " };
+
+        format!(
+            "\x1b7\x1b[{};{}H\x1b[{};48;2;{};{};{}m{}{}{}\x1b[0m\x1b8",
+            FIRST_LINE + row as u16,
+            1 + col as u16 * CELL_WIDTH,
+            foreground,
+            r,
+            g,
+            b,
+            open,
+            inner,
+            close
+        )
+    }
+}
+
+// where the button is drawn: column 0 is the left side and 9 the right, row 0 the top
+// buttons, 1 - 8 the grid, 9 the bottom row and 10 the footer
+fn position(button: Button) -> (usize, usize) {
+    match button {
+        Button::Grid(id) => (1 + id as usize % 8, 1 + id as usize / 8),
+        Button::Top(index) => (1 + index, 0),
+        Button::Left(index) => (0, 1 + index),
+        Button::Right(index) => (9, 1 + index),
+        Button::Bottom(index) => (1 + index, 9),
+        Button::Footer(index) => (1 + index, 10),
+    }
+}
+
+fn button_at(col: usize, row: usize) -> Option<Button> {
+    let side = col == 0 || col == COLUMNS - 1;
+    match row {
+        0 if !side => Some(Button::Top(col - 1)),
+        1..=8 if col == 0 => Some(Button::Left(row - 1)),
+        1..=8 if col == COLUMNS - 1 => Some(Button::Right(row - 1)),
+        1..=8 => Some(Button::Grid(((row - 1) * 8 + col - 1) as u32)),
+        9 if !side => Some(Button::Bottom(col - 1)),
+        10 if !side => Some(Button::Footer(col - 1)),
+        _ => None,
+    }
+}
+
+// the cell under a mouse position, not counting the gaps between cells
+fn cell_at(x: u16, y: u16) -> Option<(usize, usize)> {
+    if x < 1 || y < FIRST_LINE || (x - 1) % CELL_WIDTH == CELL_WIDTH - 1 {
+        return None;
+    }
+    let col = ((x - 1) / CELL_WIDTH) as usize;
+    let row = (y - FIRST_LINE) as usize;
+    if col < COLUMNS && row < ROWS {
+        Some((col, row))
+    } else {
+        None
+    }
+}
+
+// the next thing typed (None once stdin closes)
+fn parse_input(bytes: &mut impl Iterator<Item = u8>) -> Option<Input> {
+    let input = match bytes.next()? {
+        b'h' => Input::Move(-1, 0),
+        b'l' => Input::Move(1, 0),
+        b'k' => Input::Move(0, -1),
+        b'j' => Input::Move(0, 1),
+        b' ' => Input::Tap,
+        b'\r' | b'\n' => Input::Hold,
+        0x1b => {
+            if bytes.next()? != b'[' {
+                return Some(Input::Other);
+            }
+            match bytes.next()? {
+                b'A' => Input::Move(0, -1),
+                b'B' => Input::Move(0, 1),
+                b'C' => Input::Move(1, 0),
+                b'D' => Input::Move(-1, 0),
+                // SGR mouse report: ESC [ < button ; x ; y (M pressed, m released)
+                b'<' => {
+                    let mut fields = [0u16; 3];
+                    let mut index = 0;
+                    loop {
+                        match bytes.next()? {
+                            digit @ b'0'..=b'9' if index < 3 => {
+                                fields[index] = fields[index]
+                                    .saturating_mul(10)
+                                    .saturating_add((digit - b'0') as u16)
+                            }
+                            b';' => index += 1,
+                            end @ b'M' | end @ b'm' => {
+                                // only the left button, not the wheel or drags
+                                break if index != 2 || fields[0] != 0 {
+                                    Input::Other
+                                } else if end == b'M' {
+                                    Input::MouseDown(fields[1], fields[2])
+                                } else {
+                                    Input::MouseUp(fields[1], fields[2])
+                                };
+                            }
+                            _ => break Input::Other,
+                        }
+                    }
+                }
+                _ => Input::Other,
+            }
+        }
+        _ => Input::Other,
+    };
+    Some(input)
+}
+
+fn rgb(color: u32) -> (u8, u8, u8) {
+    ((color >> 16) as u8, (color >> 8) as u8, color as u8)
+}
+
+fn write_out(out: &str) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
+
+// keys come in as they are typed and aren't echoed (Ctrl-C still interrupts)
+fn raw_mode() -> io::Result<libc::termios> {
+    unsafe {
+        let mut original: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(original)
+    }
+}
+
+fn terminal_height() -> u16 {
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_row > 0 {
+            size.ws_row
+        } else {
+            24
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Input> {
+        let mut bytes = bytes.iter().cloned();
+        let mut result = Vec::new();
+        while let Some(input) = parse_input(&mut bytes) {
+            result.push(input);
+        }
+        result
+    }
+
+    #[test]
+    fn every_button_has_a_cell() {
+        let mut buttons = HashSet::new();
+        for row in 0..ROWS {
+            for col in 0..COLUMNS {
+                if let Some(button) = button_at(col, row) {
+                    assert_eq!(position(button), (col, row));
+                    buttons.insert(button);
+                }
+            }
+        }
+        assert_eq!(buttons.len(), 64 + 8 * 5);
+        assert_eq!(button_at(1, 1), Some(Button::Grid(0)));
+        assert_eq!(button_at(8, 8), Some(Button::Grid(63)));
+        assert_eq!(button_at(0, 0), None);
+    }
+
+    #[test]
+    fn reads_keys_and_clicks() {
+        assert_eq!(
+            parse(b"hj\x1b[C \r\x1b[<0;12;3M\x1b[<0;14;3m\x1b[<64;1;1Mx"),
+            vec![
+                Input::Move(-1, 0),
+                Input::Move(0, 1),
+                Input::Move(1, 0),
+                Input::Tap,
+                Input::Hold,
+                Input::MouseDown(12, 3),
+                Input::MouseUp(14, 3),
+                Input::Other,
+                Input::Other,
+            ]
+        );
+
+        // column 12 is the third cell (after the left side), line 3 the first grid row
+        assert_eq!(cell_at(12, 3), Some((2, 1)));
+        assert_eq!(cell_at(10, 3), None);
+        assert_eq!(button_at(2, 1), Some(Button::Grid(1)));
+    }
+}
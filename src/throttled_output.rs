@@ -1,9 +1,9 @@
-use ::midi_connection::SharedMidiOutputConnection;
+use ::midi_connection::{MidiMessage, SharedMidiOutputConnection};
 use std::collections::{HashMap, HashSet};
 
 pub struct ThrottledOutput {
     midi_connection: SharedMidiOutputConnection,
-    unsent_values: HashMap<(u8, u8), u8>,
+    unsent_values: HashMap<(u8, u8), MidiMessage>,
     sent_keys: HashSet<(u8, u8)>
 }
 
@@ -17,24 +17,24 @@ impl ThrottledOutput {
     }
 
     pub fn flush (&mut self) {
-        for ((msg, cc), value) in &self.unsent_values {
-            self.midi_connection.send(&[*msg, *cc, *value]).unwrap();
+        for (_, message) in self.unsent_values.drain() {
+            self.midi_connection.send(message);
         }
-        self.unsent_values.clear();
         self.sent_keys.clear();
     }
 
-    pub fn send (&mut self, message: &[u8]) {
-        if message.len() == 3 {
-            let key = (message[0], message[1]);
+    pub fn send (&mut self, message: MidiMessage) {
+        let bytes = message.to_bytes();
+        if bytes.len() == 3 {
+            let key = (bytes[0], bytes[1]);
             if self.sent_keys.contains(&key) {
-                self.unsent_values.insert(key, message[2]);
+                self.unsent_values.insert(key, message);
             } else {
-                self.midi_connection.send(message).unwrap();
+                self.midi_connection.send(message);
                 self.sent_keys.insert(key);
             }
         } else {
-            self.midi_connection.send(message).unwrap();
+            self.midi_connection.send(message);
         }
     }
 }
\ No newline at end of file
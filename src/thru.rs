@@ -0,0 +1,258 @@
+use config::ThruConfig;
+use midi_connection::{self, get_input_port, MidiMessage, SharedMidiOutputConnection};
+use scale::Scale;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// which step of C major each key is, black keys play the step below
+const WHITE_KEY_STEPS: [i32; 12] = [0, 0, 1, 1, 2, 3, 3, 4, 4, 5, 5, 6];
+
+// plays an input (e.g. a keyboard) straight through to outputs
+pub struct Thru {
+    router: Arc<Mutex<Router>>,
+    outputs: Vec<(SharedMidiOutputConnection, u8)>,
+    _input: midi_connection::ThreadReference,
+}
+
+impl Thru {
+    pub fn new(
+        config: &ThruConfig,
+        outputs: Vec<(SharedMidiOutputConnection, u8)>,
+        scale: Arc<Mutex<Scale>>,
+    ) -> Self {
+        let router = Arc::new(Mutex::new(Router {
+            input_channel: config.input_channel,
+            note_range: config.note_range,
+            scale: if config.scale { Some(scale) } else { None },
+            held: HashMap::new(),
+            sounding: HashMap::new(),
+        }));
+
+        let router_input = router.clone();
+        let mut outputs_input = outputs.clone();
+        let port_name = &config.input_port_name;
+        let input = get_input_port(port_name, config.virtual_input, move |_stamp, message| {
+            if let Some(message) = router_input.lock().unwrap().route(message) {
+                for (output, channel) in &mut outputs_input {
+                    output.send(message.clone().with_channel(*channel));
+                }
+            }
+        });
+
+        Thru {
+            router,
+            outputs,
+            _input: input,
+        }
+    }
+}
+
+impl Drop for Thru {
+    // the input is going away, nothing it was holding would ever be released
+    fn drop(&mut self) {
+        let held: Vec<u8> = {
+            let mut router = self.router.lock().unwrap();
+            router.held.clear();
+            router.sounding.drain().map(|(note, _)| note).collect()
+        };
+        for (output, channel) in &mut self.outputs {
+            for note in &held {
+                output.send(MidiMessage::NoteOn {
+                    channel: *channel,
+                    note: *note,
+                    velocity: 0,
+                });
+            }
+        }
+    }
+}
+
+struct Router {
+    input_channel: Option<u8>,
+    note_range: (u8, u8),
+    scale: Option<Arc<Mutex<Scale>>>,
+    // (channel, note) played -> note sent, so the release matches even if the scale changed
+    held: HashMap<(u8, u8), u8>,
+    // note sent -> how many keys are holding it (a black key plays the white key below it)
+    sounding: HashMap<u8, usize>,
+}
+
+impl Router {
+    // what to pass on (the channel is replaced by each output's), or None to drop it
+    fn route(&mut self, message: MidiMessage) -> Option<MidiMessage> {
+        match message {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if velocity > 0 => {
+                if !self.accepts(channel) || !self.in_range(note) {
+                    return None;
+                }
+                let mapped = self.map_note(note)?;
+                if let Some(previous) = self.held.insert((channel, note), mapped) {
+                    // pressed again without a release in between
+                    self.release(previous);
+                }
+                *self.sounding.entry(mapped).or_insert(0) += 1;
+                Some(MidiMessage::NoteOn {
+                    channel,
+                    note: mapped,
+                    velocity,
+                })
+            }
+            MidiMessage::NoteOn { channel, note, .. }
+            | MidiMessage::NoteOff { channel, note, .. } => {
+                let mapped = self.held.remove(&(channel, note))?;
+                if !self.release(mapped) {
+                    return None;
+                }
+                Some(MidiMessage::NoteOn {
+                    channel,
+                    note: mapped,
+                    velocity: 0,
+                })
+            }
+            MidiMessage::PolyAftertouch {
+                channel,
+                note,
+                pressure,
+            } => {
+                let mapped = *self.held.get(&(channel, note))?;
+                Some(MidiMessage::PolyAftertouch {
+                    channel,
+                    note: mapped,
+                    pressure,
+                })
+            }
+            MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelAftertouch { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => {
+                if self.accepts(channel) {
+                    Some(message)
+                } else {
+                    None
+                }
+            }
+            // clock, transport and sysex belong to the rig, not the player
+            _ => None,
+        }
+    }
+
+    // true once nothing is holding the note any more, so it can be sent off
+    fn release(&mut self, note: u8) -> bool {
+        match self.sounding.get_mut(&note) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                self.sounding.remove(&note);
+                true
+            }
+        }
+    }
+
+    fn accepts(&self, channel: u8) -> bool {
+        self.input_channel
+            .map_or(true, |input_channel| input_channel == channel)
+    }
+
+    fn in_range(&self, note: u8) -> bool {
+        note >= self.note_range.0 && note <= self.note_range.1
+    }
+
+    fn map_note(&self, note: u8) -> Option<u8> {
+        match self.scale {
+            Some(ref scale) => {
+                let note = note as i32 - 60;
+                let octave = note.div_euclid(12);
+                let step = octave * 7 + WHITE_KEY_STEPS[note.rem_euclid(12) as usize];
+                let mapped = scale.lock().unwrap().get_note_at(step);
+                if mapped >= 0 && mapped <= 127 {
+                    Some(mapped as u8)
+                } else {
+                    None
+                }
+            }
+            None => Some(note),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(input_channel: Option<u8>, scale: Option<Arc<Mutex<Scale>>>) -> Router {
+        Router {
+            input_channel,
+            note_range: (36, 84),
+            scale,
+            held: HashMap::new(),
+            sounding: HashMap::new(),
+        }
+    }
+
+    fn note_on(channel: u8, note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            channel,
+            note,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn filters_channels_and_notes() {
+        let mut router = router(Some(2), None);
+        assert_eq!(router.route(note_on(2, 60, 100)), Some(note_on(2, 60, 100)));
+        assert_eq!(router.route(note_on(1, 60, 100)), None);
+        assert_eq!(router.route(note_on(2, 30, 100)), None);
+        assert_eq!(router.route(MidiMessage::Clock), None);
+
+        // releases only go out for notes that were passed on
+        let note_off = |note| MidiMessage::NoteOff {
+            channel: 2,
+            note,
+            velocity: 64,
+        };
+        assert_eq!(router.route(note_off(30)), None);
+        assert_eq!(router.route(note_off(60)), Some(note_on(2, 60, 0)));
+    }
+
+    #[test]
+    fn plays_white_keys_in_the_scale() {
+        // D dorian, middle C plays the root
+        let scale = Scale::new(62, 1);
+        let mut router = router(None, Some(scale.clone()));
+
+        let played: Vec<Option<MidiMessage>> = [60, 61, 62, 64, 72, 59]
+            .iter()
+            .map(|&note| router.route(note_on(1, note, 100)))
+            .collect();
+        let expected: Vec<Option<MidiMessage>> = [62, 62, 64, 65, 74, 60]
+            .iter()
+            .map(|&note| Some(note_on(1, note, 100)))
+            .collect();
+        assert_eq!(played, expected);
+
+        // the release goes to the note that was sent, even after the scale moved
+        scale.lock().unwrap().root = 67;
+        assert_eq!(router.route(note_on(1, 64, 0)), Some(note_on(1, 65, 0)));
+        assert_eq!(router.route(note_on(1, 64, 100)), Some(note_on(1, 70, 100)));
+    }
+
+    #[test]
+    fn keys_playing_the_same_note_release_it_together() {
+        // C and C# both play the root
+        let mut router = router(None, Some(Scale::new(60, 0)));
+        assert_eq!(router.route(note_on(1, 60, 100)), Some(note_on(1, 60, 100)));
+        assert_eq!(router.route(note_on(1, 61, 90)), Some(note_on(1, 60, 90)));
+
+        // still held by the other key
+        assert_eq!(router.route(note_on(1, 61, 0)), None);
+        assert_eq!(router.route(note_on(1, 60, 0)), Some(note_on(1, 60, 0)));
+        assert!(router.sounding.is_empty());
+    }
+}